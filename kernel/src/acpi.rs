@@ -0,0 +1,191 @@
+use core::{fmt, mem};
+
+use x86_64::PhysAddr;
+
+use crate::memory;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+/// Size of the ACPI 1.0 RSDP, covered by `checksum`.
+const RSDP_V1_SIZE: usize = 20;
+/// Size of the ACPI 2.0+ RSDP, covered by `extended_checksum`.
+const RSDP_V2_SIZE: usize = 36;
+
+/// Offsets into the Fixed ACPI Description Table.
+const FADT_FLAGS_OFFSET: u64 = 112;
+const FADT_RESET_REG_OFFSET: u64 = 116;
+const FADT_RESET_VALUE_OFFSET: u64 = 128;
+/// FADT flag indicating the reset register is supported.
+const FADT_RESET_REG_SUP: u32 = 1 << 10;
+
+/// Generic Address Structure address space ids.
+pub const ADDRESS_SPACE_SYSTEM_MEMORY: u8 = 0;
+pub const ADDRESS_SPACE_SYSTEM_IO: u8 = 1;
+
+static RSDP: spin::Once<Rsdp> = spin::Once::new();
+
+/// Validates the RSDP at `rsdp_addr`, and uses it for finding ACPI tables.
+///
+/// # Safety
+/// Same as [`Rsdp::validate`].
+pub unsafe fn init(rsdp_addr: PhysAddr) -> Result<&'static Rsdp, Error> {
+    let rsdp = unsafe { Rsdp::validate(rsdp_addr)? };
+    Ok(RSDP.call_once(|| rsdp))
+}
+
+#[derive(Debug)]
+pub enum Error {
+    NullRsdp,
+    InvalidSignature,
+    InvalidChecksum,
+    InvalidExtendedChecksum,
+    InvalidLength(u32),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NullRsdp => write!(f, "RSDP address is null"),
+            Self::InvalidSignature => write!(f, "RSDP signature isn't \"RSD PTR \""),
+            Self::InvalidChecksum => write!(f, "RSDP checksum doesn't add up to zero"),
+            Self::InvalidExtendedChecksum => {
+                write!(f, "RSDP extended checksum doesn't add up to zero")
+            }
+            Self::InvalidLength(len) => {
+                write!(f, "RSDP length {len} is less than {RSDP_V2_SIZE}")
+            }
+        }
+    }
+}
+
+/// A validated Root System Description Pointer.
+#[derive(Debug, Clone, Copy)]
+pub struct Rsdp {
+    pub oem_id: [u8; 6],
+    pub revision: u8,
+    pub rsdt_addr: PhysAddr,
+    /// Only present since ACPI 2.0 (`revision >= 2`).
+    pub xsdt_addr: Option<PhysAddr>,
+}
+
+/// The header common to all System Description Tables.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// Generic Address Structure, describes a register in some address space.
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct GenericAddress {
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+/// Finds the table with `signature` through the XSDT (or the RSDT before ACPI 2.0), returning its
+/// physical address. Returns `None` if ACPI isn't initialized.
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let rsdp = RSDP.get()?;
+    let (root, entry_size) = match rsdp.xsdt_addr {
+        Some(xsdt_addr) => (xsdt_addr, 8),
+        None => (rsdp.rsdt_addr, 4),
+    };
+    let header: SdtHeader = unsafe { memory::read_phys(root) };
+    let entries_addr = root + mem::size_of::<SdtHeader>() as u64;
+    let num_entries =
+        (header.length as usize).saturating_sub(mem::size_of::<SdtHeader>()) / entry_size as usize;
+
+    (0..num_entries as u64)
+        .map(|i| {
+            let entry_addr = entries_addr + entry_size * i;
+            match entry_size {
+                8 => unsafe { memory::read_phys::<u64>(entry_addr) },
+                _ => unsafe { memory::read_phys::<u32>(entry_addr) as u64 },
+            }
+        })
+        .map(PhysAddr::new_truncate)
+        .find(|&addr| unsafe { memory::read_phys::<[u8; 4]>(addr) } == *signature)
+}
+
+/// The FADT's reset register and the value to write to it, if the firmware supports it.
+pub fn reset_register() -> Option<(GenericAddress, u8)> {
+    let fadt = find_table(b"FACP")?;
+    let header: SdtHeader = unsafe { memory::read_phys(fadt) };
+    if (header.length as u64) <= FADT_RESET_VALUE_OFFSET {
+        return None;
+    }
+    let flags: u32 = unsafe { memory::read_phys(fadt + FADT_FLAGS_OFFSET) };
+    if flags & FADT_RESET_REG_SUP == 0 {
+        return None;
+    }
+    unsafe {
+        Some((
+            memory::read_phys(fadt + FADT_RESET_REG_OFFSET),
+            memory::read_phys(fadt + FADT_RESET_VALUE_OFFSET),
+        ))
+    }
+}
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+impl Rsdp {
+    /// Reads the RSDP at `addr` and checks its signature and checksums.
+    ///
+    /// # Safety
+    /// `addr` must be readable memory, like the address the bootloader found the RSDP at.
+    pub unsafe fn validate(addr: PhysAddr) -> Result<Self, Error> {
+        if addr.is_null() {
+            return Err(Error::NullRsdp);
+        }
+
+        let v1: &[u8] = unsafe { memory::read_phys_slice(addr, RSDP_V1_SIZE) };
+        if &v1[..8] != RSDP_SIGNATURE {
+            return Err(Error::InvalidSignature);
+        }
+        if !checksum(v1) {
+            return Err(Error::InvalidChecksum);
+        }
+
+        let oem_id = v1[9..15].try_into().unwrap();
+        let revision = v1[15];
+        let rsdt_addr = PhysAddr::new(u32::from_le_bytes(v1[16..20].try_into().unwrap()) as _);
+        if revision < 2 {
+            return Ok(Self {
+                oem_id,
+                revision,
+                rsdt_addr,
+                xsdt_addr: None,
+            });
+        }
+
+        let v2: &[u8] = unsafe { memory::read_phys_slice(addr, RSDP_V2_SIZE) };
+        let length = u32::from_le_bytes(v2[20..24].try_into().unwrap());
+        if (length as usize) < RSDP_V2_SIZE {
+            return Err(Error::InvalidLength(length));
+        }
+        if !checksum(v2) {
+            return Err(Error::InvalidExtendedChecksum);
+        }
+        let xsdt_addr = PhysAddr::new_truncate(u64::from_le_bytes(v2[24..32].try_into().unwrap()));
+
+        Ok(Self {
+            oem_id,
+            revision,
+            rsdt_addr,
+            xsdt_addr: Some(xsdt_addr),
+        })
+    }
+}
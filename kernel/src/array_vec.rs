@@ -0,0 +1,139 @@
+use core::{fmt, mem::MaybeUninit, ops, ptr, slice};
+
+/// A vector with a fixed capacity of `N` elements, stored inline.
+///
+/// Unlike `Vec`, this never allocates, so it can be used during early boot (before the heap is
+/// initialized) and inside interrupt handlers.
+pub struct ArrayVec<T, const N: usize> {
+    len: usize,
+    buf: [MaybeUninit<T>; N],
+}
+
+impl<T, const N: usize> ArrayVec<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            len: 0,
+            buf: [const { MaybeUninit::uninit() }; N],
+        }
+    }
+
+    #[inline]
+    pub const fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub const fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    #[inline]
+    pub const fn is_full(&self) -> bool {
+        self.len == N
+    }
+
+    /// Appends `value` to the back of the vector, returning it back as `Err` if the vector is full.
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        let Some(slot) = self.buf.get_mut(self.len) else {
+            return Err(value);
+        };
+        slot.write(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        self.len = self.len.checked_sub(1)?;
+        Some(unsafe { self.buf[self.len].assume_init_read() })
+    }
+
+    /// Removes the element at `index`, shifting the ones after it down. Returns `None` if `index` is
+    /// out of bounds.
+    pub fn remove(&mut self, index: usize) -> Option<T> {
+        if self.len <= index {
+            return None;
+        }
+        let value = unsafe { self.buf[index].assume_init_read() };
+        let base = self.buf.as_mut_ptr();
+        unsafe { ptr::copy(base.add(index + 1), base.add(index), self.len - index - 1) };
+        self.len -= 1;
+        Some(value)
+    }
+
+    pub fn clear(&mut self) {
+        let elems: *mut [T] = self.as_mut_slice();
+        self.len = 0;
+        unsafe { ptr::drop_in_place(elems) };
+    }
+
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.buf.as_ptr().cast(), self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.buf.as_mut_ptr().cast(), self.len) }
+    }
+}
+
+impl<T, const N: usize> Default for ArrayVec<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for ArrayVec<T, N> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<T: Clone, const N: usize> Clone for ArrayVec<T, N> {
+    fn clone(&self) -> Self {
+        let mut new = Self::new();
+        for value in self {
+            // Can't fail, `new` has the same capacity as `self`
+            let _ = new.push(value.clone());
+        }
+        new
+    }
+}
+
+impl<T: fmt::Debug, const N: usize> fmt::Debug for ArrayVec<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.as_slice()).finish()
+    }
+}
+
+impl<T, const N: usize> ops::Deref for ArrayVec<T, N> {
+    type Target = [T];
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T, const N: usize> ops::DerefMut for ArrayVec<T, N> {
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+impl<'a, T, const N: usize> IntoIterator for &'a ArrayVec<T, N> {
+    type Item = &'a T;
+    type IntoIter = slice::Iter<'a, T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.as_slice().iter()
+    }
+}
+
+impl<'a, T, const N: usize> IntoIterator for &'a mut ArrayVec<T, N> {
+    type Item = &'a mut T;
+    type IntoIter = slice::IterMut<'a, T>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.as_mut_slice().iter_mut()
+    }
+}
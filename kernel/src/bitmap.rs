@@ -1,4 +1,4 @@
-use core::{fmt, mem};
+use core::{fmt, mem, ops};
 
 use itertools::Itertools;
 
@@ -64,15 +64,68 @@ impl<T: ?Sized + AsRef<[usize]>> Bitmap<T> {
         Some(Self::merge_bit(i, bits.trailing_ones()))
     }
 
-    // pub fn find_last_set(&self) -> Option<usize> {
-    //     let (i, bits) = self.0.as_ref().iter().enumerate().rfind(|(_, &b)| b != 0)?;
-    //     Some(Self::merge_bit(i, usize::BITS - 1 - bits.leading_zeros()))
-    // }
+    /// Number of set bits.
+    pub fn count_ones(&self) -> usize {
+        self.0
+            .as_ref()
+            .iter()
+            .map(|b| b.count_ones() as usize)
+            .sum()
+    }
+
+    /// The first bit at or after `bit` equal to `value`.
+    fn find_next(&self, bit: usize, value: bool) -> Option<usize> {
+        let slice = self.0.as_ref();
+        let (start, mask) = Self::split_bit(bit);
+        // Ones where the bit is `value`, ignoring the bits before `bit`.
+        let matching = |b: usize| match value {
+            true => b,
+            false => !b,
+        };
+        let first = matching(*slice.get(start)?) & !(mask - 1);
+        if first != 0 {
+            return Some(Self::merge_bit(start, first.trailing_zeros()));
+        }
+        let (i, &b) = (slice.iter().enumerate())
+            .skip(start + 1)
+            .find(|(_, &b)| matching(b) != 0)?;
+        Some(Self::merge_bit(i, matching(b).trailing_zeros()))
+    }
+
+    /// The first bit at or after `start` that starts a run of `len` unset bits.
+    pub fn find_first_run(&self, start: usize, len: usize) -> Option<usize> {
+        if len == 0 {
+            return Some(start);
+        }
+        let end = usize::BITS as usize * self.0.as_ref().len();
+        let mut run_start = self.find_next(start, false)?;
+        loop {
+            let run_end = self.find_next(run_start, true).unwrap_or(end);
+            if len <= run_end - run_start {
+                return Some(run_start);
+            }
+            run_start = self.find_next(run_end, false)?;
+        }
+    }
 
-    // pub fn find_last_unset(&self) -> Option<usize> {
-    //     let (i, bits) = self.0.as_ref().iter().enumerate().rfind(|(_, &b)| b != 0)?;
-    //     Some(Self::merge_bit(i, usize::BITS - 1 - bits.leading_ones()))
-    // }
+    /// The bitmap has no length of its own, so the unused bits of a partial last word count as
+    /// well, and must be kept unset by the caller.
+    pub fn find_last_set(&self) -> Option<usize> {
+        let (i, bits) = self.0.as_ref().iter().enumerate().rfind(|(_, &b)| b != 0)?;
+        Some(Self::merge_bit(i, usize::BITS - 1 - bits.leading_zeros()))
+    }
+
+    /// Like [`Self::find_last_set`], the unused bits of a partial last word must be kept set by the
+    /// caller.
+    pub fn find_last_unset(&self) -> Option<usize> {
+        let (i, bits) = self
+            .0
+            .as_ref()
+            .iter()
+            .enumerate()
+            .rfind(|(_, &b)| b != !0)?;
+        Some(Self::merge_bit(i, usize::BITS - 1 - bits.leading_ones()))
+    }
 }
 
 impl<T: ?Sized + AsRef<[usize]> + AsMut<[usize]>> Bitmap<T> {
@@ -97,6 +150,35 @@ impl<T: ?Sized + AsRef<[usize]> + AsMut<[usize]>> Bitmap<T> {
             false => self.reset(bit),
         }
     }
+
+    /// Calls `f` with every word overlapping `range` and a mask of the bits of the word in `range`.
+    fn update_range(&mut self, range: ops::Range<usize>, f: impl Fn(&mut usize, usize)) {
+        if range.is_empty() {
+            return;
+        }
+        let (first, first_mask) = Self::split_bit(range.start);
+        let (last, last_mask) = Self::split_bit(range.end - 1);
+        let head = !(first_mask - 1);
+        let tail = last_mask | (last_mask - 1);
+        let slice = self.0.as_mut();
+        if first == last {
+            f(&mut slice[first], head & tail);
+            return;
+        }
+        f(&mut slice[first], head);
+        for word in &mut slice[first + 1..last] {
+            f(word, !0);
+        }
+        f(&mut slice[last], tail);
+    }
+
+    pub fn set_range(&mut self, range: ops::Range<usize>) {
+        self.update_range(range, |word, mask| *word |= mask);
+    }
+
+    pub fn reset_range(&mut self, range: ops::Range<usize>) {
+        self.update_range(range, |word, mask| *word &= !mask);
+    }
 }
 
 impl<'a> From<&'a [usize]> for &'a Bitmap {
@@ -110,3 +192,96 @@ impl<'a> From<&'a mut [usize]> for &'a mut Bitmap {
         Bitmap::from_slice_mut(slice)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_last_set_across_words() {
+        let mut words = [0usize; 3];
+        let bitmap = Bitmap::from_slice_mut(&mut words);
+        assert_eq!(bitmap.find_last_set(), None);
+        bitmap.set(0);
+        assert_eq!(bitmap.find_last_set(), Some(0));
+        bitmap.set(63);
+        assert_eq!(bitmap.find_last_set(), Some(63));
+        bitmap.set(64);
+        assert_eq!(bitmap.find_last_set(), Some(64));
+        bitmap.set(191);
+        assert_eq!(bitmap.find_last_set(), Some(191));
+    }
+
+    #[test]
+    fn find_last_unset_with_partial_last_word() {
+        // 100 bits, with the unused bits of the last word kept set.
+        let mut words = [!0usize, !0 << 36];
+        let bitmap = Bitmap::from_slice_mut(&mut words);
+        assert_eq!(bitmap.find_last_unset(), Some(99));
+        bitmap.set_range(64..100);
+        assert_eq!(bitmap.find_last_unset(), None);
+        bitmap.reset(63);
+        assert_eq!(bitmap.find_last_unset(), Some(63));
+        bitmap.reset(64);
+        assert_eq!(bitmap.find_last_unset(), Some(64));
+    }
+
+    #[test]
+    fn find_next_from_inside_a_word() {
+        let mut words = [0b1011usize << 60, 0];
+        let bitmap = Bitmap::from_slice_mut(&mut words);
+        assert_eq!(bitmap.find_next(0, true), Some(60));
+        assert_eq!(bitmap.find_next(61, true), Some(61));
+        assert_eq!(bitmap.find_next(62, true), Some(63));
+        assert_eq!(bitmap.find_next(64, true), None);
+        assert_eq!(bitmap.find_next(60, false), Some(62));
+        assert_eq!(bitmap.find_next(63, false), Some(64));
+        assert_eq!(bitmap.find_next(128, false), None);
+    }
+
+    #[test]
+    fn find_first_run_across_words() {
+        let mut words = [0usize; 3];
+        let bitmap = Bitmap::from_slice_mut(&mut words);
+        bitmap.set_range(0..60);
+        bitmap.set(70);
+        // Bits 60..70 are free, then 71..192.
+        assert_eq!(bitmap.find_first_run(0, 0), Some(0));
+        assert_eq!(bitmap.find_first_run(0, 10), Some(60));
+        assert_eq!(bitmap.find_first_run(0, 11), Some(71));
+        assert_eq!(bitmap.find_first_run(62, 8), Some(62));
+        assert_eq!(bitmap.find_first_run(62, 9), Some(71));
+        assert_eq!(bitmap.find_first_run(0, 121), Some(71));
+        assert_eq!(bitmap.find_first_run(0, 122), None);
+    }
+
+    #[test]
+    fn find_first_run_of_partial_last_word() {
+        // 100 bits, with the unused bits of the last word kept set.
+        let mut words = [!0usize; 2];
+        let bitmap = Bitmap::from_slice_mut(&mut words);
+        bitmap.reset_range(90..100);
+        assert_eq!(bitmap.find_first_run(0, 10), Some(90));
+        assert_eq!(bitmap.find_first_run(0, 11), None);
+    }
+
+    #[test]
+    fn set_and_reset_ranges() {
+        let mut words = [0usize; 3];
+        let bitmap = Bitmap::from_slice_mut(&mut words);
+        bitmap.set_range(5..5);
+        assert_eq!(bitmap.0, [0, 0, 0]);
+        bitmap.set_range(3..7);
+        assert_eq!(bitmap.0, [0b1111 << 3, 0, 0]);
+        bitmap.set_range(60..130);
+        assert_eq!(bitmap.0, [0b1111 << 3 | 0b1111 << 60, !0, 0b11]);
+        bitmap.reset_range(0..64);
+        assert_eq!(bitmap.0, [0, !0, 0b11]);
+        bitmap.reset_range(64..192);
+        assert_eq!(bitmap.0, [0, 0, 0]);
+        bitmap.set_range(0..192);
+        assert_eq!(bitmap.0, [!0; 3]);
+        bitmap.reset_range(63..65);
+        assert_eq!(bitmap.0, [!0 >> 1, !1, !0]);
+    }
+}
@@ -5,20 +5,27 @@ use x86_64::{
         gdt::{self, GlobalDescriptorTable},
         tss::TaskStateSegment,
     },
-    VirtAddr,
+    PrivilegeLevel, VirtAddr,
 };
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// The stack for entering the kernel from syscalls and interrupts.
+pub const SYSCALL_IST_INDEX: u16 = 1;
+
+/// The end of a new static stack of `$size` bytes.
+macro_rules! static_stack {
+    ($size:expr) => {{
+        const STACK_SIZE: usize = $size;
+        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+        let stack_start = VirtAddr::from_ptr(ptr::addr_of!(STACK));
+        stack_start + STACK_SIZE as u64
+    }};
+}
 
 static TSS: spin::Lazy<TaskStateSegment> = spin::Lazy::new(|| {
     let mut tss = TaskStateSegment::new();
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-        const STACK_SIZE: usize = 20 << 10;
-        static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-        let stack_start = VirtAddr::from_ptr(unsafe { ptr::addr_of!(STACK) });
-        let stack_end = stack_start + STACK_SIZE as u64;
-        stack_end
-    };
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = static_stack!(20 << 10);
+    tss.interrupt_stack_table[SYSCALL_IST_INDEX as usize] = static_stack!(20 << 10);
     tss
 });
 
@@ -26,6 +33,8 @@ struct Gdt {
     gdt: GlobalDescriptorTable,
     code_selector: gdt::SegmentSelector,
     data_selector: gdt::SegmentSelector,
+    user_code_selector: gdt::SegmentSelector,
+    user_data_selector: gdt::SegmentSelector,
     tss_selector: gdt::SegmentSelector,
 }
 
@@ -33,15 +42,32 @@ static GDT: spin::Lazy<Gdt> = spin::Lazy::new(|| {
     let mut gdt = GlobalDescriptorTable::new();
     let code_selector = gdt.append(gdt::Descriptor::kernel_code_segment());
     let data_selector = gdt.append(gdt::Descriptor::kernel_data_segment());
+    // `sysret` expects the user data segment right before the user code segment.
+    let user_data_selector = gdt.append(gdt::Descriptor::user_data_segment());
+    let user_code_selector = gdt.append(gdt::Descriptor::user_code_segment());
+    debug_assert_eq!(user_data_selector.rpl(), PrivilegeLevel::Ring3);
+    debug_assert_eq!(user_code_selector.rpl(), PrivilegeLevel::Ring3);
     let tss_selector = gdt.append(gdt::Descriptor::tss_segment(&TSS));
     Gdt {
         gdt,
         code_selector,
         data_selector,
+        user_code_selector,
+        user_data_selector,
         tss_selector,
     }
 });
 
+/// The ring 3 code segment selector, for returning to user mode.
+pub fn user_code_selector() -> gdt::SegmentSelector {
+    GDT.user_code_selector
+}
+
+/// The ring 3 data segment selector, for returning to user mode.
+pub fn user_data_selector() -> gdt::SegmentSelector {
+    GDT.user_data_selector
+}
+
 pub fn init() {
     use x86_64::{
         instructions::segmentation::{Segment, CS, DS, SS},
@@ -55,3 +81,18 @@ pub fn init() {
         load_tss(GDT.tss_selector);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn user_selectors() {
+        assert_eq!(user_code_selector().rpl(), PrivilegeLevel::Ring3);
+        assert_eq!(user_data_selector().rpl(), PrivilegeLevel::Ring3);
+        assert_eq!(
+            user_data_selector().index() + 1,
+            user_code_selector().index()
+        );
+    }
+}
@@ -0,0 +1,74 @@
+//! The High Precision Event Timer, found through the ACPI HPET table. Only its main counter is used,
+//! as a time source.
+
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{acpi, memory, memory::MappingFlags};
+
+/// Where the HPET table's Generic Address Structure of the registers is.
+const TABLE_ADDRESS_OFFSET: u64 = 40;
+const TABLE_MIN_LENGTH: u32 = 52;
+
+/// Register offsets, in bytes.
+const GENERAL_CAPABILITIES: usize = 0x00;
+const GENERAL_CONFIGURATION: usize = 0x10;
+const MAIN_COUNTER: usize = 0xF0;
+
+/// Whether the main counter is 64 bits wide.
+const COUNT_SIZE_CAP: u64 = 1 << 13;
+/// Starts the main counter.
+const ENABLE_CNF: u64 = 1 << 0;
+/// The counter's period is in femtoseconds, and at most 100 ns.
+const FEMTOSECONDS_PER_SECOND: u64 = 1_000_000_000_000_000;
+const MAX_PERIOD_FS: u64 = 100_000_000;
+
+static REGS: spin::Once<VirtAddr> = spin::Once::new();
+
+/// Finds, maps and starts the HPET, returning its main counter's frequency in Hz. Returns `None`
+/// without ACPI or an HPET, and if the counter is only 32 bits wide, since it'd wrap in minutes.
+pub fn init() -> Option<u64> {
+    let table = acpi::find_table(b"HPET")?;
+    let header: acpi::SdtHeader = unsafe { memory::read_phys(table) };
+    if header.length < TABLE_MIN_LENGTH {
+        log::warn!("HPET table is too short");
+        return None;
+    }
+    let address: acpi::GenericAddress = unsafe { memory::read_phys(table + TABLE_ADDRESS_OFFSET) };
+    if address.address_space != acpi::ADDRESS_SPACE_SYSTEM_MEMORY {
+        log::warn!("HPET registers aren't in memory: {address:?}");
+        return None;
+    }
+
+    let base = unsafe {
+        memory::VMM.get().expect("VMM not initialized").lock().map(
+            true,
+            4096,
+            12,
+            PhysAddr::new_truncate(address.address),
+            MappingFlags::DATA,
+        )
+    };
+    let Some(base) = base else {
+        panic!("Virtual memory mapping failed");
+    };
+    let regs: *mut u64 = base.as_mut_ptr();
+
+    let capabilities = unsafe { regs.byte_add(GENERAL_CAPABILITIES).read_volatile() };
+    let period_fs = capabilities >> 32;
+    if capabilities & COUNT_SIZE_CAP == 0 || !(1..=MAX_PERIOD_FS).contains(&period_fs) {
+        log::warn!("Unusable HPET, with capabilities {capabilities:#x}");
+        return None;
+    }
+    unsafe {
+        let config = regs.byte_add(GENERAL_CONFIGURATION);
+        config.write_volatile(config.read_volatile() | ENABLE_CNF);
+    }
+    REGS.call_once(|| base);
+    Some(FEMTOSECONDS_PER_SECOND / period_fs)
+}
+
+/// The main counter's value. Panics before [`init`] succeeded.
+pub fn counter() -> u64 {
+    let regs: *const u64 = REGS.get().expect("HPET not initialized").as_ptr();
+    unsafe { regs.byte_add(MAIN_COUNTER).read_volatile() }
+}
@@ -1,13 +1,27 @@
 pub mod apic;
+pub mod ioapic;
+pub mod irq;
+
+use core::{
+    arch::x86_64::{_mm_mfence, _rdtsc},
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
 
 use x86_64::{
     instructions::port::Port,
+    registers::control::Cr2,
     registers::model_specific::Msr,
-    structures::idt::{InterruptDescriptorTable, InterruptStackFrame},
+    structures::idt::{
+        DescriptorTable, InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode,
+        SelectorErrorCode,
+    },
     PhysAddr,
 };
 
-use apic::ApicRegs;
+use apic::{lvt::TimerMode, ApicRegs, DivideConfigurationRegister};
+
+use crate::memory::MappingFlags;
 
 enum Interrupts {
     ApicTimer = 48,
@@ -18,43 +32,194 @@ enum Interrupts {
 pub static IDT: spin::Lazy<InterruptDescriptorTable> = spin::Lazy::new(|| {
     let mut idt = InterruptDescriptorTable::new();
     idt.breakpoint.set_handler_fn(breakpoint_handler);
+    idt.overflow.set_handler_fn(overflow_handler);
     let double_fault_options = idt.double_fault.set_handler_fn(double_fault_handler);
     unsafe { double_fault_options.set_stack_index(crate::gdt::DOUBLE_FAULT_IST_INDEX) };
+    idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+    idt.invalid_tss.set_handler_fn(invalid_tss_handler);
+    idt.segment_not_present
+        .set_handler_fn(segment_not_present_handler);
+    idt.stack_segment_fault
+        .set_handler_fn(stack_segment_fault_handler);
+    idt.general_protection_fault
+        .set_handler_fn(general_protection_fault_handler);
+    idt.page_fault.set_handler_fn(page_fault_handler);
+    idt.machine_check.set_handler_fn(machine_check_handler);
     idt[Interrupts::ApicTimer as u8].set_handler_fn(apic_timer_handler);
     idt[Interrupts::ApicError as u8].set_handler_fn(apic_error_handler);
     idt[Interrupts::ApicSpurious as u8].set_handler_fn(apic_spurious_handler);
+    irq::set_handlers(&mut idt);
     idt
 });
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    log::info!("BREAKPOINT\n{stack_frame:#?}");
+/// Decodes the error code of the exceptions caused by a segment selector.
+struct SelectorError(u64);
 
-    crate::sprintln!("Hello there");
+impl fmt::Display for SelectorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("not caused by a selector");
+        }
+        let code = SelectorErrorCode::new_truncate(self.0);
+        let table = match code.descriptor_table() {
+            DescriptorTable::Gdt => "GDT",
+            DescriptorTable::Idt => "IDT",
+            DescriptorTable::Ldt => "LDT",
+        };
+        write!(f, "{table} index {}", code.index())?;
+        if code.external() {
+            f.write_str(", external")?;
+        }
+        Ok(())
+    }
 }
 
-extern "x86-interrupt" fn double_fault_handler(
+/// Defines an exception handler that reports the exception, given its name, a message, whether the
+/// exception pushes an error code (`selector_error_code` decodes it as a [`SelectorError`]), and
+/// what to do afterwards:
+/// - `recoverable`: log a warning and return.
+/// - `fatal`: panic.
+/// - `diverging`: panic, for exceptions whose handler must not return (`-> !`).
+macro_rules! define_exception_handler {
+    ($name:ident, $msg:literal, $kind:ident) => {
+        define_exception_handler!(@handler $kind, $name, (stack_frame: InterruptStackFrame) {
+            concat!($msg, "\n{:#?}"), stack_frame
+        });
+    };
+    ($name:ident, $msg:literal, error_code, $kind:ident) => {
+        define_exception_handler!(@handler $kind, $name, (stack_frame: InterruptStackFrame, error_code: u64) {
+            concat!($msg, ": error_code=0x{:x}\n{:#?}"), error_code, stack_frame
+        });
+    };
+    ($name:ident, $msg:literal, selector_error_code, $kind:ident) => {
+        define_exception_handler!(@handler $kind, $name, (stack_frame: InterruptStackFrame, error_code: u64) {
+            concat!($msg, ": error_code=0x{:x} ({})\n{:#?}"), error_code, SelectorError(error_code), stack_frame
+        });
+    };
+    (@handler recoverable, $name:ident, ($($params:tt)*) { $($args:tt)* }) => {
+        extern "x86-interrupt" fn $name($($params)*) {
+            log::warn!($($args)*);
+        }
+    };
+    (@handler fatal, $name:ident, ($($params:tt)*) { $($args:tt)* }) => {
+        extern "x86-interrupt" fn $name($($params)*) {
+            panic!($($args)*);
+        }
+    };
+    (@handler diverging, $name:ident, ($($params:tt)*) { $($args:tt)* }) => {
+        extern "x86-interrupt" fn $name($($params)*) -> ! {
+            panic!($($args)*);
+        }
+    };
+}
+
+define_exception_handler!(breakpoint_handler, "BREAKPOINT", recoverable);
+define_exception_handler!(overflow_handler, "OVERFLOW", recoverable);
+define_exception_handler!(double_fault_handler, "DOUBLE FAULT", error_code, diverging);
+define_exception_handler!(invalid_opcode_handler, "INVALID OPCODE", fatal);
+define_exception_handler!(
+    invalid_tss_handler,
+    "INVALID TSS",
+    selector_error_code,
+    fatal
+);
+define_exception_handler!(
+    segment_not_present_handler,
+    "SEGMENT NOT PRESENT",
+    selector_error_code,
+    fatal
+);
+define_exception_handler!(
+    stack_segment_fault_handler,
+    "STACK SEGMENT FAULT",
+    selector_error_code,
+    fatal
+);
+define_exception_handler!(
+    general_protection_fault_handler,
+    "GENERAL PROTECTION FAULT",
+    selector_error_code,
+    fatal
+);
+define_exception_handler!(machine_check_handler, "MACHINE CHECK", diverging);
+
+/// Maps the pages of reserved ranges when they're first touched (see
+/// [`crate::memory::vmm::VirtualMemoryManager::reserve`]), and panics on any other page fault.
+extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    panic!("DOUBLE FAULT:\n{:#?}", stack_frame);
+    error_code: PageFaultErrorCode,
+) {
+    let addr = Cr2::read();
+    if let Ok(addr) = addr {
+        if !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            // The VMM may be locked by the faulting code, in which case it can't be helped.
+            let vmm = crate::memory::VMM.get().and_then(|vmm| vmm.try_lock());
+            if vmm.is_some_and(|mut vmm| vmm.commit_page(addr)) {
+                return;
+            }
+        }
+    }
+    panic!("PAGE FAULT: addr={addr:?} error_code={error_code:?}\n{stack_frame:#?}");
+}
+
+static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of APIC timer interrupts received so far, one every [`APIC_TIMER_PERIOD_MS`].
+pub fn timer_ticks() -> u64 {
+    TIMER_TICKS.load(Ordering::Relaxed)
 }
 
+/// The timer period in TSC ticks in TSC-deadline mode, or zero in periodic mode.
+static TSC_DEADLINE_PERIOD: AtomicU64 = AtomicU64::new(0);
+static TSC_DEADLINE: AtomicU64 = AtomicU64::new(0);
+
 extern "x86-interrupt" fn apic_timer_handler(_stack_frame: InterruptStackFrame) {
     let mut regs = APIC_REGS.get().unwrap().clone();
-    log::info!("TIMER INTERRUPT");
+    TIMER_TICKS.fetch_add(1, Ordering::Relaxed);
+    let period = TSC_DEADLINE_PERIOD.load(Ordering::Relaxed);
+    if period != 0 {
+        // Advancing from the last deadline rather than the current time keeps the ticks from
+        // drifting.
+        let deadline = TSC_DEADLINE.fetch_add(period, Ordering::Relaxed) + period;
+        unsafe { regs.arm_tsc_deadline(deadline) };
+    }
     unsafe { regs.end_interrupt(()) };
 }
 
 extern "x86-interrupt" fn apic_error_handler(_stack_frame: InterruptStackFrame) {
     let mut regs = APIC_REGS.get().unwrap().clone();
-    log::info!("ERROR: {:?}", unsafe { regs.read_error_status() });
+    log::info!("ERROR: {:?}", unsafe { regs.take_error_status() });
     unsafe { regs.end_interrupt(()) };
 }
 
+/// Only every `SPURIOUS_LOG_INTERVAL`th spurious interrupt is logged, so that a storm of them
+/// doesn't drown out everything else.
+const SPURIOUS_LOG_INTERVAL: u64 = 1000;
+static SPURIOUS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Number of spurious interrupts received so far.
+pub fn spurious_count() -> u64 {
+    SPURIOUS_COUNT.load(Ordering::Relaxed)
+}
+
 extern "x86-interrupt" fn apic_spurious_handler(stack_frame: InterruptStackFrame) {
-    let mut regs = APIC_REGS.get().unwrap().clone();
-    log::info!("ERROR: {stack_frame:#?}");
-    unsafe { regs.end_interrupt(()) };
+    let count = SPURIOUS_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+    match count {
+        1 => log::warn!("SPURIOUS INTERRUPT\n{stack_frame:#?}"),
+        _ if count.is_multiple_of(SPURIOUS_LOG_INTERVAL) => {
+            log::warn!("SPURIOUS INTERRUPT: count={count}");
+        }
+        _ => {}
+    }
+    // Spurious interrupts don't set an ISR bit, so they mustn't be EOI'd.
+    if cfg!(debug_assertions) {
+        let vector = Interrupts::ApicSpurious as usize;
+        let isr = unsafe { APIC_REGS.get().unwrap().read_isr() };
+        debug_assert!(
+            isr[vector / 32] & (1 << (vector % 32)) == 0,
+            "spurious interrupt vector is in service",
+        );
+    }
 }
 
 pub fn init_idt() {
@@ -109,6 +274,49 @@ const IA_APIC_BASE_MSR_X2APIC: u64 = 1 << 10;
 
 static APIC_REGS: spin::Once<ApicRegs> = spin::Once::new();
 
+/// The local APIC id of the current CPU. Only the bootstrap processor runs before the APIC is
+/// initialized, so that's 0.
+pub fn lapic_id() -> u32 {
+    APIC_REGS.get().map_or(0, |regs| unsafe { regs.id() })
+}
+
+/// The period of the APIC timer interrupt.
+pub const APIC_TIMER_PERIOD_MS: u64 = 10;
+const APIC_TIMER_DIVIDE: DivideConfigurationRegister = DivideConfigurationRegister::DivideBy16;
+const APIC_TIMER_CALIBRATION_MS: u16 = 10;
+
+/// Frequency of the APIC timer (after the divider) in Hz, measured by `init_apic`.
+static APIC_TIMER_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+
+/// Frequency of the APIC timer (after the divider) in Hz, or zero before `init_apic`.
+pub fn apic_timer_frequency() -> u64 {
+    APIC_TIMER_FREQUENCY.load(Ordering::Relaxed)
+}
+
+/// The frequency in Hz of a counter that counted down from `start` to `end` in `ms` milliseconds.
+fn countdown_frequency(start: u32, end: u32, ms: u16) -> u64 {
+    (start - end) as u64 * 1000 / ms as u64
+}
+
+/// Measures the APIC timer's frequency against the PIT, running it as a masked one-shot.
+unsafe fn calibrate_apic_timer(regs: &mut ApicRegs) -> u64 {
+    unsafe {
+        let mut lvt = regs.read_lvt_timer();
+        lvt.set_mask(true);
+        lvt.set_timer_mode(TimerMode::OneShot);
+        regs.write_and_verify_lvt_timer(lvt);
+        regs.write_and_verify_timer_div(APIC_TIMER_DIVIDE);
+
+        regs.write_timer_init(u32::MAX);
+        let start = regs.read_current_count();
+        crate::pit::sleep_pit_ms(APIC_TIMER_CALIBRATION_MS);
+        let end = regs.read_current_count();
+        regs.write_timer_init(0);
+
+        countdown_frequency(start, end, APIC_TIMER_CALIBRATION_MS)
+    }
+}
+
 pub unsafe fn init_apic() {
     unsafe { disable_pic8259() };
     let Some(feature_info) = raw_cpuid::CpuId::new().get_feature_info() else {
@@ -137,7 +345,7 @@ pub unsafe fn init_apic() {
             .get()
             .expect("VMM not initialized")
             .lock()
-            .map(true, 4096, 12, apic_base_addr)
+            .map(true, 4096, 12, apic_base_addr, MappingFlags::DATA)
     }) else {
         panic!("Virtual memory mapping failed");
     };
@@ -147,25 +355,81 @@ pub unsafe fn init_apic() {
         .clone();
 
     unsafe {
+        let frequency = calibrate_apic_timer(&mut regs);
+        APIC_TIMER_FREQUENCY.store(frequency, Ordering::Relaxed);
+        log::info!("APIC timer: {frequency} Hz");
+
         let mut lvt = regs.read_lvt_timer();
         lvt.set_mask(false);
         lvt.set_vector(Interrupts::ApicTimer as _);
-        lvt.set_timer_mode(apic::lvt::TimerMode::Periodic);
-        regs.write_lvt_timer(lvt);
+        // TSC-deadline mode needs the TSC's frequency, which is only known if it's invariant.
+        match crate::time::tsc_frequency().filter(|_| regs.has_tsc_deadline()) {
+            Some(tsc_frequency) => {
+                lvt.set_timer_mode(TimerMode::TSCDeadline);
+                regs.write_and_verify_lvt_timer(lvt);
+                // Orders the LVT write before the deadline write.
+                _mm_mfence();
+
+                let period = tsc_frequency * APIC_TIMER_PERIOD_MS / 1000;
+                let deadline = _rdtsc() + period;
+                TSC_DEADLINE.store(deadline, Ordering::Relaxed);
+                TSC_DEADLINE_PERIOD.store(period, Ordering::Relaxed);
+                regs.arm_tsc_deadline(deadline);
+                log::info!("APIC timer in TSC-deadline mode");
+            }
+            None => {
+                lvt.set_timer_mode(TimerMode::Periodic);
+                regs.write_and_verify_lvt_timer(lvt);
 
-        regs.write_timer_div(apic::DivideConfigurationRegister::DivideBy128);
-        regs.write_timer_init(1 << 20);
+                let period = frequency * APIC_TIMER_PERIOD_MS / 1000;
+                regs.write_and_verify_timer_init(period.clamp(1, u32::MAX as _) as _);
+            }
+        }
 
         let mut lvt = regs.read_lvt_error();
         lvt.set_mask(false);
         lvt.set_vector(Interrupts::ApicError as _);
-        regs.write_lvt_error(lvt);
+        regs.write_and_verify_lvt_error(lvt);
 
         let mut svr = regs.read_svr();
         svr.set_vector(Interrupts::ApicSpurious as _);
-        regs.write_svr(svr);
+        regs.write_and_verify_svr(svr);
+
+        regs.dump();
     }
     drop(regs);
 
     x86_64::instructions::interrupts::enable();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn countdown_frequency_of_counts() {
+        assert_eq!(countdown_frequency(1000, 0, 1000), 1000);
+        assert_eq!(
+            countdown_frequency(u32::MAX, u32::MAX - 1_000_000, 10),
+            100_000_000
+        );
+        // Partial counts round down.
+        assert_eq!(countdown_frequency(10, 3, 3), 2333);
+        assert_eq!(countdown_frequency(5, 5, 10), 0);
+        // The whole range in a millisecond doesn't overflow.
+        assert_eq!(countdown_frequency(u32::MAX, 0, 1), u32::MAX as u64 * 1000);
+    }
+
+    #[test]
+    fn selector_error_display() {
+        let display = |code| alloc::format!("{}", SelectorError(code));
+        assert_eq!(display(0), "not caused by a selector");
+        // The kernel data selector, 0x10.
+        assert_eq!(display(0x10), "GDT index 2");
+        // Bit 0 is external, bits 1 and 2 the table, and the rest the index.
+        assert_eq!(display(13 << 3 | 0b011), "IDT index 13, external");
+        assert_eq!(display(0x21 << 3 | 0b010), "IDT index 33");
+        assert_eq!(display(5 << 3 | 0b100), "LDT index 5");
+        assert_eq!(display(0x28 | 0b001), "GDT index 5, external");
+    }
+}
@@ -1,3 +1,5 @@
+use core::fmt;
+
 pub struct LocalAPICVersion(pub u32);
 
 impl LocalAPICVersion {
@@ -34,3 +36,14 @@ impl LocalAPICVersion {
         (self.0 >> 24) & 1 != 0
     }
 }
+
+impl fmt::Debug for LocalAPICVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalAPICVersion")
+            .field("version", &self.version())
+            .field("max_lvt_entry", &self.max_lvt_entry())
+            .field("extended_apic_space", &self.extended_apic_space())
+            .field("support_eoi_broadcast", &self.support_eoi_broadcast())
+            .finish()
+    }
+}
@@ -5,10 +5,15 @@ pub mod lvt;
 pub mod prio_reg;
 pub mod svr;
 
-use x86_64::{instructions::interrupts::without_interrupts, registers::model_specific::Msr};
+use x86_64::{
+    instructions::interrupts::without_interrupts, registers::model_specific::Msr,
+    structures::paging::PhysFrame,
+};
 
 use esr::ErrorStatusRegister;
-use icr::InterruptCommandRegister;
+use icr::{
+    ICRDeliveryMode, ICRDeliveryStatus, ICRDestinationShorthand, ICRLevel, InterruptCommandRegister,
+};
 use lapic_ver::LocalAPICVersion;
 use lvt::LocalVectorTable;
 use svr::SpuriousInterruptVectorRegister;
@@ -35,6 +40,94 @@ pub enum TriggerMode {
     Level = 1,
 }
 
+/// Composes the message address and data of an MSI that delivers `vector` to the local APIC with
+/// the id `dest_apic`, with physical destination mode. See Section 11.11, "Message Signalled
+/// Interrupts".
+///
+/// Without interrupt remapping, only APIC ids up to 255 can be addressed.
+pub fn msi_message(
+    dest_apic: u32,
+    vector: u8,
+    mode: ICRDeliveryMode,
+    level_trigger: bool,
+) -> (u64, u32) {
+    debug_assert!(
+        dest_apic <= 0xFF,
+        "APIC id {dest_apic} can't be addressed by an MSI"
+    );
+    let addr = 0xFEE0_0000 | ((dest_apic & 0xFF) as u64) << 12;
+
+    let mut data = vector as u32 | (mode as u32) << 8;
+    if level_trigger {
+        // Level triggered messages are always asserts.
+        data |= 1 << 15 | 1 << 14;
+    }
+    (addr, data)
+}
+
+/// The ICR of an IPI with `vector` to the local APIC with the id `dest` (unless `shorthand` picks
+/// the destinations), with physical destination mode and an edge trigger.
+fn ipi_icr(
+    x2apic: bool,
+    dest: u32,
+    vector: u8,
+    delivery: ICRDeliveryMode,
+    shorthand: ICRDestinationShorthand,
+) -> InterruptCommandRegister {
+    let mut icr = InterruptCommandRegister(0);
+    icr.set_vector(vector);
+    icr.set_delivery_mode(delivery);
+    icr.set_level(ICRLevel::Assert);
+    icr.set_destination_shorthand(shorthand);
+    // xAPIC ids are 8 bits, in the top byte of the destination.
+    debug_assert!(
+        x2apic || dest <= 0xFF,
+        "APIC id {dest} can't be addressed in xAPIC mode"
+    );
+    icr.set_destination(match x2apic {
+        true => dest,
+        false => dest << 24,
+    });
+    icr
+}
+
+/// The INIT IPI and the two SIPIs of the INIT-SIPI-SIPI sequence that starts the AP with the local
+/// APIC id `apic_id` at `trampoline`. Panics if `trampoline` isn't below 1 MiB, since a SIPI's vector
+/// is the page number it starts at.
+fn ap_startup_icrs(
+    x2apic: bool,
+    apic_id: u32,
+    trampoline: PhysFrame,
+) -> [InterruptCommandRegister; 3] {
+    let vector = trampoline.start_address().as_u64() >> 12;
+    assert!(
+        vector <= 0xFF,
+        "AP trampoline {:?} isn't below 1 MiB",
+        trampoline.start_address(),
+    );
+    let icr = |vector, delivery| {
+        ipi_icr(
+            x2apic,
+            apic_id,
+            vector,
+            delivery,
+            ICRDestinationShorthand::NoShorthand,
+        )
+    };
+    [
+        icr(0, ICRDeliveryMode::INIT),
+        icr(vector as _, ICRDeliveryMode::StartUp),
+        icr(vector as _, ICRDeliveryMode::StartUp),
+    ]
+}
+
+/// The highest vector set in a 256-bit ISR/IRR/TMR style bitmap, as assembled by [`ApicRegs::read_isr`]
+/// and friends: bit `b` of word `i` is vector `32 * i + b`.
+fn highest_vector(bits: [u32; 8]) -> Option<u8> {
+    let (i, word) = bits.iter().enumerate().rfind(|(_, &word)| word != 0)?;
+    Some((32 * i + 31 - word.leading_zeros() as usize) as _)
+}
+
 trait X2ApicReadReg {
     fn read_reg32(reg32: u32) -> Self;
 }
@@ -82,6 +175,7 @@ impl X2ApicWriteReg for () {
 }
 
 /// Divide Configuration Register (DCR; for Timer)
+#[derive(Debug)]
 pub enum DivideConfigurationRegister {
     DivideBy1 = 0b1011,
     DivideBy2 = 0b0000,
@@ -114,9 +208,12 @@ impl X2ApicWriteReg for DivideConfigurationRegister {
     }
 }
 
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+
 #[derive(Clone)]
 pub struct ApicRegs {
     x2apic: bool,
+    tsc_deadline: bool,
     base_addr: *mut u32,
 }
 
@@ -125,7 +222,18 @@ unsafe impl Send for ApicRegs {}
 
 impl ApicRegs {
     pub unsafe fn new(x2apic: bool, base_addr: *mut u32) -> Self {
-        Self { x2apic, base_addr }
+        let tsc_deadline = (raw_cpuid::CpuId::new().get_feature_info())
+            .is_some_and(|info| info.has_tsc_deadline());
+        Self {
+            x2apic,
+            tsc_deadline,
+            base_addr,
+        }
+    }
+
+    /// Whether the timer supports [`TimerMode::TSCDeadline`](lvt::TimerMode::TSCDeadline).
+    pub fn has_tsc_deadline(&self) -> bool {
+        self.tsc_deadline
     }
 }
 
@@ -161,16 +269,42 @@ macro_rules! apic_regs {
             }
         }
     };
+    (
+        $(#[$attr:meta])*
+        $name:ident: WriteVerify<$ty:ty, $write:ident, $read:ident, $mask:literal $(,)?>$(,)?
+    ) => {
+        $(#[$attr])*
+        ///
+        /// In debug builds, reads the register back and warns if any of the bits in the mask
+        /// (the writable ones) didn't take.
+        ///
+        /// # Safety
+        /// Same as writing the register directly.
+        pub unsafe fn $name(&mut self, value: $ty) {
+            let bits = X2ApicWriteReg::write_reg32(value);
+            unsafe { self.$write(X2ApicReadReg::read_reg32(bits)) };
+            if cfg!(debug_assertions) {
+                let read_back = X2ApicWriteReg::write_reg32(unsafe { self.$read() });
+                if (read_back ^ bits) & $mask != 0 {
+                    log::warn!(
+                        concat!(stringify!($write), ": wrote 0x{:08x}, but read back 0x{:08x}"),
+                        bits,
+                        read_back,
+                    );
+                }
+            }
+        }
+    };
     (
         $(
             $(#[$attr:meta])*
-            $name:ident: $act:ident<$ty:ty, $msr_addr:literal, $offset_addr:literal $(,)?>
+            $name:ident: $act:ident<$($args:tt),+>
         ),+ $(,)?
     ) => {
         $(
             apic_regs! {
                 $(#[$attr])*
-                $name: $act<$ty, $msr_addr, $offset_addr>
+                $name: $act<$($args),+>
             }
         )*
     };
@@ -307,6 +441,145 @@ impl ApicRegs {
         }
     }
 
+    /// In-Service Register (ISR), with vector `v` at bit `v % 32` of word `v / 32`.
+    ///
+    /// # Safety
+    /// Same as reading the registers directly.
+    pub unsafe fn read_isr(&self) -> [u32; 8] {
+        unsafe {
+            [
+                self.read_isr0(),
+                self.read_isr1(),
+                self.read_isr2(),
+                self.read_isr3(),
+                self.read_isr4(),
+                self.read_isr5(),
+                self.read_isr6(),
+                self.read_isr7(),
+            ]
+        }
+    }
+    /// Trigger Mode Register (TMR), with vector `v` at bit `v % 32` of word `v / 32`.
+    ///
+    /// # Safety
+    /// Same as reading the registers directly.
+    pub unsafe fn read_tmr(&self) -> [u32; 8] {
+        unsafe {
+            [
+                self.read_tmr0(),
+                self.read_tmr1(),
+                self.read_tmr2(),
+                self.read_tmr3(),
+                self.read_tmr4(),
+                self.read_tmr5(),
+                self.read_tmr6(),
+                self.read_tmr7(),
+            ]
+        }
+    }
+    /// Interrupt Request Register (IRR), with vector `v` at bit `v % 32` of word `v / 32`.
+    ///
+    /// # Safety
+    /// Same as reading the registers directly.
+    pub unsafe fn read_irr(&self) -> [u32; 8] {
+        unsafe {
+            [
+                self.read_irr0(),
+                self.read_irr1(),
+                self.read_irr2(),
+                self.read_irr3(),
+                self.read_irr4(),
+                self.read_irr5(),
+                self.read_irr6(),
+                self.read_irr7(),
+            ]
+        }
+    }
+
+    /// The highest priority vector waiting in the IRR.
+    ///
+    /// # Safety
+    /// Same as reading the registers directly.
+    pub unsafe fn highest_pending_vector(&self) -> Option<u8> {
+        highest_vector(unsafe { self.read_irr() })
+    }
+
+    /// The errors detected since the last call. The ESR only updates when it's written, so this
+    /// writes it before reading it (see Section 11.5.3, "Error Handling").
+    ///
+    /// # Safety
+    /// Same as accessing the register directly.
+    pub unsafe fn take_error_status(&mut self) -> ErrorStatusRegister {
+        unsafe {
+            self.write_error_status(());
+            self.read_error_status()
+        }
+    }
+
+    /// Fires the timer when the TSC reaches `deadline`, or disarms it if `deadline` is zero. The
+    /// timer must be in [`TimerMode::TSCDeadline`](lvt::TimerMode::TSCDeadline).
+    ///
+    /// # Safety
+    /// Same as writing the register directly.
+    pub unsafe fn arm_tsc_deadline(&mut self, deadline: u64) {
+        assert!(self.tsc_deadline, "TSC-deadline mode isn't supported");
+        let mut msr = Msr::new(IA32_TSC_DEADLINE);
+        unsafe { msr.write(deadline) };
+    }
+
+    /// Sends an IPI with `vector` to the local APIC with the id `dest` (unless `shorthand` picks
+    /// the destinations), with physical destination mode and an edge trigger. In xAPIC mode, this
+    /// waits until the IPI is sent.
+    ///
+    /// # Safety
+    /// Same as writing the ICR directly.
+    pub unsafe fn send_ipi(
+        &mut self,
+        dest: u32,
+        vector: u8,
+        delivery: ICRDeliveryMode,
+        shorthand: ICRDestinationShorthand,
+    ) {
+        let icr = ipi_icr(self.x2apic, dest, vector, delivery, shorthand);
+        unsafe { self.send_icr(icr) };
+    }
+
+    /// Writes `icr`, and in xAPIC mode waits until the IPI is sent.
+    ///
+    /// # Safety
+    /// Same as writing the ICR directly.
+    unsafe fn send_icr(&mut self, icr: InterruptCommandRegister) {
+        unsafe { self.write_icr(icr) };
+        if !self.x2apic {
+            while matches!(
+                unsafe { self.read_icr() }.delivery_status(),
+                ICRDeliveryStatus::SendPending
+            ) {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Starts the application processor with the local APIC id `apic_id` in real mode at
+    /// `trampoline`, with the INIT-SIPI-SIPI sequence (see Section 9.4.4, "MP Initialization
+    /// Example"). The delays are busy-waited on the PIT.
+    ///
+    /// # Safety
+    /// `trampoline` must be below 1 MiB and contain the AP's startup code, and `apic_id` must be
+    /// an AP that hasn't been started yet.
+    pub unsafe fn boot_ap(&mut self, apic_id: u32, trampoline: PhysFrame) {
+        let [init, sipis @ ..] = ap_startup_icrs(self.x2apic, apic_id, trampoline);
+        unsafe {
+            self.send_icr(init);
+            crate::pit::sleep_pit_ms(10);
+            for sipi in sipis {
+                self.send_icr(sipi);
+                // The manual asks for 200 us, but the PIT sleeps in milliseconds.
+                crate::pit::sleep_pit_ms(1);
+            }
+        }
+    }
+
     apic_regs! {
         /// Specifies interrupt delivery when the APIC timer signals an interrupt (see Section 11.5.4, "APIC Timer").
         read_lvt_timer: Read<LocalVectorTable, 0x832, 0x320>,
@@ -350,6 +623,68 @@ impl ApicRegs {
         write_timer_div: Write<DivideConfigurationRegister, 0x83E, 0x3E0>,
     }
 
+    apic_regs! {
+        /// Specifies interrupt delivery when the APIC timer signals an interrupt.
+        write_and_verify_lvt_timer: WriteVerify<LocalVectorTable, write_lvt_timer, read_lvt_timer, 0x7A7FF>,
+        /// Specifies interrupt delivery when the APIC detects an internal error.
+        write_and_verify_lvt_error: WriteVerify<LocalVectorTable, write_lvt_error, read_lvt_error, 0x7A7FF>,
+        /// Initial Count register (for Timer)
+        write_and_verify_timer_init: WriteVerify<u32, write_timer_init, read_initial_count, 0xFFFF_FFFF>,
+        /// Divide Configuration Register (DCR; for Timer)
+        write_and_verify_timer_div: WriteVerify<DivideConfigurationRegister, write_timer_div, read_dcr, 0b1011>,
+        /// Spurious Interrupt Vector Register (SVR). The low 4 bits of the vector are hardwired on
+        /// P6 family processors, so only the upper ones are checked.
+        write_and_verify_svr: WriteVerify<SpuriousInterruptVectorRegister, write_svr, read_svr, 0x13F0>,
+    }
+
+    /// The local APIC id, which is in the top byte of the register in xAPIC mode.
+    ///
+    /// # Safety
+    /// Same as reading the registers directly.
+    pub unsafe fn id(&self) -> u32 {
+        let reg = unsafe { self.read_lapid_id() };
+        match self.x2apic {
+            true => reg,
+            false => reg >> 24,
+        }
+    }
+
+    /// Logs the local APIC's id, version, SVR, priorities, timer configuration and every LVT entry
+    /// the APIC has.
+    ///
+    /// # Safety
+    /// Same as reading the registers directly.
+    pub unsafe fn dump(&self) {
+        unsafe {
+            let version = self.read_lapic_ver();
+            let max_lvt_entry = version.max_lvt_entry();
+            let id = self.id();
+            log::info!("Local APIC: id={id} x2apic={} {version:?}", self.x2apic);
+            log::info!("  SVR: {:?}", self.read_svr());
+            log::info!("  TPR: {:?} PPR: {:?}", self.read_tpr(), self.read_ppr());
+            log::info!("  LVT timer: {:?}", self.read_lvt_timer());
+            log::info!(
+                "  Timer: divide={:?} initial_count={} current_count={}",
+                self.read_dcr(),
+                self.read_initial_count(),
+                self.read_current_count(),
+            );
+            log::info!("  LVT LINT0: {:?}", self.read_lvt_lint0());
+            log::info!("  LVT LINT1: {:?}", self.read_lvt_lint1());
+            log::info!("  LVT error: {:?}", self.read_lvt_error());
+            // The optional entries, in the order processors added them.
+            if 4 <= max_lvt_entry {
+                log::info!("  LVT perfmon: {:?}", self.read_lvt_perfmon());
+            }
+            if 5 <= max_lvt_entry {
+                log::info!("  LVT thermal: {:?}", self.read_lvt_thermal());
+            }
+            if 6 <= max_lvt_entry {
+                log::info!("  LVT CMCI: {:?}", self.read_lvt_cmci());
+            }
+        }
+    }
+
     /// TODO: what is SELF IPI
     ///
     /// Only available in x2APIC (not xAPIC).
@@ -358,3 +693,138 @@ impl ApicRegs {
         unsafe { msr.write(value as _) };
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use x86_64::PhysAddr;
+
+    use super::*;
+
+    #[test]
+    fn ipi_icr_bits() {
+        let icr = |x2apic, dest, vector, delivery, shorthand| {
+            ipi_icr(x2apic, dest, vector, delivery, shorthand).0
+        };
+        const ASSERT: u64 = 1 << 14;
+
+        // The xAPIC destination is in bits 56..64, and the x2APIC one in bits 32..64.
+        let fixed = icr(
+            false,
+            0x12,
+            0x40,
+            ICRDeliveryMode::Fixed,
+            ICRDestinationShorthand::NoShorthand,
+        );
+        assert_eq!(fixed, 0x12 << 56 | ASSERT | 0x40);
+        let fixed = icr(
+            true,
+            0x1234,
+            0x40,
+            ICRDeliveryMode::Fixed,
+            ICRDestinationShorthand::NoShorthand,
+        );
+        assert_eq!(fixed, 0x1234 << 32 | ASSERT | 0x40);
+
+        let nmi = icr(
+            false,
+            0,
+            0,
+            ICRDeliveryMode::NMI,
+            ICRDestinationShorthand::AllExcludingSelf,
+        );
+        assert_eq!(nmi, 0b11 << 18 | ASSERT | 0b100 << 8);
+        let init = icr(
+            true,
+            7,
+            0,
+            ICRDeliveryMode::INIT,
+            ICRDestinationShorthand::NoShorthand,
+        );
+        assert_eq!(init, 7 << 32 | ASSERT | 0b101 << 8);
+        let startup = icr(
+            false,
+            0xFF,
+            0x08,
+            ICRDeliveryMode::StartUp,
+            ICRDestinationShorthand::NoShorthand,
+        );
+        assert_eq!(startup, 0xFF << 56 | ASSERT | 0b110 << 8 | 0x08);
+        let myself = icr(
+            false,
+            0,
+            0x50,
+            ICRDeliveryMode::Fixed,
+            ICRDestinationShorthand::Myself,
+        );
+        assert_eq!(myself, 0b01 << 18 | ASSERT | 0x50);
+    }
+
+    #[test]
+    #[should_panic = "can't be addressed in xAPIC mode"]
+    fn ipi_icr_rejects_wide_xapic_ids() {
+        ipi_icr(
+            false,
+            0x100,
+            0x40,
+            ICRDeliveryMode::Fixed,
+            ICRDestinationShorthand::NoShorthand,
+        );
+    }
+
+    #[test]
+    fn ap_startup_is_init_sipi_sipi() {
+        let trampoline = PhysFrame::containing_address(PhysAddr::new(0x8000));
+        let icrs = ap_startup_icrs(false, 3, trampoline).map(|icr| icr.0);
+        assert_eq!(
+            icrs,
+            [
+                3 << 56 | 1 << 14 | 0b101 << 8,
+                3 << 56 | 1 << 14 | 0b110 << 8 | 0x08,
+                3 << 56 | 1 << 14 | 0b110 << 8 | 0x08,
+            ]
+        );
+
+        let trampoline = PhysFrame::containing_address(PhysAddr::new(0xF_F000));
+        let icrs = ap_startup_icrs(true, 0x1234, trampoline).map(|icr| icr.0);
+        assert_eq!(
+            icrs,
+            [
+                0x1234 << 32 | 1 << 14 | 0b101 << 8,
+                0x1234 << 32 | 1 << 14 | 0b110 << 8 | 0xFF,
+                0x1234 << 32 | 1 << 14 | 0b110 << 8 | 0xFF,
+            ]
+        );
+    }
+
+    #[test]
+    #[should_panic = "isn't below 1 MiB"]
+    fn ap_startup_rejects_trampolines_above_1_mib() {
+        ap_startup_icrs(
+            false,
+            3,
+            PhysFrame::containing_address(PhysAddr::new(0x10_0000)),
+        );
+    }
+
+    #[test]
+    fn highest_vector_of_bitmaps() {
+        assert_eq!(highest_vector([0; 8]), None);
+
+        let mut bits = [0; 8];
+        bits[0] = 1;
+        assert_eq!(highest_vector(bits), Some(0));
+        bits[0] = 1 << 17 | 1 << 3;
+        assert_eq!(highest_vector(bits), Some(17));
+        bits[7] = 1 << 31;
+        assert_eq!(highest_vector(bits), Some(255));
+
+        // Every vector lands at bit `vector % 32` of word `vector / 32`, with lower ones ignored.
+        for vector in 0..=255u8 {
+            let mut bits = [0; 8];
+            bits[vector as usize / 32] |= 1 << (vector % 32);
+            assert_eq!(highest_vector(bits), Some(vector));
+            bits[0] |= 1;
+            assert_eq!(highest_vector(bits), Some(vector));
+        }
+    }
+}
@@ -1,3 +1,5 @@
+use core::fmt;
+
 pub struct PriorityRegisiter(u32);
 
 impl PriorityRegisiter {
@@ -25,3 +27,12 @@ impl PriorityRegisiter {
         self.0 |= 15 & subclass as u32;
     }
 }
+
+impl fmt::Debug for PriorityRegisiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PriorityRegisiter")
+            .field("class", &self.class())
+            .field("subclass", &self.subclass())
+            .finish()
+    }
+}
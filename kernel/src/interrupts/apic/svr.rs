@@ -1,3 +1,5 @@
+use core::fmt;
+
 /// A special situation may occur when a processor raises its task priority to be greater than or equal to the level of the
 /// interrupt for which the processor INTR signal is currently being asserted. If at the time the INTA cycle is issued, the
 /// interrupt that was to be dispensed has become masked (programmed by software), the local APIC will deliver a
@@ -92,3 +94,17 @@ impl SpuriousInterruptVectorRegister {
         self.0 |= (enabled as u32) << 12;
     }
 }
+
+impl fmt::Debug for SpuriousInterruptVectorRegister {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SpuriousInterruptVectorRegister")
+            .field("vector", &self.vector())
+            .field("apic_enabled", &self.apic_enabled())
+            .field("focus_processor_checking", &self.focus_processor_checking())
+            .field(
+                "eoi_broadcast_suppression",
+                &self.eoi_broadcast_suppression(),
+            )
+            .finish()
+    }
+}
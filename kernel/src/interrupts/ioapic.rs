@@ -0,0 +1,159 @@
+//! The I/O APIC, which routes the legacy ISA IRQs to local APIC vectors now that the 8259 PIC is
+//! disabled. Only the I/O APIC handling GSI 0 is used.
+
+use core::mem;
+
+use x86_64::PhysAddr;
+
+use crate::{acpi, memory, memory::MappingFlags};
+
+/// The I/O APIC's address when there's no MADT to say otherwise.
+const DEFAULT_IOAPIC_ADDR: u64 = 0xFEC0_0000;
+
+/// Register select and data window offsets, in bytes.
+const IOREGSEL: usize = 0x00;
+const IOWIN: usize = 0x10;
+/// The redirection table, two registers per entry.
+const IOREDTBL: u32 = 0x10;
+
+/// MADT entry types.
+const MADT_IOAPIC: u8 = 1;
+const MADT_INTERRUPT_SOURCE_OVERRIDE: u8 = 2;
+/// Where the MADT's entries start, after the header, the local APIC address and the flags.
+const MADT_ENTRIES_OFFSET: usize = mem::size_of::<acpi::SdtHeader>() + 8;
+
+/// MPS INTI flags of an interrupt source override.
+const INTI_POLARITY_MASK: u16 = 0b11;
+const INTI_ACTIVE_LOW: u16 = 0b11;
+const INTI_TRIGGER_MASK: u16 = 0b11 << 2;
+const INTI_LEVEL_TRIGGERED: u16 = 0b11 << 2;
+
+/// Redirection entry bits.
+const REDIRECTION_ACTIVE_LOW: u32 = 1 << 13;
+const REDIRECTION_LEVEL_TRIGGERED: u32 = 1 << 15;
+
+/// How an ISA IRQ is connected to the I/O APIC.
+#[derive(Debug, Clone, Copy)]
+struct IsaOverride {
+    gsi: u32,
+    flags: u16,
+}
+
+struct IoApic {
+    base: *mut u32,
+    /// The ISA IRQs that aren't identity mapped to GSIs, from the MADT.
+    overrides: [Option<IsaOverride>; 16],
+}
+
+unsafe impl Send for IoApic {}
+
+static IOAPIC: spin::Once<spin::Mutex<IoApic>> = spin::Once::new();
+
+impl IoApic {
+    unsafe fn read(&mut self, reg: u32) -> u32 {
+        unsafe {
+            self.base.byte_add(IOREGSEL).write_volatile(reg);
+            self.base.byte_add(IOWIN).read_volatile()
+        }
+    }
+
+    unsafe fn write(&mut self, reg: u32, value: u32) {
+        unsafe {
+            self.base.byte_add(IOREGSEL).write_volatile(reg);
+            self.base.byte_add(IOWIN).write_volatile(value);
+        }
+    }
+}
+
+/// Finds the I/O APIC for GSI 0 and the ISA overrides in the MADT, falling back to the default
+/// address and no overrides without one.
+fn parse_madt() -> (PhysAddr, [Option<IsaOverride>; 16]) {
+    let mut ioapic_addr = PhysAddr::new(DEFAULT_IOAPIC_ADDR);
+    let mut overrides = [None; 16];
+    let Some(madt) = acpi::find_table(b"APIC") else {
+        log::warn!("No MADT, assuming the I/O APIC is at {ioapic_addr:?}");
+        return (ioapic_addr, overrides);
+    };
+    let header: acpi::SdtHeader = unsafe { memory::read_phys(madt) };
+    let mut offset = MADT_ENTRIES_OFFSET;
+    while offset + 2 <= header.length as usize {
+        let [kind, len]: [u8; 2] = unsafe { memory::read_phys(madt + offset as u64) };
+        if len < 2 {
+            break;
+        }
+        let entry = madt + offset as u64;
+        match kind {
+            MADT_IOAPIC => {
+                let addr: u32 = unsafe { memory::read_phys(entry + 4) };
+                let gsi_base: u32 = unsafe { memory::read_phys(entry + 8) };
+                if gsi_base == 0 {
+                    ioapic_addr = PhysAddr::new(addr as _);
+                }
+            }
+            MADT_INTERRUPT_SOURCE_OVERRIDE => {
+                let source: u8 = unsafe { memory::read_phys(entry + 3) };
+                let gsi: u32 = unsafe { memory::read_phys(entry + 4) };
+                let flags: u16 = unsafe { memory::read_phys(entry + 8) };
+                if let Some(slot) = overrides.get_mut(source as usize) {
+                    *slot = Some(IsaOverride { gsi, flags });
+                }
+            }
+            _ => {}
+        }
+        offset += len as usize;
+    }
+    (ioapic_addr, overrides)
+}
+
+fn ioapic() -> &'static spin::Mutex<IoApic> {
+    IOAPIC.call_once(|| {
+        let (addr, overrides) = parse_madt();
+        let base = unsafe {
+            crate::memory::VMM
+                .get()
+                .expect("VMM not initialized")
+                .lock()
+                .map(true, 4096, 12, addr, MappingFlags::DATA)
+        };
+        let Some(base) = base else {
+            panic!("Virtual memory mapping failed");
+        };
+        spin::Mutex::new(IoApic {
+            base: base.as_mut_ptr(),
+            overrides,
+        })
+    })
+}
+
+/// Routes the ISA IRQ `irq` to `vector` on the local APIC with the id `dest_apic`, with the
+/// polarity and trigger mode the firmware reports.
+///
+/// # Safety
+/// `vector` must have a handler that EOIs.
+pub unsafe fn route_isa_irq(irq: u8, vector: u8, dest_apic: u32) {
+    assert!(irq < 16, "ISA IRQ {irq} doesn't exist");
+    let mut ioapic = ioapic().lock();
+    let (gsi, flags) = match ioapic.overrides[irq as usize] {
+        Some(IsaOverride { gsi, flags }) => (gsi, flags),
+        None => (irq as u32, 0),
+    };
+    // ISA IRQs default to active high and edge triggered.
+    let mut low = vector as u32;
+    if flags & INTI_POLARITY_MASK == INTI_ACTIVE_LOW {
+        low |= REDIRECTION_ACTIVE_LOW;
+    }
+    if flags & INTI_TRIGGER_MASK == INTI_LEVEL_TRIGGERED {
+        low |= REDIRECTION_LEVEL_TRIGGERED;
+    }
+    let reg = IOREDTBL + 2 * gsi;
+    unsafe {
+        // The entry is masked while it's half written, since the mask bit is in the low half.
+        ioapic.write(reg, 1 << 16);
+        ioapic.write(reg + 1, dest_apic << 24);
+        ioapic.write(reg, low);
+    }
+    log::debug!(
+        "ISA IRQ {irq} -> GSI {gsi} -> vector {vector}: {:#x}",
+        unsafe { ioapic.read(reg) },
+    );
+}
@@ -0,0 +1,149 @@
+//! Vectors that drivers can claim at runtime with [`register_handler`], instead of adding their own
+//! handler to the IDT.
+
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, Ordering},
+};
+
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+/// The first vector that can be registered.
+pub const IRQ_VECTOR_BASE: u8 = 64;
+/// The number of vectors that can be registered, starting at [`IRQ_VECTOR_BASE`].
+pub const IRQ_VECTOR_COUNT: usize = 32;
+
+/// The registered handlers, as `fn()` pointers, or null.
+static HANDLERS: [AtomicPtr<()>; IRQ_VECTOR_COUNT] =
+    [const { AtomicPtr::new(ptr::null_mut()) }; IRQ_VECTOR_COUNT];
+
+fn slot(vector: u8) -> &'static AtomicPtr<()> {
+    assert!(
+        (IRQ_VECTOR_BASE..IRQ_VECTOR_BASE + IRQ_VECTOR_COUNT as u8).contains(&vector),
+        "vector {vector} can't be registered",
+    );
+    &HANDLERS[(vector - IRQ_VECTOR_BASE) as usize]
+}
+
+/// Calls `handler` on every interrupt on `vector`, and EOIs it afterwards. Returns false if the
+/// vector already has a handler.
+pub fn register_handler(vector: u8, handler: fn()) -> bool {
+    slot(vector)
+        .compare_exchange(
+            ptr::null_mut(),
+            handler as *mut (),
+            Ordering::AcqRel,
+            Ordering::Relaxed,
+        )
+        .is_ok()
+}
+
+/// Removes the handler of `vector`, returning it.
+pub fn unregister_handler(vector: u8) -> Option<fn()> {
+    let handler = slot(vector).swap(ptr::null_mut(), Ordering::AcqRel);
+    // SAFETY: Only `fn()`s are stored in the slots.
+    (!handler.is_null()).then(|| unsafe { core::mem::transmute::<*mut (), fn()>(handler) })
+}
+
+/// Calls the handler registered for `vector`, and then `end_interrupt`.
+fn dispatch(vector: u8, end_interrupt: impl FnOnce()) {
+    let handler = slot(vector).load(Ordering::Acquire);
+    match handler.is_null() {
+        // SAFETY: Only `fn()`s are stored in the slots.
+        false => unsafe { core::mem::transmute::<*mut (), fn()>(handler)() },
+        true => log::warn!("Unhandled interrupt on vector {vector}"),
+    }
+    end_interrupt();
+}
+
+extern "x86-interrupt" fn shim<const VECTOR: u8>(_stack_frame: InterruptStackFrame) {
+    dispatch(VECTOR, || {
+        let mut regs = super::APIC_REGS.get().unwrap().clone();
+        unsafe { regs.end_interrupt(()) };
+    });
+}
+
+macro_rules! set_shims {
+    ($idt:ident, $($offset:literal)*) => {
+        $(
+            $idt[IRQ_VECTOR_BASE + $offset].set_handler_fn(shim::<{ IRQ_VECTOR_BASE + $offset }>);
+        )*
+        const _: () = assert!([$($offset),*].len() == IRQ_VECTOR_COUNT);
+    };
+}
+
+/// Points the registrable vectors at the dispatching shims.
+pub(super) fn set_handlers(idt: &mut InterruptDescriptorTable) {
+    set_shims!(
+        idt, 0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use core::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    // The handlers are global, so each test uses its own vectors.
+
+    #[test]
+    fn register_and_unregister() {
+        // Function pointers can't be compared reliably, so the handlers are told apart by calling
+        // them.
+        static CALLED: AtomicUsize = AtomicUsize::new(0);
+        fn first() {
+            CALLED.store(1, Ordering::Relaxed);
+        }
+        fn second() {
+            CALLED.store(2, Ordering::Relaxed);
+        }
+        let unregister = |vector| {
+            unregister_handler(vector).map(|handler| {
+                handler();
+                CALLED.load(Ordering::Relaxed)
+            })
+        };
+        let vector = IRQ_VECTOR_BASE;
+
+        assert_eq!(unregister(vector), None);
+        assert!(register_handler(vector, first));
+        assert!(!register_handler(vector, second));
+        assert_eq!(unregister(vector), Some(1));
+        assert_eq!(unregister(vector), None);
+
+        assert!(register_handler(vector, second));
+        assert_eq!(unregister(vector), Some(2));
+    }
+
+    #[test]
+    fn dispatch_calls_the_handler_then_ends_the_interrupt() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+        fn handler() {
+            CALLS.fetch_add(1, Ordering::Relaxed);
+        }
+        let vector = IRQ_VECTOR_BASE + 1;
+
+        let mut ends = 0;
+        dispatch(vector, || ends += 1);
+        assert_eq!((CALLS.load(Ordering::Relaxed), ends), (0, 1));
+
+        assert!(register_handler(vector, handler));
+        dispatch(vector, || {
+            assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+            ends += 1;
+        });
+        assert_eq!((CALLS.load(Ordering::Relaxed), ends), (1, 2));
+
+        unregister_handler(vector);
+        dispatch(vector, || ends += 1);
+        assert_eq!((CALLS.load(Ordering::Relaxed), ends), (1, 3));
+    }
+
+    #[test]
+    #[should_panic = "can't be registered"]
+    fn vectors_out_of_range_panic() {
+        fn handler() {}
+        register_handler(IRQ_VECTOR_BASE + IRQ_VECTOR_COUNT as u8, handler);
+    }
+}
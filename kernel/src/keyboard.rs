@@ -0,0 +1,409 @@
+//! The PS/2 keyboard: decodes scan code set 1 from the 8042 controller into [`KeyEvent`]s, and
+//! queues the characters they type for [`read_char`].
+
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+use crate::{interrupts::irq, ring_buffer::RingBuffer};
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+/// Status bit set when the output buffer has a byte for us.
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// Status bit set when the byte in the output buffer is from the mouse.
+const STATUS_AUX_DATA: u8 = 1 << 5;
+
+/// The ISA IRQ of the keyboard.
+const KEYBOARD_IRQ: u8 = 1;
+/// The vector the keyboard interrupt is routed to.
+pub const KEYBOARD_VECTOR: u8 = irq::IRQ_VECTOR_BASE + KEYBOARD_IRQ;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// A key that types a character, given unshifted (`a`, `1`, `;`, ...). Keypad keys type their
+    /// digits and operators.
+    Char(char),
+    Escape,
+    Backspace,
+    Tab,
+    Enter,
+    LeftCtrl,
+    RightCtrl,
+    LeftShift,
+    RightShift,
+    LeftAlt,
+    RightAlt,
+    CapsLock,
+    NumLock,
+    ScrollLock,
+    /// The function keys F1 to F12.
+    F(u8),
+    Up,
+    Down,
+    Left,
+    Right,
+    Home,
+    End,
+    PageUp,
+    PageDown,
+    Insert,
+    Delete,
+    /// A scan code without a meaning here, after an `0xE0` prefix if `extended`.
+    Unknown {
+        code: u8,
+        extended: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub key: Key,
+    pub pressed: bool,
+}
+
+/// Unprefixed set 1 make codes, from `0x00`.
+const KEYS: [Option<Key>; 0x59] = {
+    use Key::*;
+    let mut keys = [None; 0x59];
+    let chars: [(u8, &[u8]); 6] = [
+        (0x02, b"1234567890-="),
+        (0x10, b"qwertyuiop[]"),
+        (0x1E, b"asdfghjkl;'`"),
+        (0x2B, b"\\zxcvbnm,./"),
+        (0x47, b"789-456+1230."),
+        (0x37, b"*"),
+    ];
+    let mut i = 0;
+    while i < chars.len() {
+        let (start, chars) = chars[i];
+        let mut j = 0;
+        while j < chars.len() {
+            keys[start as usize + j] = Some(Char(chars[j] as char));
+            j += 1;
+        }
+        i += 1;
+    }
+    let mut i = 0;
+    while i < 10 {
+        keys[0x3B + i] = Some(F(i as u8 + 1));
+        i += 1;
+    }
+    keys[0x01] = Some(Escape);
+    keys[0x0E] = Some(Backspace);
+    keys[0x0F] = Some(Tab);
+    keys[0x1C] = Some(Enter);
+    keys[0x1D] = Some(LeftCtrl);
+    keys[0x2A] = Some(LeftShift);
+    keys[0x36] = Some(RightShift);
+    keys[0x38] = Some(LeftAlt);
+    keys[0x39] = Some(Char(' '));
+    keys[0x3A] = Some(CapsLock);
+    keys[0x45] = Some(NumLock);
+    keys[0x46] = Some(ScrollLock);
+    keys[0x57] = Some(F(11));
+    keys[0x58] = Some(F(12));
+    keys
+};
+
+/// The make code after an `0xE0` prefix.
+fn extended_key(code: u8) -> Option<Key> {
+    Some(match code {
+        0x1C => Key::Enter,
+        0x1D => Key::RightCtrl,
+        0x35 => Key::Char('/'),
+        0x38 => Key::RightAlt,
+        0x47 => Key::Home,
+        0x48 => Key::Up,
+        0x49 => Key::PageUp,
+        0x4B => Key::Left,
+        0x4D => Key::Right,
+        0x4F => Key::End,
+        0x50 => Key::Down,
+        0x51 => Key::PageDown,
+        0x52 => Key::Insert,
+        0x53 => Key::Delete,
+        _ => return None,
+    })
+}
+
+/// The character a shifted key types, from its unshifted one.
+fn shifted(ch: char) -> char {
+    const UNSHIFTED: &[u8] = b"1234567890-=[];'`\\,./";
+    const SHIFTED: &[u8] = b"!@#$%^&*()_+{}:\"~|<>?";
+    match UNSHIFTED.iter().position(|&c| c as char == ch) {
+        Some(i) => SHIFTED[i] as char,
+        None => ch.to_ascii_uppercase(),
+    }
+}
+
+/// Turns scan code bytes into key events, and tracks the shift and caps lock state.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    /// The last byte was the `0xE0` prefix.
+    extended: bool,
+    /// Bytes left of a Pause sequence (`E1 1D 45 E1 9D C5`), which has no break code.
+    skip: u8,
+    left_shift: bool,
+    right_shift: bool,
+    caps_lock: bool,
+}
+
+impl Decoder {
+    pub const fn new() -> Self {
+        Self {
+            extended: false,
+            skip: 0,
+            left_shift: false,
+            right_shift: false,
+            caps_lock: false,
+        }
+    }
+
+    /// Feeds a byte from the keyboard, returning the event it completes.
+    pub fn feed(&mut self, byte: u8) -> Option<KeyEvent> {
+        if 0 < self.skip {
+            self.skip -= 1;
+            return None;
+        }
+        match byte {
+            0xE0 => {
+                self.extended = true;
+                return None;
+            }
+            0xE1 => {
+                self.skip = 5;
+                return None;
+            }
+            _ => {}
+        }
+        let extended = core::mem::take(&mut self.extended);
+        let code = byte & 0x7F;
+        let pressed = byte & 0x80 == 0;
+        // `E0 2A` and `E0 36` are fake shifts sent around Print Screen and the navigation keys.
+        if extended && matches!(code, 0x2A | 0x36) {
+            return None;
+        }
+        let key = match extended {
+            true => extended_key(code),
+            false => KEYS.get(code as usize).copied().flatten(),
+        }
+        .unwrap_or(Key::Unknown { code, extended });
+
+        match key {
+            Key::LeftShift => self.left_shift = pressed,
+            Key::RightShift => self.right_shift = pressed,
+            Key::CapsLock if pressed => self.caps_lock = !self.caps_lock,
+            _ => {}
+        }
+        Some(KeyEvent { key, pressed })
+    }
+
+    /// The character `event` types with the current shift and caps lock state, if any.
+    pub fn char_for(&self, event: KeyEvent) -> Option<char> {
+        if !event.pressed {
+            return None;
+        }
+        match event.key {
+            Key::Char(ch) if ch.is_ascii_alphabetic() => {
+                Some(match self.shift() != self.caps_lock {
+                    true => ch.to_ascii_uppercase(),
+                    false => ch,
+                })
+            }
+            Key::Char(ch) => Some(match self.shift() {
+                true => shifted(ch),
+                false => ch,
+            }),
+            Key::Enter => Some('\n'),
+            Key::Tab => Some('\t'),
+            Key::Backspace => Some('\x08'),
+            Key::Escape => Some('\x1B'),
+            _ => None,
+        }
+    }
+
+    fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+}
+
+static DECODER: spin::Mutex<Decoder> = spin::Mutex::new(Decoder::new());
+static CHARS: RingBuffer<64> = RingBuffer::new();
+
+/// Reads the byte waiting in the controller, decodes it, and queues the character it types.
+/// Returns the decoded event.
+fn handle_byte() -> Option<KeyEvent> {
+    let status = unsafe { Port::<u8>::new(STATUS_PORT).read() };
+    if status & STATUS_OUTPUT_FULL == 0 || status & STATUS_AUX_DATA != 0 {
+        return None;
+    }
+    // Reading the byte empties the output buffer, which lets the controller send the next one.
+    let byte = unsafe { Port::<u8>::new(DATA_PORT).read() };
+    let mut decoder = DECODER.lock();
+    let event = decoder.feed(byte)?;
+    if let Some(ch) = decoder.char_for(event) {
+        CHARS.push(ch as u32);
+    }
+    Some(event)
+}
+
+fn keyboard_handler() {
+    handle_byte();
+}
+
+/// Delivers the keyboard interrupt to the current CPU, and handles it.
+pub fn init() {
+    if !irq::register_handler(KEYBOARD_VECTOR, keyboard_handler) {
+        log::warn!("Keyboard vector {KEYBOARD_VECTOR} is already taken");
+        return;
+    }
+    unsafe {
+        crate::interrupts::ioapic::route_isa_irq(
+            KEYBOARD_IRQ,
+            KEYBOARD_VECTOR,
+            crate::interrupts::lapic_id(),
+        )
+    };
+}
+
+/// Processes the byte waiting in the controller, for when its interrupt isn't routed.
+pub fn poll() -> Option<KeyEvent> {
+    without_interrupts(handle_byte)
+}
+
+/// Reads a typed character, if there is one.
+pub fn read_char() -> Option<char> {
+    CHARS.pop().and_then(char::from_u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::String, vec::Vec};
+
+    use super::*;
+
+    /// The characters typed by `bytes`.
+    fn type_bytes(decoder: &mut Decoder, bytes: &[u8]) -> String {
+        let mut typed = String::new();
+        for &byte in bytes {
+            if let Some(ch) = decoder.feed(byte).and_then(|event| decoder.char_for(event)) {
+                typed.push(ch);
+            }
+        }
+        typed
+    }
+
+    #[test]
+    fn shift_and_caps_lock() {
+        let mut decoder = Decoder::new();
+        // a, 1, then with left shift held: a, 1, /
+        assert_eq!(type_bytes(&mut decoder, &[0x1E, 0x9E, 0x02, 0x82]), "a1");
+        assert_eq!(
+            type_bytes(&mut decoder, &[0x2A, 0x1E, 0x9E, 0x02, 0x82, 0x35, 0xAA]),
+            "A!?"
+        );
+        // Released shift types lowercase again, and the right shift works too.
+        assert_eq!(type_bytes(&mut decoder, &[0x1E, 0x36, 0x1E, 0xB6]), "aA");
+
+        // Caps lock toggles on press, only affects letters, and shift inverts it.
+        assert_eq!(
+            type_bytes(&mut decoder, &[0x3A, 0xBA, 0x1E, 0x02, 0x2A, 0x1E, 0xAA]),
+            "A1a"
+        );
+        assert_eq!(type_bytes(&mut decoder, &[0x3A, 0xBA, 0x1E]), "a");
+    }
+
+    #[test]
+    fn events_and_control_characters() {
+        let mut decoder = Decoder::new();
+        let event = |key, pressed| Some(KeyEvent { key, pressed });
+        assert_eq!(decoder.feed(0x1C), event(Key::Enter, true));
+        assert_eq!(decoder.feed(0x9C), event(Key::Enter, false));
+        assert_eq!(decoder.feed(0x3B), event(Key::F(1), true));
+        assert_eq!(decoder.feed(0x58), event(Key::F(12), true));
+        assert_eq!(
+            decoder.feed(0x7F),
+            event(
+                Key::Unknown {
+                    code: 0x7F,
+                    extended: false
+                },
+                true
+            )
+        );
+        assert_eq!(
+            type_bytes(&mut decoder, &[0x1C, 0x0F, 0x0E, 0x01, 0x39, 0x3B]),
+            "\n\t\x08\x1B "
+        );
+    }
+
+    #[test]
+    fn extended_codes() {
+        let mut decoder = Decoder::new();
+        let events: Vec<_> = [0xE0, 0x48, 0xE0, 0xC8, 0xE0, 0x1D, 0xE0, 0x35, 0x48]
+            .iter()
+            .filter_map(|&byte| decoder.feed(byte))
+            .collect();
+        assert_eq!(
+            events,
+            [
+                KeyEvent {
+                    key: Key::Up,
+                    pressed: true
+                },
+                KeyEvent {
+                    key: Key::Up,
+                    pressed: false
+                },
+                KeyEvent {
+                    key: Key::RightCtrl,
+                    pressed: true
+                },
+                KeyEvent {
+                    key: Key::Char('/'),
+                    pressed: true
+                },
+                // The prefix only applies to the next code: this is keypad 8.
+                KeyEvent {
+                    key: Key::Char('8'),
+                    pressed: true
+                },
+            ]
+        );
+        assert_eq!(decoder.feed(0xE0), None);
+        assert_eq!(
+            decoder.feed(0x10),
+            Some(KeyEvent {
+                key: Key::Unknown {
+                    code: 0x10,
+                    extended: true
+                },
+                pressed: true
+            })
+        );
+
+        // The fake shifts around Print Screen (`E0 2A E0 37`) don't shift.
+        assert_eq!(
+            type_bytes(
+                &mut decoder,
+                &[0xE0, 0x2A, 0xE0, 0x37, 0xE0, 0xB7, 0xE0, 0xAA, 0x1E]
+            ),
+            "a"
+        );
+    }
+
+    #[test]
+    fn pause_sequence_is_skipped() {
+        let mut decoder = Decoder::new();
+        let pause = [0xE1, 0x1D, 0x45, 0xE1, 0x9D, 0xC5];
+        assert!(pause.iter().all(|&byte| decoder.feed(byte).is_none()));
+        // Nothing is left over: not a ctrl, num lock, or extended key.
+        assert_eq!(type_bytes(&mut decoder, &[0x2A, 0x1E]), "A");
+        assert_eq!(
+            decoder.feed(0x48),
+            Some(KeyEvent {
+                key: Key::Char('8'),
+                pressed: true
+            })
+        );
+    }
+}
@@ -1,19 +1,34 @@
-#![no_std] // don't link the Rust standard library
-#![no_main] // disable all Rust-level entry points
+#![cfg_attr(not(test), no_std)] // don't link the Rust standard library
+#![cfg_attr(not(test), no_main)] // disable all Rust-level entry points
 #![feature(abi_x86_interrupt)]
 #![deny(unsafe_op_in_unsafe_fn)]
 
 extern crate alloc;
 
+pub mod acpi;
+pub mod array_vec;
 pub mod bitmap;
 pub mod gdt;
+pub mod hpet;
 pub mod interrupts;
+pub mod keyboard;
 pub mod memory;
 pub mod output;
+pub mod pci;
+pub mod percpu;
+pub mod pit;
+pub mod power;
 pub mod psf;
+pub mod ring_buffer;
+#[cfg(feature = "self_test")]
+pub mod self_test;
+pub mod shell;
+pub mod time;
 
-use bootloader_api::{entry_point, BootInfo, BootloaderConfig};
+use bootloader_api::{BootInfo, BootloaderConfig};
+use x86_64::PhysAddr;
 
+use output::console::FontSource;
 use psf::PsfFile;
 
 const KENREL_START: u64 = 0xFFFF_8000_0000_0000;
@@ -25,12 +40,17 @@ pub const BOOTLOADER_CONFIG: BootloaderConfig = {
     config
 };
 
-entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
+// The tests run on the host, with std's entry point.
+#[cfg(not(test))]
+bootloader_api::entry_point!(kernel_main, config = &BOOTLOADER_CONFIG);
 
-static PSF_FONT: spin::Lazy<PsfFile<'static>> =
-    spin::Lazy::new(|| PsfFile::parse(include_bytes!("../LatKaCyrHeb-14.psfu")).unwrap());
+#[cfg_attr(test, allow(dead_code))]
+static PSF_FONT: spin::Lazy<Result<PsfFile<'static>, psf::Error>> =
+    spin::Lazy::new(|| PsfFile::parse(include_bytes!("../LatKaCyrHeb-14.psfu")));
 
+#[cfg_attr(test, allow(dead_code))]
 fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
+    percpu::init_early();
     output::init_logger();
 
     gdt::init();
@@ -38,8 +58,27 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
 
     memory::init(boot_info);
 
+    if let Some(rsdp_addr) = boot_info.rsdp_addr.into_option() {
+        match unsafe { acpi::init(PhysAddr::new_truncate(rsdp_addr)) } {
+            Ok(rsdp) => log::info!("RSDP: {rsdp:?}"),
+            Err(err) => log::error!("Invalid RSDP, skipping ACPI init: {err}"),
+        }
+    }
+    time::init();
+
     if let Some(framebuffer) = boot_info.framebuffer.take() {
-        output::console::init(&PSF_FONT, framebuffer);
+        let font = match &*PSF_FONT {
+            Ok(font) => FontSource::Psf(font),
+            Err(err) => {
+                log::error!(
+                    "Failed to parse the PSF font, falling back to the builtin font: {err}"
+                );
+                FontSource::Builtin
+            }
+        };
+        output::console::init(font, framebuffer, true);
+    } else {
+        log::info!("No framebuffer; serial-only console");
     }
     log::info!("BOOT_INFO: {boot_info:#?}");
 
@@ -56,10 +95,19 @@ fn kernel_main(boot_info: &'static mut BootInfo) -> ! {
     x86_64::instructions::interrupts::int3(); // test interrupts
 
     unsafe { interrupts::init_apic() };
-
-    loop {
-        x86_64::instructions::hlt();
-    }
+    percpu::init_percpu(0);
+    keyboard::init();
+    output::serial::init_input();
+
+    let alloc_stats = x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        memory::malloc::ALLOC.stats()
+    });
+    log::info!("Allocator: {alloc_stats}");
+
+    #[cfg(feature = "self_test")]
+    self_test::run();
+    #[cfg(not(feature = "self_test"))]
+    shell::run();
 }
 
 #[cfg_attr(not(test), panic_handler)]
@@ -67,15 +115,15 @@ fn panic_handler(info: &core::panic::PanicInfo) -> ! {
     #[allow(dead_code)]
     const _: &dyn core::any::Any = &panic_handler;
 
-    unsafe {
-        output::force_unlock();
-    }
-
-    println!();
-    println!("{info}");
+    unsafe { output::panic_print(format_args!("\n{info}\n")) };
 
-    loop {
-        x86_64::instructions::interrupts::disable();
-        x86_64::instructions::hlt();
+    match power::panic_action() {
+        power::PanicAction::Halt => power::halt(),
+        power::PanicAction::Reboot => {
+            // The panic may have happened while printing, and rebooting logs.
+            unsafe { output::force_unlock() };
+            power::reboot()
+        }
+        power::PanicAction::QemuExit(code) => power::exit_qemu(code),
     }
 }
@@ -2,17 +2,27 @@ use core::{
     alloc::{GlobalAlloc, Layout},
     array,
     cell::UnsafeCell,
-    hint::unreachable_unchecked,
+    fmt,
+    hint::{self, unreachable_unchecked},
+    iter,
     mem::{self, MaybeUninit},
     ops,
     ptr::{self, NonNull},
     slice,
-    sync::atomic::{self, AtomicPtr, AtomicU32, AtomicUsize, Ordering::SeqCst},
+    sync::atomic::{
+        AtomicPtr, AtomicU32, AtomicUsize,
+        Ordering::{Acquire, Relaxed, Release},
+    },
 };
 
 use x86_64::VirtAddr;
 
-use super::vmm::VirtualMemoryManager;
+use super::vmm::{MappingFlags, VirtualMemoryManager};
+use crate::array_vec::ArrayVec;
+
+// Nothing uses the pairing heap yet, so it's only built for its tests.
+#[cfg(test)]
+mod pairing_heap;
 
 macro_rules! cfor {
     ($ident:ident in range($end:expr) $block:block) => {
@@ -55,15 +65,116 @@ const LARGE_SIZE_CLASS_PAGE_STARTS: [usize; LARGE_SIZE_CLASSES.len()] = {
     a
 };
 
+const fn class_size(class: usize) -> usize {
+    match class < SMALL_SIZE_CLASSES.len() {
+        true => SMALL_SIZE_CLASSES[class],
+        false => LARGE_SIZE_CLASSES[class - SMALL_SIZE_CLASSES.len()],
+    }
+}
+
+/// Number of blocks in a page of size class `class`.
+const fn class_page_blocks(class: usize) -> usize {
+    match class < SMALL_SIZE_CLASSES.len() {
+        true => SMALL_PAGE_SIZE / SMALL_SIZE_CLASSES[class],
+        false => {
+            let large_class = class - SMALL_SIZE_CLASSES.len();
+            (SEGMENT_SIZE - LARGE_SIZE_CLASS_PAGE_STARTS[large_class])
+                / LARGE_SIZE_CLASSES[large_class]
+        }
+    }
+}
+
+const MAX_SMALL_SIZE: usize = SMALL_SIZE_CLASSES[SMALL_SIZE_CLASSES.len() - 1];
+
+/// The small size class of every multiple of 8 up to `MAX_SMALL_SIZE`, indexed by `size + 7 >> 3`.
+const SMALL_SIZE_CLASS_TABLE: [u8; (MAX_SMALL_SIZE >> 3) + 1] = {
+    let mut table = [0; (MAX_SMALL_SIZE >> 3) + 1];
+    let mut class = 0;
+    cfor!(i in range(table.len()) {
+        if SMALL_SIZE_CLASSES[class] < 8 * i {
+            class += 1;
+        }
+        table[i] = class as u8;
+    });
+    table
+};
+
+/// The size class for large sizes, where there are 4 classes between consecutive powers of 2.
+const fn large_size_class(size: usize) -> usize {
+    let bits = usize::BITS - size.leading_zeros();
+    4 * bits as usize + (size - 1 >> bits - 3) - 27
+}
+
 const fn size_class(size: usize) -> usize {
-    if size <= 64 {
-        [0, 0, 1, 2, 2, 3, 3, 4, 4][size + 7 >> 3]
-    } else {
-        let bits = usize::BITS - size.leading_zeros();
-        4 * bits as usize + (size - 1 >> bits - 3) - 27
+    match size <= MAX_SMALL_SIZE {
+        true => SMALL_SIZE_CLASS_TABLE[size + 7 >> 3] as usize,
+        false => large_size_class(size),
+    }
+}
+
+/// Whether every block of `class` is aligned to `align`. Small pages are `SMALL_PAGE_SIZE` aligned,
+/// and large pages start `LARGE_SIZE_CLASS_PAGE_STARTS` into their segment.
+const fn class_is_aligned(class: usize, align: usize) -> bool {
+    match class < SMALL_SIZE_CLASSES.len() {
+        true => align <= SMALL_PAGE_SIZE && SMALL_SIZE_CLASSES[class] & (align - 1) == 0,
+        false => {
+            let large_class = class - SMALL_SIZE_CLASSES.len();
+            align <= SEGMENT_SIZE
+                && LARGE_SIZE_CLASSES[large_class] & (align - 1) == 0
+                && LARGE_SIZE_CLASS_PAGE_STARTS[large_class] & (align - 1) == 0
+        }
+    }
+}
+
+/// The size class to allocate `layout` from, or `None` if it has to go straight to the VMM. When
+/// the alignment is larger than 8, the class is bumped until its blocks are aligned.
+///
+/// Zero-sized layouts have no class, they're handled before getting here.
+fn layout_class(layout: Layout) -> Option<usize> {
+    debug_assert_ne!(
+        layout.size(),
+        0,
+        "zero-sized layouts don't have a size class"
+    );
+    let size = layout.align_to(8).unwrap().pad_to_align().size();
+    if *LARGE_SIZE_CLASSES.last().unwrap() < size {
+        return None;
+    }
+    let mut class = size_class(size);
+    while !class_is_aligned(class, layout.align()) {
+        class += 1;
+        if class == NUM_SIZE_CLASSES {
+            return None;
+        }
     }
+    Some(class)
 }
 
+// The table must agree with the arithmetic where both apply, and each class must be the smallest
+// that fits.
+const _: () = cfor!(i in range(9, SMALL_SIZE_CLASS_TABLE.len()) {
+    assert!(SMALL_SIZE_CLASS_TABLE[i] as usize == large_size_class(8 * i));
+    let class = SMALL_SIZE_CLASS_TABLE[i] as usize;
+    assert!(8 * i <= SMALL_SIZE_CLASSES[class]);
+    assert!(class == 0 || SMALL_SIZE_CLASSES[class - 1] < 8 * i);
+});
+
+/// The bytes at the end of a small page that are left over after carving it into blocks of each
+/// small size class. The sizes between powers of two trade this waste for less internal
+/// fragmentation per block.
+const SMALL_CLASS_PAGE_WASTE: [usize; SMALL_SIZE_CLASSES.len()] = {
+    let mut waste = [0; SMALL_SIZE_CLASSES.len()];
+    cfor!(i in range(SMALL_SIZE_CLASSES.len()) {
+        waste[i] = SMALL_PAGE_SIZE % SMALL_SIZE_CLASSES[i];
+    });
+    waste
+};
+
+// No small size class may waste more than 1/8 of a small page.
+const _: () = cfor!(i in range(0, SMALL_CLASS_PAGE_WASTE.len()) {
+    assert!(SMALL_CLASS_PAGE_WASTE[i] <= SMALL_PAGE_SIZE / 8);
+});
+
 #[repr(transparent)]
 struct ThreadOwned<T>(UnsafeCell<T>);
 
@@ -137,7 +248,8 @@ impl PageMeta {
     }
 
     fn thread_free(&self) -> (usize, ThreadFreeState, *mut FreeList) {
-        let thread_free = self.thread_free.load(SeqCst);
+        // Relaxed: only used as the expected value of a following CAS, the list isn't dereferenced.
+        let thread_free = self.thread_free.load(Relaxed);
         Self::split_thread_free(thread_free)
     }
     fn split_thread_free(thread_free: usize) -> (usize, ThreadFreeState, *mut FreeList) {
@@ -243,6 +355,17 @@ struct FreeList {
     next: *mut Self,
 }
 
+impl FreeList {
+    unsafe fn len(mut list: *const Self) -> usize {
+        let mut len = 0;
+        while !list.is_null() {
+            list = unsafe { (*list).next };
+            len += 1;
+        }
+        len
+    }
+}
+
 // #[repr(transparent)]
 // struct AtomicFreeList {
 //     next: AtomicPtr<Self>,
@@ -292,6 +415,39 @@ unsafe fn remove_page(page: &ThreadOwned<PageMeta>) {
     }
 }
 
+/// Iterates over the pages of a list, starting from `page`.
+///
+/// # Safety
+/// Must only be called by the thread owning the list, and the list must not be modified while
+/// iterating.
+unsafe fn iter_page_list<'a>(mut page: ThreadPagePtr) -> impl Iterator<Item = &'a PageMeta> {
+    iter::from_fn(move || {
+        let current = unsafe { page?.as_ref() };
+        page = unsafe { *current.next.get() };
+        Some(&**current)
+    })
+}
+
+/// Checks that every page's `prev_next` points back at the link pointing to it, panicking at the
+/// first mismatch. Only does anything in debug builds.
+unsafe fn validate_page_list(list: &ThreadPagePtr) {
+    if !cfg!(debug_assertions) {
+        return;
+    }
+    let mut link = NonNull::from(list);
+    let mut index = 0usize;
+    while let Some(page) = unsafe { *link.as_ref() } {
+        let page = unsafe { page.as_ref() };
+        assert_eq!(
+            unsafe { *page.prev_next.get() },
+            link,
+            "Page list corrupted: page {index} ({page:p}) has a bad `prev_next`",
+        );
+        link = unsafe { NonNull::new_unchecked(page.next.get()) };
+        index += 1;
+    }
+}
+
 // unsafe fn pop_page(list: &mut ThreadPagePtr) -> Option<&ThreadOwned<PageMeta>> {
 //     let page = unsafe { list.as_mut()?.as_ref() };
 //     *list = unsafe { *page.next.get() };
@@ -302,6 +458,15 @@ unsafe fn remove_page(page: &ThreadOwned<PageMeta>) {
 // }
 
 impl ThreadOwned<ThreadAllocator> {
+    /// Iterates over the pages in the list of size class `class`.
+    ///
+    /// # Safety
+    /// Must only be called by the owning thread, and the list must not be modified while
+    /// iterating.
+    unsafe fn pages_in_class(&self, class: usize) -> impl Iterator<Item = &PageMeta> {
+        unsafe { iter_page_list(*self.pages[class].get()) }
+    }
+
     unsafe fn free_small_page(&self, free_segments: &FreeSegments, page: &mut PageMeta) {
         let seg = unsafe { ThreadOwned::from_ref(&*Segment::from_ptr(page)) };
         let seg_used = unsafe { &mut *seg.used.get() };
@@ -322,10 +487,10 @@ impl ThreadOwned<ThreadAllocator> {
         while let Some(page) = unsafe { *self.pages[class].get() } {
             let page = unsafe { page.as_ref() };
             let next_page = unsafe { *page.next.get() };
-            if unsafe { *page.used.get() } == page.thread_freed.load(atomic::Ordering::Relaxed)
-                && next_page.is_some()
+            if unsafe { *page.used.get() } == page.thread_freed.load(Relaxed) && next_page.is_some()
             {
                 unsafe { remove_page(page) };
+                unsafe { validate_page_list(&*self.pages[class].get()) };
                 if SMALL_SIZE_CLASSES.len() <= class {
                     unsafe { free_segments.push(Segment::from_ptr(page) as _) };
                 } else {
@@ -387,6 +552,8 @@ impl ThreadOwned<ThreadAllocator> {
 
         unsafe { remove_page(page.into()) };
         unsafe { push_page(&mut *self.pages[class].get(), page.into()) };
+        unsafe { validate_page_list(free_small_pages) };
+        unsafe { validate_page_list(&*self.pages[class].get()) };
 
         let page_start: *mut u8 = Segment::small_page_start(page as _);
 
@@ -471,7 +638,8 @@ impl ThreadOwned<ThreadAllocator> {
     }
 
     pub unsafe fn alloc(&self, free_segments: &FreeSegments, class: usize) -> *mut u8 {
-        let mut delayed_free = self.delayed_free.swap(ptr::null_mut(), SeqCst);
+        // Acquire: pairs with the Release CAS in `dealloc`, so the blocks' `next` links are visible.
+        let mut delayed_free = self.delayed_free.swap(ptr::null_mut(), Acquire);
         while let Some(free) = NonNull::new(delayed_free) {
             delayed_free = unsafe { free.as_ref().next };
 
@@ -501,18 +669,23 @@ impl ThreadOwned<ThreadAllocator> {
             match NonNull::new(unsafe { *page.free.get() })
                 .or_else(|| NonNull::new(unsafe { page.local_free.get().replace(ptr::null_mut()) }))
                 .or_else(|| {
+                    // Acquire on success: pairs with the Release store of `Normal` in `dealloc`, which
+                    // ends a remote thread's push to `delayed_free`.
+                    // Relaxed on failure: the list is (re)read by the following swap.
                     page.thread_free
                         .compare_exchange(
                             ThreadFreeState::Normal as _,
                             ThreadFreeState::Delayed as _,
-                            SeqCst,
-                            SeqCst,
+                            Acquire,
+                            Relaxed,
                         )
                         .err()
                         .map(|_| unsafe {
+                            // Acquire: pairs with the Release CAS in `dealloc` that pushed the blocks,
+                            // so their `next` links are visible.
                             // SAFETY: We checked that it isn't zero and other threads won't zero it
                             NonNull::new_unchecked(
-                                (page.thread_free.swap(ThreadFreeState::Normal as _, SeqCst) & !7)
+                                (page.thread_free.swap(ThreadFreeState::Normal as _, Acquire) & !7)
                                     as _,
                             )
                         })
@@ -532,6 +705,27 @@ impl ThreadOwned<ThreadAllocator> {
     }
 }
 
+/// Exponential backoff for contended CAS loops.
+struct Backoff {
+    step: u32,
+}
+
+impl Backoff {
+    const MAX_STEP: u32 = 6;
+
+    const fn new() -> Self {
+        Self { step: 0 }
+    }
+
+    /// Spins for `2**step` iterations, and doubles the spin count for the next call.
+    fn spin(&mut self) {
+        for _ in 0..1 << self.step {
+            hint::spin_loop();
+        }
+        self.step = Self::MAX_STEP.min(self.step + 1);
+    }
+}
+
 #[derive(Debug)]
 pub struct FreeSegments {
     ptr: AtomicPtr<FreeList>,
@@ -544,40 +738,75 @@ impl FreeSegments {
         unsafe { self.push(ptr as _) };
     }
 
+    /// Approximate number of free segments, it may lag behind concurrent pushes and pops.
     #[inline]
     pub fn len(&self) -> usize {
-        self.len.load(SeqCst)
+        // Relaxed: `len` is only a heuristic counter, it doesn't guard any memory.
+        self.len.load(Relaxed)
     }
 
     unsafe fn push(&self, list: *mut Segment) {
         let list = list as *mut FreeList;
-        unsafe { (*list).next = self.ptr.load(SeqCst) };
+        // Relaxed: the head is only stored into `list.next`, and not dereferenced.
+        unsafe { (*list).next = self.ptr.load(Relaxed) };
+        let mut backoff = Backoff::new();
+        // Release on success: publishes `list.next` (and the segment's previous contents) to the
+        // thread that pops it.
         while let Err(next) =
-            (self.ptr).compare_exchange(unsafe { (*list).next }, list, SeqCst, SeqCst)
+            (self.ptr).compare_exchange(unsafe { (*list).next }, list, Release, Relaxed)
         {
             unsafe { (*list).next = next };
+            backoff.spin();
         }
-        self.len.fetch_add(1, SeqCst);
+        self.len.fetch_add(1, Relaxed);
     }
 
     unsafe fn pop(&self) -> Option<NonNull<MaybeUninit<Segment>>> {
-        let mut ptr = NonNull::new(self.ptr.load(SeqCst))?;
+        // Acquire everywhere: pairs with the Release CAS in `push`, since the head is dereferenced to
+        // read its `next` link, and the segment is then handed out.
+        let mut ptr = NonNull::new(self.ptr.load(Acquire))?;
+        let mut backoff = Backoff::new();
         while let Some(curr) = (self.ptr)
-            .compare_exchange(ptr.as_ptr(), unsafe { ptr.as_ref().next }, SeqCst, SeqCst)
+            .compare_exchange(ptr.as_ptr(), unsafe { ptr.as_ref().next }, Acquire, Acquire)
             .err()
         {
             ptr = NonNull::new(curr)?;
+            backoff.spin();
         }
-        self.len.fetch_sub(1, SeqCst);
+        self.len.fetch_sub(1, Relaxed);
 
         Some(ptr.cast())
     }
 }
 
+/// Maximum number of CPUs, each gets its own [`ThreadAllocator`], indexed by its
+/// [`PerCpu::alloc_index`](crate::percpu::PerCpu::alloc_index).
+pub const MAX_CPUS: usize = 16;
+
+/// The index of the [`ThreadAllocator`] of the CPU we're running on. Allocations made before the
+/// per-CPU data is set up use the first one.
+#[cfg(not(test))]
+fn current_thread_id() -> usize {
+    crate::percpu::this_cpu().map_or(0, |cpu| cpu.alloc_index)
+}
+
+#[cfg(test)]
+fn current_thread_id() -> usize {
+    tests::THREAD_ID.get()
+}
+
+/// The VMM maps whole pages, so huge allocations are rounded up to them.
+const HUGE_ALLOC_GRANULE: usize = 4 << 10;
+/// How many freed huge allocations are kept mapped for reuse.
+const HUGE_CACHE_LEN: usize = 4;
+
 #[derive(Debug)]
 pub struct Allocator {
     pub free_segments: FreeSegments,
-    thread_allocs: [ThreadAllocator; 1],
+    thread_allocs: [ThreadAllocator; MAX_CPUS],
+    /// Recently freed huge allocations and their rounded sizes, oldest first. Allocating the same
+    /// size again reuses them, instead of unmapping and mapping them again.
+    huge_cache: spin::Mutex<ArrayVec<(VirtAddr, usize), HUGE_CACHE_LEN>>,
     pub vmm: spin::Once<&'static spin::Mutex<VirtualMemoryManager<'static>>>,
 }
 
@@ -592,36 +821,198 @@ impl Allocator {
                 len: AtomicUsize::new(0),
             },
             thread_allocs: array::from_fn(|thread_id| ThreadAllocator::new(thread_id as _)),
+            huge_cache: spin::Mutex::new(ArrayVec::new()),
             vmm: spin::Once::new(),
         }
     }
 }
 
+impl Allocator {
+    /// Takes a cached huge allocation of `size` bytes aligned to `align`.
+    fn take_cached_huge(&self, size: usize, align: usize) -> Option<*mut u8> {
+        let mut cache = self.huge_cache.try_lock()?;
+        let index = (cache.iter()).position(|&(addr, cached_size)| {
+            cached_size == size && addr.is_aligned(align as u64)
+        })?;
+        Some(cache.remove(index)?.0.as_mut_ptr())
+    }
+
+    /// Caches a freed huge allocation, returning the one that has to be freed to the VMM instead:
+    /// the oldest one if the cache is full, or this one if the cache is busy.
+    fn cache_huge(&self, addr: VirtAddr, size: usize) -> Option<(VirtAddr, usize)> {
+        let Some(mut cache) = self.huge_cache.try_lock() else {
+            return Some((addr, size));
+        };
+        let evicted = match cache.is_full() {
+            true => cache.remove(0),
+            false => None,
+        };
+        let _ = cache.push((addr, size));
+        evicted
+    }
+
+    /// Allocates `layout` straight from the VMM, reserving its pages instead of mapping them: the
+    /// page fault handler maps a zeroed frame at each page as it's first touched. The handler can't
+    /// map pages while the VMM is locked, so this is only for memory that isn't touched then, unlike
+    /// the console's buffers, which are written by logging. Free it with [`Self::dealloc_reserved`].
+    pub fn alloc_reserved(&self, layout: Layout) -> *mut u8 {
+        let Some(mut vmm) = self.vmm.get().and_then(|vmm| vmm.try_lock()) else {
+            log::info!("ALLOC_RESERVED: Failed to acquire vmm lock");
+            return ptr::null_mut();
+        };
+        let size = layout.size().max(1).next_multiple_of(HUGE_ALLOC_GRANULE);
+        vmm.reserve(true, size, layout.align().trailing_zeros() as _)
+            .map_or(ptr::null_mut(), |addr| addr.as_mut_ptr())
+    }
+
+    /// Frees an allocation made by [`Self::alloc_reserved`], along with the pages that were touched.
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by [`Self::alloc_reserved`], and nothing may reference it.
+    pub unsafe fn dealloc_reserved(&self, ptr: *mut u8) {
+        let Some(mut vmm) = self.vmm.get().and_then(|vmm| vmm.try_lock()) else {
+            log::info!("DEALLOC_RESERVED: Failed to acquire vmm lock");
+            return;
+        };
+        unsafe { vmm.unreserve(VirtAddr::from_ptr(ptr)) };
+    }
+
+    /// Approximate number of bytes that can be allocated without mapping more memory: the free
+    /// segments, the free small pages, and the free blocks of this thread's pages. Blocks freed by
+    /// other threads aren't counted until they're collected.
+    ///
+    /// # Safety
+    /// Must be called by the thread owning the allocator, and not concurrently with `alloc` or
+    /// `dealloc` on it (e.g. with interrupts disabled).
+    pub unsafe fn free_bytes(&self) -> usize {
+        let thread_id = current_thread_id();
+        let thread_alloc = unsafe { ThreadOwned::from_ref(&self.thread_allocs[thread_id]) };
+
+        let mut bytes = self.free_segments.len() * SEGMENT_SIZE;
+        for class in 0..NUM_SIZE_CLASSES {
+            for page in unsafe { thread_alloc.pages_in_class(class) } {
+                let free_blocks = unsafe {
+                    FreeList::len(*page.free.get()) + FreeList::len(*page.local_free.get())
+                };
+                bytes += class_size(class) * free_blocks;
+            }
+        }
+        let free_small_pages = unsafe { iter_page_list(*thread_alloc.free_small_pages.get()) };
+        bytes += SMALL_PAGE_SIZE * free_small_pages.count();
+        bytes
+    }
+}
+
+/// A snapshot of a thread's allocator, see [`Allocator::stats`].
+#[derive(Debug, Clone)]
+pub struct AllocatorStats {
+    /// Bytes in blocks that are handed out, per size class. Blocks freed by other threads count
+    /// until they're collected.
+    pub used_bytes: [usize; NUM_SIZE_CLASSES],
+    /// Number of pages without free blocks.
+    pub full_pages: usize,
+    /// Number of small pages that aren't assigned a size class.
+    pub free_small_pages: usize,
+    /// Number of unused segments, shared by all threads.
+    pub free_segments: usize,
+}
+
+impl AllocatorStats {
+    pub fn total_used_bytes(&self) -> usize {
+        self.used_bytes.iter().sum()
+    }
+}
+
+impl fmt::Display for AllocatorStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} bytes used [", self.total_used_bytes())?;
+        let used_classes = (0..NUM_SIZE_CLASSES).filter(|&class| self.used_bytes[class] != 0);
+        for (i, class) in used_classes.enumerate() {
+            if i != 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "0x{:x}: {}", class_size(class), self.used_bytes[class])?;
+        }
+        write!(
+            f,
+            "], full_pages={} free_small_pages={} free_segments={}",
+            self.full_pages, self.free_small_pages, self.free_segments,
+        )
+    }
+}
+
+impl Allocator {
+    /// Collects the statistics of this thread's allocator.
+    ///
+    /// # Safety
+    /// Same as [`Self::free_bytes`].
+    pub unsafe fn stats(&self) -> AllocatorStats {
+        let thread_id = current_thread_id();
+        let thread_alloc = unsafe { ThreadOwned::from_ref(&self.thread_allocs[thread_id]) };
+
+        let mut stats = AllocatorStats {
+            used_bytes: [0; NUM_SIZE_CLASSES],
+            full_pages: 0,
+            free_small_pages: 0,
+            free_segments: self.free_segments.len(),
+        };
+        for (class, used_bytes) in stats.used_bytes.iter_mut().enumerate() {
+            for page in unsafe { thread_alloc.pages_in_class(class) } {
+                let free_blocks = unsafe {
+                    FreeList::len(*page.free.get()) + FreeList::len(*page.local_free.get())
+                };
+                *used_bytes += class_size(class) * (class_page_blocks(class) - free_blocks);
+            }
+        }
+        for page in unsafe { iter_page_list(*thread_alloc.full_pages.get()) } {
+            let class = page.class as usize;
+            stats.used_bytes[class] += class_size(class) * class_page_blocks(class);
+            stats.full_pages += 1;
+        }
+        stats.free_small_pages =
+            unsafe { iter_page_list(*thread_alloc.free_small_pages.get()) }.count();
+        stats
+    }
+}
+
 unsafe impl GlobalAlloc for Allocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
         let vmm = || self.vmm.get().and_then(|vmm| vmm.try_lock());
 
-        let size = layout.align_to(8).unwrap().pad_to_align().size();
-        if *LARGE_SIZE_CLASSES.last().unwrap() < size {
+        // Zero-sized allocations don't need memory, hand out a dangling but aligned pointer.
+        if layout.size() == 0 {
+            return ptr::without_provenance_mut(layout.align());
+        }
+
+        let Some(class) = layout_class(layout) else {
+            let size = layout.size().next_multiple_of(HUGE_ALLOC_GRANULE);
+            if let Some(ptr) = self.take_cached_huge(size, layout.align()) {
+                return ptr;
+            }
             let Some(mut vmm) = vmm() else {
                 log::info!("ALLOC_HUGE: Failed to acquire vmm lock");
                 return ptr::null_mut();
             };
-            log::info!("ALLOC_HUGE: layout={layout:?} size=0x{size:x}");
+            log::info!("ALLOC_HUGE: layout={layout:?}");
             return vmm
-                .alloc(true, layout.size(), layout.align().trailing_zeros() as _)
+                .alloc(
+                    true,
+                    size,
+                    layout.align().trailing_zeros() as _,
+                    MappingFlags::DATA,
+                )
                 .map_or(ptr::null_mut(), |addr| addr.as_mut_ptr());
-        }
+        };
 
-        // Get this thread's id
-        let thread_id = 0;
+        let thread_id = current_thread_id();
         let thread_alloc = unsafe { ThreadOwned::from_ref(&self.thread_allocs[thread_id]) };
-        let class = size_class(size);
 
         if let Some(ptr) = unsafe { thread_alloc.fast_alloc(class) } {
             return ptr.as_ptr();
         }
 
+        // Segments are mapped up front rather than reserved: the VMM's own trees allocate from them
+        // while it's locked, when a page fault couldn't be served.
         'alloc_segments: {
             if 3 < self.free_segments.len() {
                 break 'alloc_segments;
@@ -636,7 +1027,7 @@ unsafe impl GlobalAlloc for Allocator {
                 break 'alloc_segments;
             };
             while let Some(list) = vmm
-                .alloc(true, SEGMENT_SIZE, SEGMENT_SIZE.trailing_zeros() as _)
+                .alloc_huge(true, SEGMENT_SIZE, SEGMENT_SIZE.trailing_zeros() as _)
                 .map(|addr| addr.as_mut_ptr::<Segment>())
             {
                 unsafe { self.free_segments.push(list) };
@@ -659,17 +1050,23 @@ unsafe impl GlobalAlloc for Allocator {
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         // log::info!("DEALLOC: ptr={ptr:p} layout={layout:?}");
-        let size = layout.align_to(8).unwrap().pad_to_align().size();
-        if *LARGE_SIZE_CLASSES.last().unwrap() < size {
+        // Zero-sized allocations are dangling pointers from `alloc`, there's nothing to free.
+        if layout.size() == 0 {
+            return;
+        }
+        let Some(class) = layout_class(layout) else {
+            let size = layout.size().next_multiple_of(HUGE_ALLOC_GRANULE);
+            let Some((addr, size)) = self.cache_huge(VirtAddr::from_ptr(ptr), size) else {
+                return;
+            };
             let Some(mut vmm) = self.vmm.get().and_then(|vmm| vmm.try_lock()) else {
                 log::info!("DEALLOC_HUGE: Failed to acquire vmm lock");
                 return;
             };
-            return unsafe { vmm.free(VirtAddr::from_ptr(ptr), layout.size()) };
-        }
+            return unsafe { vmm.free(addr, size) };
+        };
 
-        // Get this thread's id
-        let thread_id = 0;
+        let thread_id = current_thread_id() as u32;
 
         let ptr = unsafe { &mut *ptr.cast() };
 
@@ -684,7 +1081,7 @@ unsafe impl GlobalAlloc for Allocator {
             let page = unsafe { ThreadOwned::from_ref(page) };
             let thread_alloc =
                 unsafe { ThreadOwned::from_ref(&self.thread_allocs[thread_id as usize]) };
-            unsafe { thread_alloc.local_free(size_class(size), page, ptr.into()) };
+            unsafe { thread_alloc.local_free(class, page, ptr.into()) };
         } else {
             let (mut cur, mut state, mut thread_free) = page.thread_free();
             let mut delaying_counter = 0;
@@ -693,11 +1090,13 @@ unsafe impl GlobalAlloc for Allocator {
                 match state {
                     ThreadFreeState::Normal => {
                         ptr.next = thread_free;
+                        // Release on success: publishes `ptr.next` to the owner's Acquire swap.
+                        // Relaxed on failure: the new list head is only stored, not dereferenced.
                         match page.thread_free.compare_exchange(
                             cur,
                             ptr as *mut _ as _,
-                            SeqCst,
-                            SeqCst,
+                            Release,
+                            Relaxed,
                         ) {
                             Ok(_) => break,
                             Err(new) => thread_free_raw = new,
@@ -705,24 +1104,31 @@ unsafe impl GlobalAlloc for Allocator {
                     }
                     ThreadFreeState::Delaying if delaying_counter < 4 => {
                         delaying_counter += 1;
-                        thread_free_raw = page.thread_free.load(SeqCst);
+                        thread_free_raw = page.thread_free.load(Relaxed);
                     }
                     ThreadFreeState::Delayed | ThreadFreeState::Delaying => {
+                        // Relaxed: the `Delaying` state only claims the right to push to
+                        // `delayed_free`, the block itself is published by that push.
                         match page.thread_free.compare_exchange(
                             cur,
                             ThreadFreeState::Delaying as _,
-                            SeqCst,
-                            SeqCst,
+                            Relaxed,
+                            Relaxed,
                         ) {
                             Ok(_) => {
                                 let alloc = &self.thread_allocs[seg.thread_id as usize];
-                                ptr.next = alloc.delayed_free.load(SeqCst);
+                                // Release on success: publishes `ptr.next` to the owner's Acquire
+                                // swap in `ThreadAllocator::alloc`. The heads are never dereferenced
+                                // here, so the rest is Relaxed.
+                                ptr.next = alloc.delayed_free.load(Relaxed);
                                 while let Err(new_next) = (alloc.delayed_free)
-                                    .compare_exchange(ptr.next, ptr, SeqCst, SeqCst)
+                                    .compare_exchange(ptr.next, ptr, Release, Relaxed)
                                 {
                                     ptr.next = new_next;
                                 }
-                                page.thread_free.store(ThreadFreeState::Normal as _, SeqCst);
+                                // Release: orders the push above before the page leaves `Delaying`.
+                                page.thread_free
+                                    .store(ThreadFreeState::Normal as _, Release);
                                 break;
                             }
                             Err(new) => thread_free_raw = new,
@@ -733,6 +1139,39 @@ unsafe impl GlobalAlloc for Allocator {
             }
         }
     }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        // Carving a page writes a free list link into each of its blocks, and the frames of segments
+        // and huge allocations are whatever the buddy allocator had, so fresh memory is as dirty as
+        // reused memory. `Allocator::alloc_reserved` is zeroed for free.
+        let ptr = unsafe { self.alloc(layout) };
+        if !ptr.is_null() {
+            unsafe { ptr.write_bytes(0, layout.size()) };
+        }
+        ptr
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_layout = unsafe { Layout::from_size_align_unchecked(new_size, layout.align()) };
+
+        // A block of the same size class fits the new size as well. Zero-sized and huge allocations
+        // have no class, and move.
+        if layout.size() != 0 && new_size != 0 {
+            if let (Some(class), Some(new_class)) = (layout_class(layout), layout_class(new_layout))
+            {
+                if class == new_class {
+                    return ptr;
+                }
+            }
+        }
+
+        let new_ptr = unsafe { self.alloc(new_layout) };
+        if !new_ptr.is_null() {
+            unsafe {
+                ptr::copy_nonoverlapping(ptr, new_ptr, layout.size().min(new_size));
+                self.dealloc(ptr, layout);
+            }
+        }
+        new_ptr
+    }
 }
 
 pub struct LazyAllocator(pub spin::Lazy<Allocator>);
@@ -743,13 +1182,61 @@ impl LazyAllocator {
     }
 }
 
+/// Checks that `ptr` is aligned to `layout` and lies on the block grid of its size class, and halts
+/// if it doesn't.
+#[cfg(feature = "alloc_debug")]
+fn check_block(ptr: *mut u8, layout: Layout) {
+    if ptr.is_null() || layout.size() == 0 {
+        return;
+    }
+    let class = layout_class(layout);
+    let page_start = match class {
+        // Huge allocations come straight from the VMM and have no block grid.
+        None => ptr as usize & !(layout.align() - 1),
+        Some(class) if class < SMALL_SIZE_CLASSES.len() => {
+            Segment::from_ptr(ptr) as usize
+                + SMALL_PAGE_SIZE * (1 + Segment::block_small_page_id(ptr.cast()))
+        }
+        Some(class) => {
+            Segment::from_ptr(ptr) as usize
+                + LARGE_SIZE_CLASS_PAGE_STARTS[class - SMALL_SIZE_CLASSES.len()]
+        }
+    };
+    let on_grid =
+        class.is_none_or(|class| (ptr as usize).wrapping_sub(page_start) % class_size(class) == 0);
+    if ptr as usize & (layout.align() - 1) != 0 || !on_grid {
+        log::error!(
+            "ALLOC_DEBUG: misplaced block: layout={layout:?} ptr={ptr:p} class={class:?} \
+             page_start={page_start:#x}"
+        );
+        crate::power::halt();
+    }
+}
+
 unsafe impl GlobalAlloc for LazyAllocator {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        unsafe { self.0.alloc(layout) }
+        let ptr = unsafe { self.0.alloc(layout) };
+        #[cfg(feature = "alloc_debug")]
+        check_block(ptr, layout);
+        ptr
     }
     unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
         unsafe { self.0.dealloc(ptr, layout) }
     }
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = unsafe { self.0.alloc_zeroed(layout) };
+        #[cfg(feature = "alloc_debug")]
+        check_block(ptr, layout);
+        ptr
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = unsafe { self.0.realloc(ptr, layout, new_size) };
+        #[cfg(feature = "alloc_debug")]
+        check_block(new_ptr, unsafe {
+            Layout::from_size_align_unchecked(new_size, layout.align())
+        });
+        new_ptr
+    }
 }
 
 impl ops::Deref for LazyAllocator {
@@ -759,5 +1246,340 @@ impl ops::Deref for LazyAllocator {
     }
 }
 
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 pub static ALLOC: LazyAllocator = LazyAllocator::new();
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{alloc, cell::Cell, vec::Vec};
+
+    std::thread_local! {
+        pub static THREAD_ID: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// An allocator without a VMM, with `segments` free segments from the host's heap.
+    fn allocator(segments: usize) -> (Allocator, Vec<*mut u8>) {
+        let layout = Layout::from_size_align(SEGMENT_SIZE, SEGMENT_SIZE).unwrap();
+        let allocator = Allocator::new();
+        let segments: Vec<_> = (0..segments)
+            .map(|_| unsafe { alloc::alloc(layout) })
+            .collect();
+        for &segment in &segments {
+            assert!(!segment.is_null());
+            unsafe { allocator.free_segments.push_bytes(segment) };
+        }
+        (allocator, segments)
+    }
+
+    fn free_segments(segments: Vec<*mut u8>) {
+        let layout = Layout::from_size_align(SEGMENT_SIZE, SEGMENT_SIZE).unwrap();
+        for segment in segments {
+            unsafe { alloc::dealloc(segment, layout) };
+        }
+    }
+
+    /// Bytes of a huge allocation the tests put in the huge cache, since there's no VMM to map them.
+    const HUGE_SIZE: usize = 1 << 20;
+
+    /// Caches a huge allocation from the host's heap, as if it was freed.
+    fn cache_huge_region(allocator: &Allocator) -> *mut u8 {
+        let layout = Layout::from_size_align(HUGE_SIZE, HUGE_ALLOC_GRANULE).unwrap();
+        let region = unsafe { alloc::alloc(layout) };
+        assert!(!region.is_null());
+        assert!(allocator
+            .cache_huge(VirtAddr::from_ptr(region), HUGE_SIZE)
+            .is_none());
+        region
+    }
+
+    fn free_huge_region(region: *mut u8) {
+        let layout = Layout::from_size_align(HUGE_SIZE, HUGE_ALLOC_GRANULE).unwrap();
+        unsafe { alloc::dealloc(region, layout) };
+    }
+
+    /// Fills `len` bytes at `ptr` with a pattern that `has_pattern` checks for.
+    fn fill_pattern(ptr: *mut u8, len: usize) {
+        for i in 0..len {
+            unsafe { ptr.add(i).write(i as u8 ^ 0x5A) };
+        }
+    }
+
+    fn has_pattern(ptr: *mut u8, len: usize) -> bool {
+        (0..len).all(|i| unsafe { ptr.add(i).read() } == i as u8 ^ 0x5A)
+    }
+
+    #[test]
+    fn cross_thread_free_of_full_page_is_delayed() {
+        let (allocator, segments) = allocator(2);
+        let layout = Layout::from_size_align(MAX_SMALL_SIZE, 8).unwrap();
+        let class = layout_class(layout).unwrap();
+
+        THREAD_ID.set(0);
+        let blocks: Vec<_> = (0..class_page_blocks(class))
+            .map(|_| unsafe { allocator.alloc(layout) })
+            .collect();
+        assert!(blocks.iter().all(|block| !block.is_null()));
+        // The first page is out of blocks, so this marks it delayed and takes a new page.
+        let other = unsafe { allocator.alloc(layout) };
+        assert!(!other.is_null());
+        assert_ne!(Segment::block_small_page_id(other.cast()), {
+            Segment::block_small_page_id(blocks[0].cast())
+        });
+
+        THREAD_ID.set(1);
+        unsafe { allocator.dealloc(blocks[0], layout) };
+        let delayed_free = allocator.thread_allocs[0].delayed_free.load(Relaxed);
+        assert_eq!(delayed_free.cast(), blocks[0]);
+        assert!(allocator.thread_allocs[1]
+            .delayed_free
+            .load(Relaxed)
+            .is_null());
+
+        // Once the new page runs out, the owner collects the block, and hands it out again.
+        THREAD_ID.set(0);
+        for _ in 1..class_page_blocks(class) {
+            assert!(!unsafe { allocator.alloc(layout) }.is_null());
+        }
+        assert_eq!(unsafe { allocator.alloc(layout) }, blocks[0]);
+        assert!(allocator.thread_allocs[0]
+            .delayed_free
+            .load(Relaxed)
+            .is_null());
+
+        free_segments(segments);
+    }
+
+    #[test]
+    fn alloc_zeroed_zeroes_fresh_and_reused_blocks() {
+        let (allocator, segments) = allocator(2);
+        for size in [0x18, 0x3000] {
+            let layout = Layout::from_size_align(size, 8).unwrap();
+            let blocks = class_page_blocks(layout_class(layout).unwrap());
+            let alloc_page = || {
+                let mut ptrs: Vec<_> = (0..blocks)
+                    .map(|_| unsafe { allocator.alloc_zeroed(layout) })
+                    .collect();
+                for &ptr in &ptrs {
+                    assert!(!ptr.is_null());
+                    let bytes = unsafe { slice::from_raw_parts_mut(ptr, size) };
+                    assert!(bytes.iter().all(|&byte| byte == 0));
+                    bytes.fill(0xA5);
+                }
+                ptrs.sort();
+                ptrs
+            };
+
+            let fresh = alloc_page();
+            for &ptr in &fresh {
+                unsafe { allocator.dealloc(ptr, layout) };
+            }
+            let reused = alloc_page();
+            assert_eq!(fresh, reused);
+        }
+        free_segments(segments);
+    }
+
+    #[test]
+    fn small_sizes_with_large_alignments() {
+        let (allocator, segments) = allocator(2);
+        for align in [16, 32, 64, 128] {
+            for size in [8, align / 2 + 8, align] {
+                let layout = Layout::from_size_align(size, align).unwrap();
+                let class = layout_class(layout).unwrap();
+                assert!(class_is_aligned(class, align));
+                // A whole page of blocks, and the first of the next page.
+                for _ in 0..=class_page_blocks(class) {
+                    let ptr = unsafe { allocator.alloc(layout) };
+                    assert!(!ptr.is_null());
+                    assert_eq!(ptr as usize % align, 0, "{layout:?} in class {class}");
+                }
+            }
+        }
+        free_segments(segments);
+    }
+
+    #[test]
+    fn realloc_within_and_across_classes() {
+        let (allocator, segments) = allocator(3);
+        let layout = Layout::from_size_align(20, 8).unwrap();
+        let ptr = unsafe { allocator.alloc(layout) };
+        fill_pattern(ptr, 20);
+
+        // 20 and 30 bytes are both in the 0x20 class.
+        let grown = unsafe { allocator.realloc(ptr, layout, 30) };
+        assert_eq!(grown, ptr);
+        let layout = Layout::from_size_align(30, 8).unwrap();
+        fill_pattern(grown, 30);
+
+        let moved = unsafe { allocator.realloc(grown, layout, 0x100) };
+        assert_ne!(moved, grown);
+        assert!(has_pattern(moved, 30));
+        let layout = Layout::from_size_align(0x100, 8).unwrap();
+        fill_pattern(moved, 0x100);
+
+        // Into a large class, and back to a smaller small one.
+        let large = unsafe { allocator.realloc(moved, layout, 0x3000) };
+        assert!(has_pattern(large, 0x100));
+        let layout = Layout::from_size_align(0x3000, 8).unwrap();
+        fill_pattern(large, 0x3000);
+        let shrunk = unsafe { allocator.realloc(large, layout, 0x10) };
+        assert_ne!(shrunk, large);
+        assert!(has_pattern(shrunk, 0x10));
+        let layout = Layout::from_size_align(0x10, 8).unwrap();
+
+        // Without a VMM or a cached huge allocation, growing to a huge size fails and keeps the
+        // block.
+        assert!(unsafe { allocator.realloc(shrunk, layout, HUGE_SIZE) }.is_null());
+        assert!(has_pattern(shrunk, 0x10));
+
+        // Small to huge and back, through the huge cache.
+        let region = cache_huge_region(&allocator);
+        let huge = unsafe { allocator.realloc(shrunk, layout, HUGE_SIZE - 5) };
+        assert_eq!(huge, region);
+        assert!(has_pattern(huge, 0x10));
+        let layout = Layout::from_size_align(HUGE_SIZE - 5, 8).unwrap();
+        fill_pattern(huge, 0x100);
+        let small = unsafe { allocator.realloc(huge, layout, 0x100) };
+        assert_ne!(small, huge);
+        assert!(has_pattern(small, 0x100));
+        assert_eq!(allocator.take_cached_huge(HUGE_SIZE, 8), Some(region));
+
+        free_huge_region(region);
+        free_segments(segments);
+    }
+
+    #[test]
+    fn stats_count_used_blocks_and_pages() {
+        let (allocator, segments) = allocator(2);
+        THREAD_ID.set(0);
+        let stats = unsafe { allocator.stats() };
+        assert_eq!(stats.total_used_bytes(), 0);
+        assert_eq!(
+            (
+                stats.full_pages,
+                stats.free_small_pages,
+                stats.free_segments
+            ),
+            (0, 0, 2)
+        );
+
+        let layout = Layout::from_size_align(0x40, 8).unwrap();
+        let class = layout_class(layout).unwrap();
+        let mut blocks: Vec<_> = (0..10)
+            .map(|_| unsafe { allocator.alloc(layout) })
+            .collect();
+        let stats = unsafe { allocator.stats() };
+        assert_eq!(stats.used_bytes[class], 10 * 0x40);
+        assert_eq!(stats.total_used_bytes(), 10 * 0x40);
+        // One of the segment's small pages is in use.
+        let small_pages = SEGMENT_SIZE / SMALL_PAGE_SIZE - 1;
+        assert_eq!(
+            (
+                stats.full_pages,
+                stats.free_small_pages,
+                stats.free_segments
+            ),
+            (0, small_pages - 1, 1)
+        );
+
+        // The page is found full when the next block is allocated.
+        blocks.extend((10..=class_page_blocks(class)).map(|_| unsafe { allocator.alloc(layout) }));
+        let stats = unsafe { allocator.stats() };
+        assert_eq!(stats.used_bytes[class], blocks.len() * 0x40);
+        assert_eq!(
+            (stats.full_pages, stats.free_small_pages),
+            (1, small_pages - 2)
+        );
+
+        for block in blocks.drain(..5) {
+            unsafe { allocator.dealloc(block, layout) };
+        }
+        // Freeing blocks of the full page moves it back to the class's pages.
+        let stats = unsafe { allocator.stats() };
+        assert_eq!(stats.used_bytes[class], blocks.len() * 0x40);
+        assert_eq!(
+            (stats.full_pages, stats.free_small_pages),
+            (0, small_pages - 2)
+        );
+        assert!(stats.to_string().starts_with(&format!(
+            "{} bytes used [0x40: {}], full_pages=0",
+            blocks.len() * 0x40,
+            blocks.len() * 0x40,
+        )));
+
+        for block in blocks {
+            unsafe { allocator.dealloc(block, layout) };
+        }
+        assert_eq!(unsafe { allocator.stats() }.total_used_bytes(), 0);
+        free_segments(segments);
+    }
+
+    #[test]
+    fn zero_sized_allocations_are_dangling() {
+        let (allocator, segments) = allocator(1);
+        for align in [1, 8, 64, 4096] {
+            let layout = Layout::from_size_align(0, align).unwrap();
+            let ptr = unsafe { allocator.alloc(layout) };
+            assert_eq!(ptr as usize, align);
+            assert_eq!(unsafe { allocator.alloc_zeroed(layout) }, ptr);
+            unsafe { allocator.dealloc(ptr, layout) };
+        }
+        // Nothing was taken from the segments.
+        assert_eq!(allocator.free_segments.len(), 1);
+
+        // Growing from nothing, like pushing to an empty `Vec`, allocates a block, and shrinking
+        // to nothing frees it.
+        let empty = Layout::from_size_align(0, 1).unwrap();
+        let ptr = unsafe { allocator.realloc(ptr::without_provenance_mut(1), empty, 8) };
+        assert!(!ptr.is_null() && ptr as usize != 1);
+        fill_pattern(ptr, 8);
+        let layout = Layout::from_size_align(8, 1).unwrap();
+        let grown = unsafe { allocator.realloc(ptr, layout, 16) };
+        assert!(has_pattern(grown, 8));
+        let layout = Layout::from_size_align(16, 1).unwrap();
+        assert_eq!(unsafe { allocator.realloc(grown, layout, 0) } as usize, 1);
+        assert_eq!(unsafe { allocator.stats() }.total_used_bytes(), 0);
+
+        free_segments(segments);
+    }
+
+    #[test]
+    fn huge_allocations_are_reused_from_the_cache() {
+        let (allocator, segments) = allocator(0);
+        let region = cache_huge_region(&allocator);
+
+        // There's no VMM, so every allocation has to come from the cache.
+        let layout = Layout::from_size_align(HUGE_SIZE - 100, 64).unwrap();
+        for _ in 0..10 {
+            let ptr = unsafe { allocator.alloc(layout) };
+            assert_eq!(ptr, region);
+            assert!(unsafe { allocator.alloc(layout) }.is_null());
+            unsafe { allocator.dealloc(ptr, layout) };
+            assert_eq!(allocator.huge_cache.lock().len(), 1);
+        }
+
+        // Other sizes, and larger alignments than the region has, don't match.
+        let smaller = Layout::from_size_align(HUGE_SIZE - HUGE_ALLOC_GRANULE, 8).unwrap();
+        assert!(unsafe { allocator.alloc(smaller) }.is_null());
+        let misaligned = 2 << (region as usize).trailing_zeros();
+        let aligned = Layout::from_size_align(HUGE_SIZE, misaligned).unwrap();
+        assert!(unsafe { allocator.alloc(aligned) }.is_null());
+        assert_eq!(allocator.take_cached_huge(HUGE_SIZE, 8), Some(region));
+
+        // The cache is bounded, and evicts the oldest allocation.
+        let addrs: Vec<_> = (1..=HUGE_CACHE_LEN as u64 + 2)
+            .map(|i| VirtAddr::new(i * HUGE_SIZE as u64))
+            .collect();
+        for (i, &addr) in addrs.iter().enumerate() {
+            let evicted = allocator.cache_huge(addr, HUGE_SIZE);
+            let expected = i.checked_sub(HUGE_CACHE_LEN).map(|i| (addrs[i], HUGE_SIZE));
+            assert_eq!(evicted, expected);
+            assert!(allocator.huge_cache.lock().len() <= HUGE_CACHE_LEN);
+        }
+
+        free_huge_region(region);
+        free_segments(segments);
+    }
+}
@@ -1,4 +1,4 @@
-use core::mem;
+use core::{iter::FusedIterator, marker::PhantomData, mem, ptr, ptr::NonNull};
 
 use alloc::boxed::Box;
 
@@ -8,47 +8,109 @@ use alloc::boxed::Box;
 /// small per element allocations. Therefore this'll perform worse, but there won't be reallocation
 /// stutters.
 pub struct PairingHeap<T: Ord> {
-    root: Option<Box<Node<T>>>,
+    root: Link<T>,
     num_merges: usize,
     len: usize,
+    _marker: PhantomData<Box<Node<T>>>,
 }
 
+// SAFETY: The heap owns its nodes, and the links and `prev` pointers only point into them.
+unsafe impl<T: Ord + Send> Send for PairingHeap<T> {}
+
+/// Refers to an element of a [`PairingHeap`], for changing its value with
+/// [`PairingHeap::increase_key`].
+pub struct Handle<T: Ord>(NonNull<Node<T>>);
+
+impl<T: Ord> Clone for Handle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+impl<T: Ord> Copy for Handle<T> {}
+
+/// An owning link to a node, allocated with [`Node::alloc`]. These are raw pointers rather than
+/// `Box`es, since moving a `Box` would invalidate the handles and `prev` pointers to its node.
+type Link<T> = Option<NonNull<Node<T>>>;
+
 struct Node<T: Ord> {
-    left_child: Option<Box<Node<T>>>,
-    right_sibiling: Option<Box<Node<T>>>,
+    /// The node whose `left_child` or `right_sibiling` this is, or null for the root.
+    prev: *mut Node<T>,
+    left_child: Link<T>,
+    right_sibiling: Link<T>,
     value: T,
 }
 
 impl<T: Ord> Node<T> {
-    const fn new(value: T) -> Self {
-        Self {
+    /// Allocates a detached node.
+    fn alloc(value: T) -> NonNull<Self> {
+        NonNull::from(Box::leak(Box::new(Self {
+            prev: ptr::null_mut(),
             left_child: None,
             right_sibiling: None,
             value,
+        })))
+    }
+
+    /// Frees `node`, returning its value.
+    ///
+    /// # Safety
+    /// `node` must be from [`Self::alloc`], and nothing may link to it anymore.
+    unsafe fn free(node: NonNull<Self>) -> T {
+        unsafe { Box::from_raw(node.as_ptr()).value }
+    }
+
+    /// # Safety
+    /// `node` and `child` must be valid.
+    unsafe fn set_left_child(node: NonNull<Self>, child: Link<T>) {
+        unsafe {
+            if let Some(child) = child {
+                (*child.as_ptr()).prev = node.as_ptr();
+            }
+            (*node.as_ptr()).left_child = child;
+        }
+    }
+
+    /// # Safety
+    /// `node` and `sibiling` must be valid.
+    unsafe fn set_right_sibiling(node: NonNull<Self>, sibiling: Link<T>) {
+        unsafe {
+            if let Some(sibiling) = sibiling {
+                (*sibiling.as_ptr()).prev = node.as_ptr();
+            }
+            (*node.as_ptr()).right_sibiling = sibiling;
         }
     }
 }
 
-fn meld<T: Ord>(root: &mut Box<Node<T>>, mut child: Box<Node<T>>) {
-    debug_assert!(root.right_sibiling.is_none());
-    debug_assert!(child.right_sibiling.is_none());
+/// # Safety
+/// `root` and `child` must be valid trees without right sibilings.
+unsafe fn meld<T: Ord>(root: &mut NonNull<Node<T>>, mut child: NonNull<Node<T>>) {
+    unsafe {
+        debug_assert!((*root.as_ptr()).right_sibiling.is_none());
+        debug_assert!((*child.as_ptr()).right_sibiling.is_none());
 
-    if root.value < child.value {
-        mem::swap(&mut child, root);
+        if (*root.as_ptr()).value < (*child.as_ptr()).value {
+            mem::swap(&mut child, root);
+            (*root.as_ptr()).prev = (*child.as_ptr()).prev;
+        }
+        Node::set_right_sibiling(child, (*root.as_ptr()).left_child.take());
+        Node::set_left_child(*root, Some(child));
     }
-    let sibiling = root.left_child.take();
-    root.left_child.insert(child).right_sibiling = sibiling;
 }
 
-fn merge_pairs<T: Ord>(root: &mut Box<Node<T>>) {
-    let Some(mut node1) = root.right_sibiling.take() else {
-        return;
-    };
-    let node2 = node1.right_sibiling.take();
-    meld(root, node1);
-    if let Some(mut node2) = node2 {
-        merge_pairs(&mut node2);
-        meld(root, node2);
+/// # Safety
+/// `root` must be a valid tree.
+unsafe fn merge_pairs<T: Ord>(root: &mut NonNull<Node<T>>) {
+    unsafe {
+        let Some(node1) = (*root.as_ptr()).right_sibiling.take() else {
+            return;
+        };
+        let node2 = (*node1.as_ptr()).right_sibiling.take();
+        meld(root, node1);
+        if let Some(mut node2) = node2 {
+            merge_pairs(&mut node2);
+            meld(root, node2);
+        }
     }
 }
 
@@ -58,6 +120,7 @@ impl<T: Ord> PairingHeap<T> {
             root: None,
             len: 0,
             num_merges: 0,
+            _marker: PhantomData,
         }
     }
 
@@ -70,73 +133,368 @@ impl<T: Ord> PairingHeap<T> {
     pub fn peek(&mut self) -> Option<&T> {
         let root = self.root.as_mut()?;
         self.num_merges = 0;
-        merge_pairs(root);
-        Some(&root.value)
+        unsafe {
+            merge_pairs(root);
+            Some(&(*root.as_ptr()).value)
+        }
     }
 
-    pub fn push(&mut self, value: T) {
+    /// Pushes `value`, returning a handle to it for [`Self::increase_key`].
+    pub fn push(&mut self, value: T) -> Handle<T> {
         self.len += 1;
-        self.num_merges += 1;
+        let node = Node::alloc(value);
+        unsafe { self.push_node(node) };
+        Handle(node)
+    }
 
-        let mut node = Box::new(Node::new(value));
+    /// Inserts a detached subtree.
+    ///
+    /// # Safety
+    /// `node` must be a valid tree that nothing links to.
+    unsafe fn push_node(&mut self, mut node: NonNull<Node<T>>) {
+        self.num_merges += 1;
 
         let Some(root) = &mut self.root else {
-            self.root = Some(node);
+            self.set_root(Some(node));
             self.num_merges = 0;
             return;
         };
 
-        if root.value <= node.value {
-            mem::swap(root, &mut node);
-            root.left_child = Some(node);
-            self.num_merges = 0;
+        unsafe {
+            if (*root.as_ptr()).value <= (*node.as_ptr()).value {
+                mem::swap(root, &mut node);
+                (*root.as_ptr()).prev = ptr::null_mut();
+                // The old root's unmerged siblings are smaller than the new root too.
+                let sibilings = (*node.as_ptr()).right_sibiling.take();
+                meld(root, node);
+                Node::set_right_sibiling(*root, sibilings);
+                self.num_merges = 0;
+                return;
+            }
+            let root = *root;
+            Node::set_right_sibiling(node, (*root.as_ptr()).right_sibiling.take());
+            Node::set_right_sibiling(root, Some(node));
+
+            for _ in 0..self.num_merges.trailing_zeros() {
+                let Some(mut node1) = (*root.as_ptr()).right_sibiling.take() else {
+                    break;
+                };
+                let Some(node2) = (*node1.as_ptr()).right_sibiling.take() else {
+                    Node::set_right_sibiling(root, Some(node1));
+                    break;
+                };
+                let node3 = (*node2.as_ptr()).right_sibiling.take();
+                meld(&mut node1, node2);
+                Node::set_right_sibiling(node1, node3);
+                let done = (*node1.as_ptr()).right_sibiling.is_none();
+                Node::set_right_sibiling(root, Some(node1));
+                if done {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Moves all of `other`'s elements into this heap. This only merges `other`'s unmerged pairs,
+    /// like a `peek()` on it would.
+    pub fn merge(&mut self, mut other: Self) {
+        let Some(mut root) = other.root.take() else {
             return;
+        };
+        self.len += other.len;
+        unsafe {
+            merge_pairs(&mut root);
+            self.push_node(root);
         }
-        node.right_sibiling = root.right_sibiling.take();
-        root.right_sibiling = Some(node);
+    }
 
-        for _ in 0..self.num_merges.trailing_zeros() {
-            let Some(mut node1) = root.right_sibiling.take() else {
-                break;
-            };
-            let Some(mut node2) = node1.right_sibiling.take() else {
-                break;
+    fn set_root(&mut self, root: Link<T>) {
+        if let Some(root) = root {
+            unsafe { (*root.as_ptr()).prev = ptr::null_mut() };
+        }
+        self.root = root;
+    }
+
+    /// Replaces the value of the element at `handle` with `value`, which must not be smaller.
+    ///
+    /// # Safety
+    /// `handle` must have been returned by [`Self::push`] on this heap or on one merged into it, and
+    /// its element must not have been popped.
+    pub unsafe fn increase_key(&mut self, handle: Handle<T>, value: T) {
+        let node = handle.0;
+        unsafe {
+            assert!(
+                (*node.as_ptr()).value <= value,
+                "increase_key can't decrease a value"
+            );
+            (*node.as_ptr()).value = value;
+            let Some(prev) = NonNull::new((*node.as_ptr()).prev) else {
+                return;
             };
-            let node3 = node2.right_sibiling.take();
-            meld(&mut node1, node2);
-            node1.right_sibiling = node3;
-            let node1 = root.right_sibiling.insert(node1);
-            if node1.right_sibiling.is_none() {
-                break;
+            // Cut the node's subtree out and push it back in.
+            let next = (*node.as_ptr()).right_sibiling.take();
+            match (*prev.as_ptr()).left_child == Some(node) {
+                true => Node::set_left_child(prev, next),
+                false => Node::set_right_sibiling(prev, next),
             }
+            self.push_node(node);
         }
     }
 
+    /// Iterates over the elements in descending order, by popping them.
+    pub fn into_iter_sorted(self) -> IntoIterSorted<T> {
+        IntoIterSorted(self)
+    }
+
     pub fn pop(&mut self) -> Option<T> {
         let mut root = self.root.take()?;
-        merge_pairs(&mut root);
         self.num_merges = 0;
         self.len -= 1;
-        self.root = root.left_child.take();
-        Some(root.value)
+        unsafe {
+            merge_pairs(&mut root);
+            self.set_root((*root.as_ptr()).left_child.take());
+            Some(Node::free(root))
+        }
     }
 
     /// Usually faster than `pop()`
     pub fn pop_any(&mut self) -> Option<T> {
-        let mut root = self.root.take()?;
-        if let Some(mut node) = root.right_sibiling.take() {
-            if let Some(mut child) = node.left_child.take() {
-                merge_pairs(&mut child);
-                root.right_sibiling.insert(child).right_sibiling = node.right_sibiling.take();
-            } else {
-                root.right_sibiling = node.right_sibiling.take();
+        let root = self.root?;
+        self.len -= 1;
+        unsafe {
+            let Some(node) = (*root.as_ptr()).right_sibiling.take() else {
+                self.set_root((*root.as_ptr()).left_child.take());
+                return Some(Node::free(root));
+            };
+            let rest = (*node.as_ptr()).right_sibiling.take();
+            match (*node.as_ptr()).left_child.take() {
+                Some(mut child) => {
+                    merge_pairs(&mut child);
+                    Node::set_right_sibiling(child, rest);
+                    Node::set_right_sibiling(root, Some(child));
+                }
+                None => Node::set_right_sibiling(root, rest),
+            }
+            Some(Node::free(node))
+        }
+    }
+}
+
+impl<T: Ord> Drop for PairingHeap<T> {
+    fn drop(&mut self) {
+        // The tree can be deeper than the stack, so instead of recursing, a node's left child is
+        // rotated up until it has none, and then it's freed.
+        let mut link = self.root.take();
+        while let Some(node) = link {
+            unsafe {
+                link = match (*node.as_ptr()).left_child.take() {
+                    Some(child) => {
+                        (*node.as_ptr()).left_child = (*child.as_ptr()).right_sibiling;
+                        (*child.as_ptr()).right_sibiling = Some(node);
+                        Some(child)
+                    }
+                    None => {
+                        let next = (*node.as_ptr()).right_sibiling;
+                        Node::free(node);
+                        next
+                    }
+                };
             }
-            self.root = Some(root);
+        }
+    }
+}
+
+impl<T: Ord> FromIterator<T> for PairingHeap<T> {
+    fn from_iter<I: IntoIterator<Item = T>>(iter: I) -> Self {
+        let mut heap = Self::new();
+        for value in iter {
+            heap.push(value);
+        }
+        heap
+    }
+}
+
+impl<T: Ord> IntoIterator for PairingHeap<T> {
+    type Item = T;
+    type IntoIter = IntoIterSorted<T>;
+
+    fn into_iter(self) -> IntoIterSorted<T> {
+        self.into_iter_sorted()
+    }
+}
+
+/// Returned by [`PairingHeap::into_iter_sorted`].
+pub struct IntoIterSorted<T: Ord>(PairingHeap<T>);
+
+impl<T: Ord> Iterator for IntoIterSorted<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.0.pop()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.len(), Some(self.0.len()))
+    }
+}
+
+impl<T: Ord> ExactSizeIterator for IntoIterSorted<T> {}
+impl<T: Ord> FusedIterator for IntoIterSorted<T> {}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{collections::BTreeMap, rc::Rc, vec::Vec};
+    use core::iter;
+
+    use super::*;
+
+    /// Pseudo-random numbers from a fixed seed.
+    fn lcg(seed: u64) -> impl Iterator<Item = u64> {
+        iter::successors(Some(seed), |x| {
+            Some(
+                x.wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407),
+            )
+        })
+        .map(|x| x >> 33)
+    }
+
+    fn drain(heap: PairingHeap<u64>) -> Vec<u64> {
+        let len = heap.len();
+        let values: Vec<u64> = heap.into_iter_sorted().collect();
+        assert_eq!(values.len(), len);
+        values
+    }
+
+    #[test]
+    fn pops_in_descending_order() {
+        let values: Vec<u64> = lcg(1).take(200).map(|x| x % 50).collect();
+        let mut heap: PairingHeap<u64> = values.iter().copied().collect();
+        assert_eq!(heap.len(), values.len());
+        let mut sorted = values.clone();
+        sorted.sort_unstable_by(|a, b| b.cmp(a));
+
+        assert_eq!(heap.peek(), Some(&sorted[0]));
+        for (i, &expected) in sorted.iter().enumerate() {
+            assert_eq!(heap.pop(), Some(expected));
+            assert_eq!(heap.len(), sorted.len() - i - 1);
+        }
+        assert_eq!(heap.pop(), None);
+        assert_eq!(heap.pop_any(), None);
+    }
+
+    #[test]
+    fn increase_key_surfaces_the_new_max() {
+        let mut heap = PairingHeap::new();
+        let handles: Vec<Handle<u64>> = (0..20).map(|value| heap.push(value)).collect();
+        assert_eq!(heap.peek(), Some(&19));
+
+        unsafe { heap.increase_key(handles[5], 100) };
+        assert_eq!(heap.peek(), Some(&100));
+        // Increasing the root, or to the same value, keeps the order.
+        unsafe { heap.increase_key(handles[5], 101) };
+        unsafe { heap.increase_key(handles[3], 3) };
+        unsafe { heap.increase_key(handles[7], 50) };
+
+        let mut expected: Vec<u64> = (0..20).filter(|&value| value != 5 && value != 7).collect();
+        expected.extend([101, 50]);
+        expected.sort_unstable_by(|a, b| b.cmp(a));
+        assert_eq!(drain(heap), expected);
+    }
+
+    #[test]
+    #[should_panic = "can't decrease"]
+    fn increase_key_rejects_smaller_values() {
+        let mut heap = PairingHeap::new();
+        let handle = heap.push(5);
+        heap.push(7);
+        unsafe { heap.increase_key(handle, 4) };
+    }
+
+    /// Random pushes, pops and key increases, checked against a map from ids to values.
+    #[test]
+    fn random_operations_match_a_model() {
+        let mut heap = PairingHeap::new();
+        let mut handles = BTreeMap::new();
+        let mut model = BTreeMap::new();
+        let mut rng = lcg(7);
+        let mut next = || rng.next().unwrap();
+
+        for id in 0..2000 {
+            match next() % 6 {
+                0..=2 => {
+                    let key = next() % 1000;
+                    handles.insert(id, heap.push((key, id)));
+                    model.insert(id, key);
+                }
+                3 if !model.is_empty() => {
+                    let (key, id) = heap.pop().unwrap();
+                    let max = model.iter().map(|(&id, &key)| (key, id)).max().unwrap();
+                    assert_eq!((key, id), max);
+                    model.remove(&id);
+                    handles.remove(&id);
+                }
+                4 if !model.is_empty() => {
+                    let (key, id) = heap.pop_any().unwrap();
+                    assert_eq!(model.remove(&id), Some(key));
+                    handles.remove(&id);
+                }
+                5 if !model.is_empty() => {
+                    let nth = next() as usize % model.len();
+                    let (&id, key) = model.iter_mut().nth(nth).unwrap();
+                    *key += next() % 500;
+                    unsafe { heap.increase_key(handles[&id], (*key, id)) };
+                }
+                _ => {}
+            }
+            assert_eq!(heap.len(), model.len());
+        }
+
+        let mut expected: Vec<(u64, u64)> = model.into_iter().map(|(id, key)| (key, id)).collect();
+        expected.sort_unstable_by(|a, b| b.cmp(a));
+        let values: Vec<(u64, u64)> = heap.into_iter().collect();
+        assert_eq!(values, expected);
+    }
+
+    #[test]
+    fn merge_keeps_every_element() {
+        let mut evens: PairingHeap<u64> = (0..50).map(|x| 2 * x).collect();
+        let mut odds = PairingHeap::new();
+        let handles: Vec<Handle<u64>> = (0..30).map(|x| odds.push(2 * x + 1)).collect();
+        evens.merge(odds);
+        assert_eq!(evens.len(), 80);
+        evens.merge(PairingHeap::new());
+        assert_eq!(evens.len(), 80);
+
+        // Handles into the merged heap stay valid.
+        unsafe { evens.increase_key(handles[0], 1000) };
+        assert_eq!(evens.peek(), Some(&1000));
+
+        let mut expected: Vec<u64> = (0..50)
+            .map(|x| 2 * x)
+            .chain((1..30).map(|x| 2 * x + 1))
+            .collect();
+        expected.push(1000);
+        expected.sort_unstable_by(|a, b| b.cmp(a));
+        assert_eq!(drain(evens), expected);
+
+        let mut empty = PairingHeap::new();
+        empty.merge((0..3).collect());
+        assert_eq!(drain(empty), [2, 1, 0]);
+    }
 
-            Some(node.value)
-        } else {
-            self.root = root.left_child.take();
-            Some(root.value)
+    #[test]
+    fn drop_frees_every_element() {
+        let counter = Rc::new(());
+        let mut heap = PairingHeap::new();
+        for i in 0..100 {
+            heap.push((i % 7, i, Rc::clone(&counter)));
         }
+        heap.peek();
+        heap.pop_any();
+        heap.push((3, 100, Rc::clone(&counter)));
+        assert_eq!(Rc::strong_count(&counter), 101);
+        drop(heap);
+        assert_eq!(Rc::strong_count(&counter), 1);
     }
 }
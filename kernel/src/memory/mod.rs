@@ -2,10 +2,50 @@ pub mod malloc;
 pub mod pmm;
 pub mod vmm;
 
-pub use vmm::VMM;
+pub use vmm::{MappingFlags, VMM};
 
 use bootloader_api::info::{BootInfo, MemoryRegionKind};
-use x86_64::{registers::control::Cr3, structures::paging::OffsetPageTable, VirtAddr};
+use core::{mem, slice};
+
+use x86_64::{
+    instructions::interrupts::without_interrupts, registers::control::Cr3,
+    structures::paging::OffsetPageTable, PhysAddr, VirtAddr,
+};
+
+/// Where the bootloader mapped all of physical memory, and the end of the mapped physical range.
+static PHYS_WINDOW: spin::Once<(VirtAddr, PhysAddr)> = spin::Once::new();
+
+/// Translates a physical address to its virtual address in the physical memory window, checking (in
+/// debug builds) that `size` bytes from `phys` are mapped.
+pub(crate) fn phys_to_virt(phys: PhysAddr, size: usize) -> VirtAddr {
+    let &(phys_offset, phys_end) = PHYS_WINDOW.get().expect("Memory not initialized");
+    debug_assert!(
+        phys.as_u64()
+            .checked_add(size as u64)
+            .is_some_and(|end| end <= phys_end.as_u64()),
+        "{phys:?}+0x{size:x} is outside of the physical memory window ending at {phys_end:?}",
+    );
+    phys_offset + phys.as_u64()
+}
+
+/// Reads a `T` at the physical address `phys`. The address doesn't need to be aligned.
+///
+/// # Safety
+/// `phys` must hold a valid `T`.
+pub unsafe fn read_phys<T: Copy>(phys: PhysAddr) -> T {
+    let virt = phys_to_virt(phys, mem::size_of::<T>());
+    unsafe { virt.as_ptr::<T>().read_unaligned() }
+}
+
+/// Borrows `len` `T`s at the physical address `phys`, which must be aligned to `T`.
+///
+/// # Safety
+/// `phys` must hold `len` valid `T`s, which aren't modified while the slice is alive.
+pub unsafe fn read_phys_slice<T: Copy>(phys: PhysAddr, len: usize) -> &'static [T] {
+    let virt = phys_to_virt(phys, mem::size_of::<T>() * len);
+    debug_assert!(virt.is_aligned(mem::align_of::<T>() as u64));
+    unsafe { slice::from_raw_parts(virt.as_ptr(), len) }
+}
 
 unsafe fn offset_page_table(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
     let (lvl4_table, _) = Cr3::read();
@@ -13,6 +53,16 @@ unsafe fn offset_page_table(physical_memory_offset: VirtAddr) -> OffsetPageTable
     unsafe { OffsetPageTable::new(&mut *lvl4_table_addr.as_mut_ptr(), physical_memory_offset) }
 }
 
+/// Approximate number of bytes the kernel heap can still allocate: the memory the allocator holds
+/// on to, plus the kernel address space that can still be mapped (limited by the free physical
+/// memory). Fragmentation is ignored, so an allocation of this size may still fail.
+pub fn free_heap_bytes() -> usize {
+    without_interrupts(|| {
+        let vmm_free = VMM.get().map_or(0, |vmm| vmm.lock().kernel_free_bytes());
+        vmm_free + unsafe { malloc::ALLOC.free_bytes() }
+    })
+}
+
 pub fn init(boot_info: &mut BootInfo) {
     boot_info.memory_regions.sort_unstable_by_key(|r| r.start);
     let memory_size = (boot_info.memory_regions.iter())
@@ -20,7 +70,13 @@ pub fn init(boot_info: &mut BootInfo) {
         .last()
         .unwrap();
     let phys_offset = VirtAddr::new(boot_info.physical_memory_offset.into_option().unwrap());
+    let phys_end = (boot_info.memory_regions.iter())
+        .map(|r| r.end)
+        .max()
+        .unwrap();
+    PHYS_WINDOW.call_once(|| (phys_offset, PhysAddr::new(phys_end)));
     let mapper = unsafe { offset_page_table(phys_offset) };
+    vmm::enable_nx();
 
     vmm::init(
         mapper,
@@ -28,4 +84,14 @@ pub fn init(boot_info: &mut BootInfo) {
         &*boot_info.memory_regions,
         memory_size,
     );
+    let (free, total) = {
+        let mut vmm = VMM.get().unwrap().lock();
+        unsafe { vmm.reclaim_lower_half() };
+        vmm.phys_memory()
+    };
+    log::info!(
+        "Physical memory: {} KiB free of {} KiB",
+        free >> 10,
+        total >> 10,
+    );
 }
@@ -5,7 +5,8 @@ use core::{array, iter, mem, ops, ptr::NonNull, slice};
 use bootloader_api::info::{MemoryRegion, MemoryRegionKind};
 use x86_64::{
     structures::paging::{
-        FrameAllocator, FrameDeallocator, OffsetPageTable, PageSize, PhysFrame, Size2MiB, Size4KiB,
+        FrameAllocator, FrameDeallocator, OffsetPageTable, PageSize, PhysFrame, Size1GiB, Size2MiB,
+        Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
@@ -14,6 +15,8 @@ use crate::bitmap::Bitmap;
 
 struct FreeList {
     next: Option<NonNull<Self>>,
+    /// `None` for the head of the list.
+    prev: Option<NonNull<Self>>,
 }
 
 #[derive(Debug)]
@@ -72,7 +75,7 @@ pub unsafe fn init(
                 start &= !4095;
             }
             // blue waffle
-            allocator.free_region(PhysAddr::new(start)..PhysAddr::new(end));
+            allocator.add_region(PhysAddr::new(start)..PhysAddr::new(end));
 
             start = r.start + 4095 & !4095;
         }
@@ -83,7 +86,7 @@ pub unsafe fn init(
         start += (mem::size_of::<usize>() * buddy_map_len) as u64 + 4095;
         start &= !4095;
     }
-    allocator.free_region(PhysAddr::new(start)..PhysAddr::new(end));
+    allocator.add_region(PhysAddr::new(start)..PhysAddr::new(end));
 
     allocator
 }
@@ -131,23 +134,41 @@ impl Buddy<'_> {
             return;
         };
         let next = self.free_list;
+        if let Some(mut next) = next {
+            unsafe { next.as_mut().prev = Some(ptr) };
+        }
         unsafe {
-            self.free_list.insert(ptr).as_ptr().write(FreeList { next });
+            (self.free_list.insert(ptr).as_ptr()).write(FreeList { next, prev: None });
         }
     }
 
     fn pop_free_list(&mut self) -> Option<VirtAddr> {
-        let mut free = self.free_list?;
-        self.free_list = unsafe { free.as_mut().next };
+        let free = self.free_list?;
+        unsafe { self.remove_free_list(VirtAddr::from_ptr(free.as_ptr())) };
         Some(VirtAddr::from_ptr(free.as_ptr()))
     }
+
+    /// Unlinks the block at `addr` from the free list.
+    ///
+    /// # Safety
+    /// The block must be in this free list.
+    unsafe fn remove_free_list(&mut self, addr: VirtAddr) {
+        let free = unsafe { &*addr.as_ptr::<FreeList>() };
+        match free.prev {
+            Some(mut prev) => unsafe { prev.as_mut().next = free.next },
+            None => self.free_list = free.next,
+        }
+        if let Some(mut next) = free.next {
+            unsafe { next.as_mut().prev = free.prev };
+        }
+    }
 }
 
 // 2**12 bytes = 4 KiB
 // 2**21 bytes = 2 MiB
 // 2**30 bytes = 1 GiB
 
-const ORDERS: ops::Range<u8> = 12..22;
+const ORDERS: ops::Range<u8> = 12..31;
 // const MIN_ORDER: u8 = 21;
 // const MAX_ORDER: u8 = 30;
 
@@ -209,12 +230,18 @@ impl<'a> ops::DerefMut for Buddies<'a> {
 pub struct BuddyAllocator<'a> {
     buddies: Buddies<'a>,
     phys_offset: VirtAddr,
+    memory_size: usize,
+    /// Size of the free blocks, kept up to date by `alloc` and `free`.
+    free_bytes: usize,
+    /// Size of all the memory handed to the allocator with `add_region` and `add_block`.
+    total_bytes: usize,
 }
 
 impl<'a> BuddyAllocator<'a> {
+    /// Size of an order's map in words, with a bit for every (possibly partial) pair of blocks.
     const fn order_map_size(memory_size: usize, order: u8) -> usize {
         const BITS: usize = usize::BITS as _;
-        ((memory_size >> order + 1) + BITS - 1) / BITS
+        memory_size.div_ceil(1 << (order + 1)).div_ceil(BITS)
     }
 
     /// Required length of the `buddy_map` slice.
@@ -261,9 +288,40 @@ impl<'a> BuddyAllocator<'a> {
         Self {
             buddies: Buddies(buddies),
             phys_offset: page_table.phys_offset(),
+            memory_size,
+            free_bytes: 0,
+            total_bytes: 0,
         }
     }
 
+    /// Total size of the free blocks, in bytes.
+    pub fn free_bytes(&self) -> usize {
+        self.free_bytes
+    }
+
+    /// Size of all the memory the allocator manages, in bytes, whether free or allocated.
+    pub fn total_bytes(&self) -> usize {
+        self.total_bytes
+    }
+
+    /// Hands a region of memory that didn't come from this allocator over to it, counting it in
+    /// [`Self::total_bytes`].
+    pub fn add_region(&mut self, range: ops::Range<PhysAddr>) {
+        self.total_bytes += (range.end - range.start) as usize;
+        self.free_region(range);
+    }
+
+    /// Like [`Self::add_region`], for a single block of `1 << order` bytes.
+    pub fn add_block(&mut self, order: u8, addr: PhysAddr) {
+        self.total_bytes += 1 << order;
+        self.free(order, addr);
+    }
+
+    /// Whether `addr` is in the physical range the allocator keeps track of.
+    pub fn contains(&self, addr: PhysAddr) -> bool {
+        (addr.as_u64() as usize) < self.memory_size
+    }
+
     pub fn free_region(&mut self, range: ops::Range<PhysAddr>) {
         log::info!("free_region: {range:?}");
 
@@ -308,18 +366,19 @@ impl<'a> BuddyAllocator<'a> {
         // );
 
         assert!(addr.is_aligned(1u64 << order));
+        self.free_bytes += 1 << order;
 
-        let mut pair = (addr.as_u64() >> order) as usize;
+        // Merge with the buddy for as long as it's free. The top order's blocks aren't merged.
+        let mut addr = addr.as_u64();
         for (order, buddy) in (order..).zip(&mut self.buddies[order..]) {
-            pair /= 2;
+            let pair = (addr >> (order + 1)) as usize;
             buddy.toggle_chunk_pair(pair);
-            if buddy.is_chunk_pair_different(pair) {
-                unsafe { buddy.push_free_list(self.phys_offset + addr.as_u64()) };
+            if buddy.is_chunk_pair_different(pair) || order + 1 == ORDERS.end {
+                unsafe { buddy.push_free_list(self.phys_offset + addr) };
                 return;
             }
-        }
-        unsafe {
-            (self.buddies.last_mut().unwrap()).push_free_list(self.phys_offset + addr.as_u64());
+            unsafe { buddy.remove_free_list(self.phys_offset + (addr ^ (1 << order))) };
+            addr &= !((1 << (order + 1)) - 1);
         }
     }
 
@@ -335,10 +394,18 @@ impl<'a> BuddyAllocator<'a> {
                 continue;
             };
             let addr = PhysAddr::new(addr - self.phys_offset);
+            let phys_offset = self.phys_offset;
 
+            // Split the block down to `order`, freeing the upper half at every order on the way.
             for (buddy_order, buddy) in (order..).zip(&mut self.buddies[order..=buddy_order]) {
                 buddy.toggle_chunk_pair((addr.as_u64() >> buddy_order + 1) as _);
             }
+            self.free_bytes -= 1 << buddy_order;
+            for (half_order, buddy) in (order..).zip(&mut self.buddies[order..buddy_order]) {
+                let half = phys_offset + addr.as_u64() + (1u64 << half_order);
+                unsafe { buddy.push_free_list(half) };
+                self.free_bytes += 1 << half_order;
+            }
             return Some(addr);
         }
 
@@ -346,6 +413,29 @@ impl<'a> BuddyAllocator<'a> {
     }
 }
 
+impl BuddyAllocator<'_> {
+    /// Allocates `num_frames` physically contiguous 4 KiB frames. The block is allocated with the
+    /// next power of two frames, and the frames after `num_frames` are freed right away.
+    pub fn alloc_contiguous(&mut self, num_frames: usize) -> Option<PhysAddr> {
+        let order = ORDERS.start + num_frames.max(1).next_power_of_two().trailing_zeros() as u8;
+        if !ORDERS.contains(&order) {
+            return None;
+        }
+        let addr = self.alloc(order)?;
+        let end = addr + (num_frames << ORDERS.start) as u64;
+        let block_end = addr + (1u64 << order);
+        if end < block_end {
+            self.free_region(end..block_end);
+        }
+        Some(addr)
+    }
+
+    /// Frees `num_frames` frames allocated by [`Self::alloc_contiguous`].
+    pub fn free_contiguous(&mut self, addr: PhysAddr, num_frames: usize) {
+        self.free_region(addr..addr + (num_frames << ORDERS.start) as u64);
+    }
+}
+
 unsafe impl FrameAllocator<Size4KiB> for BuddyAllocator<'_> {
     fn allocate_frame(&mut self) -> Option<PhysFrame> {
         Some(PhysFrame::from_start_address(self.alloc(12)?).unwrap())
@@ -369,3 +459,183 @@ impl FrameDeallocator<Size2MiB> for BuddyAllocator<'_> {
         self.free(21, frame.start_address());
     }
 }
+
+unsafe impl FrameAllocator<Size1GiB> for BuddyAllocator<'_> {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size1GiB>> {
+        Some(PhysFrame::from_start_address(self.alloc(30)?).unwrap())
+    }
+}
+
+impl FrameDeallocator<Size1GiB> for BuddyAllocator<'_> {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size1GiB>) {
+        self.free(30, frame.start_address());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::{alloc, vec, vec::Vec};
+
+    use x86_64::structures::paging::PageTable;
+
+    /// Host memory standing in for the physical memory from address 0 up to its size. It's only
+    /// touched where the free lists are, so large sizes are cheap.
+    struct Memory {
+        ptr: *mut u8,
+        layout: alloc::Layout,
+    }
+
+    impl Memory {
+        fn new(size: usize) -> Self {
+            let layout = alloc::Layout::from_size_align(size, 1 << ORDERS.start).unwrap();
+            let ptr = unsafe { alloc::alloc(layout) };
+            assert!(!ptr.is_null());
+            Self { ptr, layout }
+        }
+
+        /// An allocator without any memory in it yet, with its map in `buddy_map`.
+        fn allocator<'a>(&self, buddy_map: &'a mut Vec<usize>) -> BuddyAllocator<'a> {
+            let size = self.layout.size();
+            *buddy_map = vec![0; BuddyAllocator::buddy_map_len(size)];
+            let mut level_4_table = PageTable::new();
+            let page_table =
+                unsafe { OffsetPageTable::new(&mut level_4_table, VirtAddr::from_ptr(self.ptr)) };
+            BuddyAllocator::new(size, &page_table, buddy_map)
+        }
+    }
+
+    impl Drop for Memory {
+        fn drop(&mut self) {
+            unsafe { alloc::dealloc(self.ptr, self.layout) };
+        }
+    }
+
+    const GIB: usize = 1 << 30;
+
+    /// The physical addresses in each order's free list, sorted, and the buddy map.
+    fn snapshot(allocator: &BuddyAllocator) -> (Vec<Vec<u64>>, Vec<usize>) {
+        let free_lists = (allocator.buddies.iter())
+            .map(|buddy| {
+                let mut addrs: Vec<_> =
+                    iter::successors(buddy.free_list, |free| unsafe { free.as_ref().next })
+                        .map(|free| VirtAddr::from_ptr(free.as_ptr()) - allocator.phys_offset)
+                        .collect();
+                addrs.sort();
+                addrs
+            })
+            .collect();
+        let map = (allocator.buddies.iter())
+            .flat_map(|buddy| buddy.map.0.iter().copied())
+            .collect();
+        (free_lists, map)
+    }
+
+    /// Allocates and frees `num_frames` contiguous frames, checking that they're all merged back.
+    fn alloc_free_contiguous(num_frames: usize) {
+        const SIZE: usize = 64 << 20;
+        let memory = Memory::new(SIZE);
+        let mut buddy_map = Vec::new();
+        let mut allocator = memory.allocator(&mut buddy_map);
+        allocator.add_region(PhysAddr::new(0)..PhysAddr::new(SIZE as _));
+        let before = snapshot(&allocator);
+
+        let addr = allocator.alloc_contiguous(num_frames).unwrap();
+        assert_eq!(allocator.free_bytes(), SIZE - (num_frames << 12));
+        allocator.free_contiguous(addr, num_frames);
+        assert_eq!(allocator.free_bytes(), SIZE);
+        assert_eq!(snapshot(&allocator), before);
+
+        // Every frame is handed out exactly once.
+        let mut frames: Vec<_> = iter::from_fn(|| allocator.alloc(12)).collect();
+        frames.sort();
+        frames.dedup();
+        assert_eq!(frames.len(), SIZE >> 12);
+    }
+
+    #[test]
+    fn free_bytes_of_memory_map() {
+        const MIB: u64 = 1 << 20;
+        let region = |start, end, kind| MemoryRegion { start, end, kind };
+        let regions = [
+            region(0, MIB / 2, MemoryRegionKind::Usable),
+            region(MIB / 2, MIB, MemoryRegionKind::Bootloader),
+            region(MIB, 3 * MIB, MemoryRegionKind::Usable),
+            region(3 * MIB, 4 * MIB, MemoryRegionKind::Usable),
+            region(4 * MIB, 5 * MIB, MemoryRegionKind::Bootloader),
+            region(5 * MIB, 37 * MIB, MemoryRegionKind::Usable),
+            region(37 * MIB, 38 * MIB, MemoryRegionKind::UnknownUefi(0)),
+        ];
+        let memory_size = 37 * MIB;
+        let memory = Memory::new(memory_size as _);
+        let mut level_4_table = PageTable::new();
+        let page_table =
+            unsafe { OffsetPageTable::new(&mut level_4_table, VirtAddr::from_ptr(memory.ptr)) };
+        let mut allocator = unsafe { init(&page_table, &regions, memory_size) };
+
+        // The usable memory above 1 MiB, less the pages of the buddy map at its start.
+        let buddy_map_bytes =
+            (8 * BuddyAllocator::buddy_map_len(memory_size as _)).next_multiple_of(4096);
+        let usable = (3 * MIB + 32 * MIB) as usize;
+        assert_eq!(allocator.total_bytes(), usable - buddy_map_bytes);
+        assert_eq!(allocator.free_bytes(), usable - buddy_map_bytes);
+
+        let frames: Vec<_> = iter::from_fn(|| allocator.alloc(12)).collect();
+        assert_eq!(frames.len() << 12, usable - buddy_map_bytes);
+        assert_eq!(allocator.free_bytes(), 0);
+        for frame in frames {
+            allocator.free(12, frame);
+        }
+        assert_eq!(allocator.free_bytes(), usable - buddy_map_bytes);
+    }
+
+    #[test]
+    fn alloc_free_3_contiguous_frames() {
+        alloc_free_contiguous(3);
+    }
+
+    #[test]
+    fn alloc_free_5_contiguous_frames() {
+        alloc_free_contiguous(5);
+    }
+
+    #[test]
+    fn alloc_1gib_frames() {
+        let memory = Memory::new(4 * GIB);
+        let mut buddy_map = Vec::new();
+        let mut allocator = memory.allocator(&mut buddy_map);
+        allocator.add_region(PhysAddr::new(0)..PhysAddr::new(4 * GIB as u64));
+
+        let mut frames: Vec<PhysFrame<Size1GiB>> =
+            iter::from_fn(|| allocator.allocate_frame()).collect();
+        frames.sort_by_key(|frame| frame.start_address());
+        let starts: Vec<_> = frames.iter().map(|frame| frame.start_address()).collect();
+        assert_eq!(
+            starts,
+            (0..4)
+                .map(|i| PhysAddr::new((i * GIB) as _))
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(allocator.free_bytes(), 0);
+
+        // Splitting a 1 GiB block keeps the rest of it free.
+        unsafe { allocator.deallocate_frame(frames.pop().unwrap()) };
+        let frame: PhysFrame<Size4KiB> = allocator.allocate_frame().unwrap();
+        assert_eq!(allocator.free_bytes(), GIB - (1 << 12));
+        let huge_frames: Vec<PhysFrame<Size2MiB>> =
+            iter::from_fn(|| allocator.allocate_frame()).collect();
+        assert_eq!(huge_frames.len(), 511);
+        assert_eq!(allocator.free_bytes(), (1 << 21) - (1 << 12));
+
+        unsafe { allocator.deallocate_frame(frame) };
+        for frame in huge_frames {
+            unsafe { allocator.deallocate_frame(frame) };
+        }
+        for frame in frames {
+            unsafe { allocator.deallocate_frame(frame) };
+        }
+        assert_eq!(allocator.free_bytes(), 4 * GIB);
+        assert!(buddy_map.iter().all(|&word| word == 0));
+    }
+}
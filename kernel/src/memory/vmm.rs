@@ -1,13 +1,22 @@
-use core::fmt;
+use core::{
+    fmt, ops,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
 use alloc::collections::{BTreeMap, BTreeSet};
+use bitflags::bitflags;
 use bootloader_api::info::MemoryRegion;
+use raw_cpuid::CpuId;
 use x86_64::{
+    registers::{
+        control::Cr3,
+        model_specific::{Efer, EferFlags},
+    },
     structures::paging::{
-        mapper::{MapToError, MapperFlush},
+        mapper::{MapToError, MappedFrame, MapperFlush, TranslateResult},
         page_table::PageTableLevel,
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags,
-        PhysFrame, Size2MiB, Size4KiB,
+        FrameAllocator, FrameDeallocator, Mapper, OffsetPageTable, Page, PageSize, PageTable,
+        PageTableFlags, PhysFrame, Size1GiB, Size2MiB, Size4KiB, Translate,
     },
     PhysAddr, VirtAddr,
 };
@@ -19,6 +28,110 @@ use super::{
 
 const PAGE_SIZE: usize = Size4KiB::SIZE as _;
 const HUGE_PAGE_SIZE: usize = Size2MiB::SIZE as _;
+/// Operations touching more pages than this flush the whole TLB once at the end, instead of
+/// flushing every page.
+const TLB_FLUSH_ALL_THRESHOLD: usize = 32;
+
+/// Returns the frames of the page table at `table_addr` and of the tables under it to
+/// `frame_allocator`, but not the frames they map. The tables were allocated from `frame_allocator`
+/// if `allocated`, and otherwise they're the bootloader's, and their frames are added to it as new
+/// memory. Returns the number of frames freed.
+fn free_table_frames(
+    frame_allocator: &mut BuddyAllocator,
+    phys_offset: VirtAddr,
+    table_addr: PhysAddr,
+    level: PageTableLevel,
+    allocated: bool,
+) -> usize {
+    let table: &PageTable = unsafe { &*(phys_offset + table_addr.as_u64()).as_ptr() };
+    let mut freed = 0;
+    if let Some(next_level) = level.next_lower_level() {
+        for entry in table.iter() {
+            let flags = entry.flags();
+            if flags.contains(PageTableFlags::PRESENT) && !flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                freed += free_table_frames(
+                    frame_allocator,
+                    phys_offset,
+                    entry.addr(),
+                    next_level,
+                    allocated,
+                );
+            }
+        }
+    }
+    // The frames the bootloader took from low memory or beyond the managed range aren't the buddy
+    // allocator's to add.
+    let page_ord = PAGE_SIZE.trailing_zeros() as _;
+    if allocated {
+        frame_allocator.free(page_ord, table_addr);
+        freed += 1;
+    } else if 0x100000 <= table_addr.as_u64() && frame_allocator.contains(table_addr) {
+        frame_allocator.add_block(page_ord, table_addr);
+        freed += 1;
+    }
+    freed
+}
+
+/// Number of pages mapping `size` bytes at `addr`, with 2 MiB pages where they're aligned.
+fn page_count(addr: VirtAddr, size: usize) -> usize {
+    let head = size.min((addr.align_up(HUGE_PAGE_SIZE as u64) - addr) as usize);
+    let rest = size - head;
+    head / PAGE_SIZE + rest / HUGE_PAGE_SIZE + rest % HUGE_PAGE_SIZE / PAGE_SIZE
+}
+
+/// Flushes a single page, unless the whole TLB is flushed at the end of the operation.
+fn flush_page<S: PageSize>(flush: MapperFlush<S>, flush_all: bool) {
+    match flush_all {
+        true => flush.ignore(),
+        false => flush.flush(),
+    }
+}
+
+/// Whether `NO_EXECUTE` can be used in page table entries, set by [`enable_nx`].
+static NX_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Enables the no-execute bit in EFER, if the CPU supports it. Until then, [`MappingFlags`]
+/// ignores `EXECUTABLE` and every mapping is executable.
+pub fn enable_nx() {
+    let supported = (CpuId::new().get_extended_processor_and_feature_identifiers())
+        .is_some_and(|info| info.has_execute_disable());
+    if supported {
+        unsafe { Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+        NX_ENABLED.store(true, Ordering::Relaxed);
+    }
+}
+
+bitflags! {
+    /// Permissions of the pages of a mapping. Mappings are always readable.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct MappingFlags: u8 {
+        const WRITABLE = 1 << 0;
+        const EXECUTABLE = 1 << 1;
+        /// Accessible from ring 3. Mappings in the user half always are.
+        const USER = 1 << 2;
+    }
+}
+
+impl MappingFlags {
+    /// Writable data, what every mapping used to be.
+    pub const DATA: Self = Self::WRITABLE;
+
+    /// The page table flags of a present page with these permissions, in the kernel or user half.
+    pub fn page_table_flags(self, kernel: bool) -> PageTableFlags {
+        let mut flags = PageTableFlags::PRESENT;
+        flags.set(PageTableFlags::WRITABLE, self.contains(Self::WRITABLE));
+        flags.set(
+            PageTableFlags::USER_ACCESSIBLE,
+            !kernel || self.contains(Self::USER),
+        );
+        flags.set(
+            PageTableFlags::NO_EXECUTE,
+            !self.contains(Self::EXECUTABLE) && NX_ENABLED.load(Ordering::Relaxed),
+        );
+        flags
+    }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 struct SizeAddr {
@@ -78,6 +191,11 @@ impl TreeBestFitAlloc {
         Some(SizeAddr { size, addr })
     }
 
+    /// Total size of the free ranges.
+    fn free_bytes(&self) -> usize {
+        self.addr_size_tree.values().sum()
+    }
+
     fn free(&mut self, mut addr: usize, mut size: usize) {
         // log::info!("We shall free: addr={addr:?} size={size}");
         addr &= !(PAGE_SIZE - 1);
@@ -97,12 +215,15 @@ impl TreeBestFitAlloc {
 
         // log::info!("JOE SHAV 2");
 
-        if let Some((next_addr, next_size)) = (addr.checked_add(size))
-            .and_then(|next_addr| self.addr_size_tree.remove_entry(&next_addr))
+        // Absorb the ranges that overlap or touch the end of the freed range.
+        while let Some((&next_addr, &next_size)) = (self.addr_size_tree)
+            .range(addr..=addr.saturating_add(size))
+            .next()
         {
+            self.addr_size_tree.remove(&next_addr);
             self.size_addr_tree
                 .remove(&SizeAddr::new(next_size, next_addr));
-            size += next_size;
+            size = size.max(next_addr - addr + next_size);
         }
 
         // log::info!("New memory to be created");
@@ -119,6 +240,8 @@ pub struct VirtualMemoryManager<'a> {
     kernel_alloc: TreeBestFitAlloc,
     user_alloc: TreeBestFitAlloc,
     kernel_start: VirtAddr,
+    /// Ranges made by `reserve`, by address, whose pages are only mapped when first touched.
+    reserved: BTreeMap<usize, usize>,
 }
 
 impl<'a> fmt::Debug for VirtualMemoryManager<'a> {
@@ -129,6 +252,7 @@ impl<'a> fmt::Debug for VirtualMemoryManager<'a> {
             .field("kernel_alloc", &self.kernel_alloc)
             .field("user_alloc", &self.user_alloc)
             .field("kernel_start", &self.kernel_start)
+            .field("reserved", &self.reserved)
             .finish()
     }
 }
@@ -145,7 +269,58 @@ impl<'a> VirtualMemoryManager<'a> {
             frame_allocator,
             kernel_alloc: TreeBestFitAlloc::new(),
             user_alloc: TreeBestFitAlloc::new(),
+            reserved: BTreeMap::new(),
+        }
+    }
+
+    /// How many bytes can still be allocated in kernel space, limited by both the free virtual
+    /// address space and the free physical memory.
+    pub fn kernel_free_bytes(&self) -> usize {
+        (self.kernel_alloc.free_bytes()).min(self.frame_allocator.free_bytes())
+    }
+
+    /// Free and total physical memory, in bytes.
+    pub fn phys_memory(&self) -> (usize, usize) {
+        (
+            self.frame_allocator.free_bytes(),
+            self.frame_allocator.total_bytes(),
+        )
+    }
+
+    /// What the page containing `addr` is mapped to, or `None` if it isn't mapped.
+    pub fn query(&self, addr: VirtAddr) -> Option<Mapping> {
+        let phys_offset = self.page_table.phys_offset();
+        let kernel = self.kernel_start <= addr;
+        let mut table = self.page_table.level_4_table();
+        let indices = [
+            addr.p4_index(),
+            addr.p3_index(),
+            addr.p2_index(),
+            addr.p1_index(),
+        ];
+        for (level, index) in indices.into_iter().enumerate() {
+            let entry = &table[index];
+            let flags = entry.flags();
+            if !flags.contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            let page_size = match level {
+                1 if flags.contains(PageTableFlags::HUGE_PAGE) => Some(MappedPageSize::Size1GiB),
+                2 if flags.contains(PageTableFlags::HUGE_PAGE) => Some(MappedPageSize::Size2MiB),
+                3 => Some(MappedPageSize::Size4KiB),
+                _ => None,
+            };
+            if let Some(page_size) = page_size {
+                return Some(Mapping {
+                    frame: entry.addr(),
+                    page_size,
+                    flags,
+                    kernel,
+                });
+            }
+            table = unsafe { &*(phys_offset + entry.addr().as_u64()).as_ptr() };
         }
+        unreachable!()
     }
 
     unsafe fn page_map<S: PageSize + fmt::Debug>(
@@ -167,6 +342,155 @@ impl<'a> VirtualMemoryManager<'a> {
         }
     }
 
+    /// Unmaps the lower half PML4 entries the kernel doesn't use anymore, like the bootloader's
+    /// identity mappings, returning their page tables to the frame allocator and their address
+    /// space to `user_alloc`. Entries holding the kernel image, the current stack or the physical
+    /// memory window are kept. Everything else the bootloader mapped for the kernel (boot info,
+    /// framebuffer) is in the dynamic range, which starts at `kernel_start`.
+    ///
+    /// # Safety
+    /// Nothing may reference the reclaimed mappings.
+    pub unsafe fn reclaim_lower_half(&mut self) {
+        const LVL4_ENTRY_ALIGN: u64 = PageTableLevel::Four.entry_address_space_alignment();
+
+        let stack_marker = 0u8;
+        let keep = [
+            VirtAddr::from_ptr(Self::reclaim_lower_half as *const ()),
+            VirtAddr::from_ptr(&VMM),
+            VirtAddr::from_ptr(&stack_marker),
+            self.page_table.phys_offset(),
+        ];
+        let pml4_kernel_start = self.kernel_start.as_u64() / LVL4_ENTRY_ALIGN % 512;
+        let phys_offset = self.page_table.phys_offset();
+
+        let mut reclaimed_entries = 0;
+        let mut freed_frames = 0;
+        for i in 0..pml4_kernel_start {
+            let entry = &mut self.page_table.level_4_table_mut()[i as usize];
+            if entry.is_unused()
+                || keep
+                    .iter()
+                    .any(|addr| u16::from(addr.p4_index()) as u64 == i)
+            {
+                continue;
+            }
+            let (table_addr, present) = (
+                entry.addr(),
+                entry.flags().contains(PageTableFlags::PRESENT),
+            );
+            entry.set_unused();
+            if present {
+                freed_frames += free_table_frames(
+                    &mut self.frame_allocator,
+                    phys_offset,
+                    table_addr,
+                    PageTableLevel::Three,
+                    false,
+                );
+            }
+            self.user_alloc
+                .free((i * LVL4_ENTRY_ALIGN) as _, LVL4_ENTRY_ALIGN as _);
+            reclaimed_entries += 1;
+        }
+        x86_64::instructions::tlb::flush_all();
+
+        log::info!(
+            "Reclaimed {reclaimed_entries} lower half PML4 entries and {freed_frames} page table \
+             frames"
+        );
+    }
+
+    /// Creates an address space with an empty lower half and the kernel half shared with the
+    /// current one. The kernel half PML4 entries are filled in first, so that kernel mappings made
+    /// later show up in every address space.
+    pub fn new_address_space(&mut self) -> Option<AddressSpace> {
+        const LVL4_ENTRY_ALIGN: u64 = PageTableLevel::Four.entry_address_space_alignment();
+
+        let phys_offset = self.page_table.phys_offset();
+        let page_ord = PAGE_SIZE.trailing_zeros() as _;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        let pml4_kernel_start = (self.kernel_start.as_u64() / LVL4_ENTRY_ALIGN % 512) as usize;
+
+        for i in pml4_kernel_start..512 {
+            if !self.page_table.level_4_table()[i].is_unused() {
+                continue;
+            }
+            let table_addr = self.frame_allocator.alloc(page_ord)?;
+            unsafe {
+                (phys_offset + table_addr.as_u64())
+                    .as_mut_ptr::<PageTable>()
+                    .write(PageTable::new());
+            }
+            self.page_table.level_4_table_mut()[i].set_addr(table_addr, flags);
+        }
+
+        let pml4_addr = self.frame_allocator.alloc(page_ord)?;
+        let pml4: &mut PageTable = unsafe { &mut *(phys_offset + pml4_addr.as_u64()).as_mut_ptr() };
+        *pml4 = PageTable::new();
+        for i in pml4_kernel_start..512 {
+            pml4[i] = self.page_table.level_4_table()[i].clone();
+        }
+
+        let mut user_alloc = TreeBestFitAlloc::new();
+        user_alloc.free(0, pml4_kernel_start * LVL4_ENTRY_ALIGN as usize);
+        Some(AddressSpace {
+            pml4: PhysFrame::from_start_address(pml4_addr).unwrap(),
+            user_alloc,
+        })
+    }
+
+    /// Makes `space` the active address space by loading its PML4 into CR3, and returns the
+    /// previously active one. From then on, lower half mappings are made in `space`.
+    ///
+    /// # Safety
+    /// The lower half of the current address space must not be in use, as it's replaced by the
+    /// lower half of `space`.
+    pub unsafe fn switch_to(&mut self, mut space: AddressSpace) -> AddressSpace {
+        let phys_offset = self.page_table.phys_offset();
+        let (old_pml4, flags) = Cr3::read();
+        let pml4 =
+            unsafe { &mut *(phys_offset + space.pml4.start_address().as_u64()).as_mut_ptr() };
+        self.page_table = unsafe { OffsetPageTable::new(pml4, phys_offset) };
+        core::mem::swap(&mut self.user_alloc, &mut space.user_alloc);
+        unsafe { Cr3::write(space.pml4, flags) };
+        space.pml4 = old_pml4;
+        space
+    }
+
+    /// Returns the PML4 of `space` and its lower half page tables to the frame allocator. The frames
+    /// its lower half maps are left alone, so they should be freed with [`Self::free`] while
+    /// `space` is active.
+    ///
+    /// # Safety
+    /// `space` must have been made by [`Self::new_address_space`], and it must not be active.
+    pub unsafe fn free_address_space(&mut self, space: AddressSpace) {
+        const LVL4_ENTRY_ALIGN: u64 = PageTableLevel::Four.entry_address_space_alignment();
+
+        assert_ne!(
+            Cr3::read().0,
+            space.pml4,
+            "can't free the active address space"
+        );
+        let phys_offset = self.page_table.phys_offset();
+        let pml4_addr = space.pml4.start_address();
+        let pml4: &PageTable = unsafe { &*(phys_offset + pml4_addr.as_u64()).as_ptr() };
+        let pml4_kernel_start = (self.kernel_start.as_u64() / LVL4_ENTRY_ALIGN % 512) as usize;
+        for entry in pml4.iter().take(pml4_kernel_start) {
+            if entry.flags().contains(PageTableFlags::PRESENT) {
+                free_table_frames(
+                    &mut self.frame_allocator,
+                    phys_offset,
+                    entry.addr(),
+                    PageTableLevel::Three,
+                    true,
+                );
+            }
+        }
+        // The kernel half's tables are shared, so only the PML4 itself goes.
+        self.frame_allocator
+            .free(PAGE_SIZE.trailing_zeros() as _, pml4_addr);
+    }
+
     /// Make sure that `phys_addr` is not mapped to any virtual address.
     pub unsafe fn map(
         &mut self,
@@ -174,6 +498,7 @@ impl<'a> VirtualMemoryManager<'a> {
         mut size: usize,
         align_order: u8,
         mut phys_addr: PhysAddr,
+        flags: MappingFlags,
     ) -> Option<VirtAddr> {
         let addr_offset = phys_addr.as_u64() as usize & (PAGE_SIZE - 1);
         phys_addr -= addr_offset as u64;
@@ -186,34 +511,62 @@ impl<'a> VirtualMemoryManager<'a> {
         let mut addr = VirtAddr::new(addr as _);
         let return_addr = addr + addr_offset as u64;
 
-        let mut page_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        page_flags.set(PageTableFlags::USER_ACCESSIBLE, !kernel);
+        let page_flags = flags.page_table_flags(kernel);
+        let flush_all = TLB_FLUSH_ALL_THRESHOLD < page_count(addr, size);
         while 0 < size && !addr.is_aligned(HUGE_PAGE_SIZE as u64) {
             let frame = unsafe { PhysFrame::<Size4KiB>::from_start_address_unchecked(phys_addr) };
-            unsafe { self.page_map(addr, frame, page_flags).unwrap().flush() };
+            flush_page(
+                unsafe { self.page_map(addr, frame, page_flags).unwrap() },
+                flush_all,
+            );
             phys_addr += PAGE_SIZE as u64;
             addr += PAGE_SIZE as u64;
             size -= PAGE_SIZE;
         }
         while HUGE_PAGE_SIZE <= size {
             let frame = unsafe { PhysFrame::<Size2MiB>::from_start_address_unchecked(phys_addr) };
-            unsafe { self.page_map(addr, frame, page_flags).unwrap().flush() };
+            flush_page(
+                unsafe { self.page_map(addr, frame, page_flags).unwrap() },
+                flush_all,
+            );
             phys_addr += HUGE_PAGE_SIZE as u64;
             addr += HUGE_PAGE_SIZE as u64;
             size -= HUGE_PAGE_SIZE;
         }
         while 0 < size {
             let frame = unsafe { PhysFrame::<Size4KiB>::from_start_address_unchecked(phys_addr) };
-            unsafe { self.page_map(addr, frame, page_flags).unwrap().flush() };
+            flush_page(
+                unsafe { self.page_map(addr, frame, page_flags).unwrap() },
+                flush_all,
+            );
             phys_addr += PAGE_SIZE as u64;
             addr += PAGE_SIZE as u64;
             size -= PAGE_SIZE;
         }
+        if flush_all {
+            x86_64::instructions::tlb::flush_all();
+        }
 
         Some(return_addr)
     }
 
-    pub fn alloc(&mut self, kernel: bool, size: usize, align_order: u8) -> Option<VirtAddr> {
+    /// Temporarily maps `size` bytes of physical memory at `phys_addr` into kernel space. The
+    /// mapping is removed when the returned guard is dropped.
+    ///
+    /// # Safety
+    /// Same as [`Self::map`].
+    pub unsafe fn map_temp(&mut self, phys_addr: PhysAddr, size: usize) -> Option<MappedRegion> {
+        let addr = unsafe { self.map(true, size, 0, phys_addr, MappingFlags::DATA)? };
+        Some(MappedRegion { addr, size })
+    }
+
+    pub fn alloc(
+        &mut self,
+        kernel: bool,
+        size: usize,
+        align_order: u8,
+        flags: MappingFlags,
+    ) -> Option<VirtAddr> {
         let SizeAddr { addr, mut size } = match kernel {
             true => self.kernel_alloc.alloc(size, align_order)?,
             false => self.user_alloc.alloc(size, align_order)?,
@@ -225,26 +578,38 @@ impl<'a> VirtualMemoryManager<'a> {
             core::alloc::Layout::from_size_align(size, 1 << align_order),
         );
 
-        let mut page_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
-        page_flags.set(PageTableFlags::USER_ACCESSIBLE, !kernel);
+        let page_flags = flags.page_table_flags(kernel);
+        let flush_all = TLB_FLUSH_ALL_THRESHOLD < page_count(addr, size);
         while 0 < size && !addr.is_aligned(HUGE_PAGE_SIZE as u64) {
             let frame: PhysFrame<Size4KiB> = self.frame_allocator.allocate_frame()?;
-            unsafe { self.page_map(addr, frame, page_flags).unwrap().flush() };
+            flush_page(
+                unsafe { self.page_map(addr, frame, page_flags).unwrap() },
+                flush_all,
+            );
             addr += PAGE_SIZE as u64;
             size -= PAGE_SIZE;
         }
         while HUGE_PAGE_SIZE <= size {
             let frame: PhysFrame<Size2MiB> = self.frame_allocator.allocate_frame()?;
-            unsafe { self.page_map(addr, frame, page_flags).unwrap().flush() };
+            flush_page(
+                unsafe { self.page_map(addr, frame, page_flags).unwrap() },
+                flush_all,
+            );
             addr += HUGE_PAGE_SIZE as u64;
             size -= HUGE_PAGE_SIZE;
         }
         while 0 < size {
             let frame: PhysFrame<Size4KiB> = self.frame_allocator.allocate_frame()?;
-            unsafe { self.page_map(addr, frame, page_flags).unwrap().flush() };
+            flush_page(
+                unsafe { self.page_map(addr, frame, page_flags).unwrap() },
+                flush_all,
+            );
             addr += PAGE_SIZE as u64;
             size -= PAGE_SIZE;
         }
+        if flush_all {
+            x86_64::instructions::tlb::flush_all();
+        }
 
         log::info!(
             "VMM_END_ALLOC: addr={return_addr:?} layout={:?} kernel={kernel}",
@@ -254,16 +619,210 @@ impl<'a> VirtualMemoryManager<'a> {
         Some(return_addr)
     }
 
-    pub unsafe fn free(&mut self, mut addr: VirtAddr, mut size: usize) {
-        let kernel = self.kernel_start <= addr;
+    /// Like [`Self::alloc`], but maps only with 2 MiB pages. `size` must be a multiple of 2 MiB,
+    /// and the address is aligned to at least 2 MiB.
+    pub fn alloc_huge(&mut self, kernel: bool, size: usize, align_order: u8) -> Option<VirtAddr> {
+        if size & (HUGE_PAGE_SIZE - 1) != 0 {
+            return None;
+        }
+        let align_order = align_order.max(HUGE_PAGE_SIZE.trailing_zeros() as _);
+        let SizeAddr { addr, size } = match kernel {
+            true => self.kernel_alloc.alloc(size, align_order)?,
+            false => self.user_alloc.alloc(size, align_order)?,
+        };
+        let return_addr = VirtAddr::new(addr as _);
 
-        if !kernel && self.kernel_start < addr + size as u64 {
-            unsafe {
-                self.free(
-                    self.kernel_start,
-                    (addr + size as u64 - self.kernel_start) as _,
-                );
+        let page_flags = MappingFlags::DATA.page_table_flags(kernel);
+        let flush_all = TLB_FLUSH_ALL_THRESHOLD < size / HUGE_PAGE_SIZE;
+        for offset in (0..size).step_by(HUGE_PAGE_SIZE) {
+            let addr = return_addr + offset as u64;
+            let frame: PhysFrame<Size2MiB> = self.frame_allocator.allocate_frame()?;
+            flush_page(
+                unsafe { self.page_map(addr, frame, page_flags).unwrap() },
+                flush_all,
+            );
+        }
+        if flush_all {
+            x86_64::instructions::tlb::flush_all();
+        }
+
+        Some(return_addr)
+    }
+
+    /// Like [`Self::alloc`], but nothing is mapped until it's touched: the page fault handler maps
+    /// a zeroed frame at each page of the range with [`Self::commit_page`].
+    pub fn reserve(&mut self, kernel: bool, size: usize, align_order: u8) -> Option<VirtAddr> {
+        let SizeAddr { addr, size } = match kernel {
+            true => self.kernel_alloc.alloc(size, align_order)?,
+            false => self.user_alloc.alloc(size, align_order)?,
+        };
+        self.reserved.insert(addr, size);
+        Some(VirtAddr::new(addr as _))
+    }
+
+    /// The reserved range containing `addr`, as its start and size.
+    fn reserved_range(&self, addr: VirtAddr) -> Option<(VirtAddr, usize)> {
+        let addr = addr.as_u64() as usize;
+        let (&start, &size) = self.reserved.range(..=addr).next_back()?;
+        (addr < start + size).then_some((VirtAddr::new(start as _), size))
+    }
+
+    /// Maps a zeroed frame at the page containing `addr`, if it's in a reserved range and not mapped
+    /// yet. Returns whether a page was mapped.
+    pub fn commit_page(&mut self, addr: VirtAddr) -> bool {
+        if self.reserved_range(addr).is_none() || self.page_table.translate_addr(addr).is_some() {
+            return false;
+        }
+        let page = Page::<Size4KiB>::containing_address(addr);
+        let Some(frame) = FrameAllocator::<Size4KiB>::allocate_frame(&mut self.frame_allocator)
+        else {
+            return false;
+        };
+        let phys_offset = self.page_table.phys_offset();
+        unsafe {
+            (phys_offset + frame.start_address().as_u64())
+                .as_mut_ptr::<u8>()
+                .write_bytes(0, PAGE_SIZE);
+        }
+
+        let page_flags = MappingFlags::DATA.page_table_flags(self.kernel_start <= addr);
+        match unsafe { self.page_map(page.start_address(), frame, page_flags) } {
+            Ok(flush) => {
+                flush.flush();
+                true
+            }
+            Err(_) => {
+                unsafe { self.frame_allocator.deallocate_frame(frame) };
+                false
+            }
+        }
+    }
+
+    /// Frees a range made by [`Self::reserve`], along with the frames of the pages that were
+    /// committed.
+    ///
+    /// # Safety
+    /// Nothing may reference the range.
+    pub unsafe fn unreserve(&mut self, addr: VirtAddr) {
+        let Some(size) = self.reserved.remove(&(addr.as_u64() as usize)) else {
+            log::warn!("unreserve: {addr:?} isn't the start of a reserved range");
+            return;
+        };
+        let flush_all = TLB_FLUSH_ALL_THRESHOLD < size / PAGE_SIZE;
+        for offset in (0..size).step_by(PAGE_SIZE) {
+            let page = Page::<Size4KiB>::from_start_address(addr + offset as u64).unwrap();
+            if let Ok((frame, flush)) = self.page_table.unmap(page) {
+                flush_page(flush, flush_all);
+                unsafe { self.frame_allocator.deallocate_frame(frame) };
+            }
+        }
+        if flush_all {
+            x86_64::instructions::tlb::flush_all();
+        }
+        match self.kernel_start <= addr {
+            true => self.kernel_alloc.free(addr.as_u64() as _, size),
+            false => self.user_alloc.free(addr.as_u64() as _, size),
+        }
+    }
+
+    /// Remaps the 2 MiB `page` with 4 KiB pages, keeping its frame and flags.
+    ///
+    /// # Safety
+    /// `page` must be mapped to `frame` with `flags`.
+    unsafe fn split_huge_page(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+        flush_all: bool,
+    ) {
+        let (_, flush) = self.page_table.unmap(page).unwrap();
+        flush_page(flush, flush_all);
+        let flags = flags - PageTableFlags::HUGE_PAGE;
+        for offset in (0..HUGE_PAGE_SIZE as u64).step_by(PAGE_SIZE) {
+            let frame = PhysFrame::<Size4KiB>::containing_address(frame.start_address() + offset);
+            flush_page(
+                unsafe { (self.page_map(page.start_address() + offset, frame, flags)).unwrap() },
+                flush_all,
+            );
+        }
+    }
+
+    /// Changes the permissions of the pages in `size` bytes at `addr`, without unmapping them. A 2
+    /// MiB page only partly in the range is split into 4 KiB pages first. Fails with the first
+    /// unmapped address if the range has holes, or the first address of a 1 GiB page that's only
+    /// partly in the range, in which case nothing is changed.
+    pub fn protect(
+        &mut self,
+        addr: VirtAddr,
+        size: usize,
+        flags: MappingFlags,
+    ) -> Result<(), VirtAddr> {
+        let end = (addr + size as u64).align_up(PAGE_SIZE as u64);
+        let start = addr.align_down(PAGE_SIZE as u64);
+
+        let mut addr = start;
+        while addr < end {
+            let mapping = self.query(addr).ok_or(addr)?;
+            let page_start = addr.align_down(mapping.page_size.size());
+            let page_end = page_start + mapping.page_size.size();
+            if mapping.page_size == MappedPageSize::Size1GiB
+                && (page_start < start || end < page_end)
+            {
+                return Err(addr);
             }
+            addr = page_end;
+        }
+
+        let flush_all = TLB_FLUSH_ALL_THRESHOLD < page_count(start, (end - start) as _);
+        let mut addr = start;
+        while addr < end {
+            let mapping = self.query(addr).unwrap();
+            let page_flags = flags.page_table_flags(mapping.kernel);
+            match mapping.page_size {
+                MappedPageSize::Size4KiB => {
+                    let page = Page::<Size4KiB>::containing_address(addr);
+                    let flush = unsafe { self.page_table.update_flags(page, page_flags) };
+                    flush_page(flush.unwrap(), flush_all);
+                    addr += PAGE_SIZE as u64;
+                }
+                MappedPageSize::Size2MiB => {
+                    let page = Page::<Size2MiB>::containing_address(addr);
+                    if addr != page.start_address() || end < page.start_address() + page.size() {
+                        let frame = PhysFrame::containing_address(mapping.frame);
+                        unsafe { self.split_huge_page(page, frame, mapping.flags, flush_all) };
+                        continue;
+                    }
+                    let flush = unsafe { self.page_table.update_flags(page, page_flags) };
+                    flush_page(flush.unwrap(), flush_all);
+                    addr += HUGE_PAGE_SIZE as u64;
+                }
+                MappedPageSize::Size1GiB => {
+                    // Checked above to be entirely in the range.
+                    let page = Page::<Size1GiB>::containing_address(addr);
+                    let flush = unsafe { self.page_table.update_flags(page, page_flags) };
+                    flush_page(flush.unwrap(), flush_all);
+                    addr = page.start_address() + page.size();
+                }
+            }
+        }
+        if flush_all {
+            x86_64::instructions::tlb::flush_all();
+        }
+        Ok(())
+    }
+
+    /// Unmaps `size` bytes at `addr` and returns their address space. The range doesn't have to be
+    /// a whole allocation: a 2 MiB page only partly in the range is split into 4 KiB pages, and the
+    /// pages outside the range stay mapped. Holes in the range are skipped.
+    pub unsafe fn free(&mut self, addr: VirtAddr, size: usize) {
+        let end = (addr + size as u64).align_up(PAGE_SIZE as u64);
+        let mut addr = addr.align_down(PAGE_SIZE as u64);
+        let mut size = (end - addr) as usize;
+        let kernel = self.kernel_start <= addr;
+
+        if !kernel && self.kernel_start < end {
+            unsafe { self.free(self.kernel_start, (end - self.kernel_start) as _) };
             size = (self.kernel_start - addr) as _;
         }
 
@@ -272,36 +831,130 @@ impl<'a> VirtualMemoryManager<'a> {
             false => self.user_alloc.free(addr.as_u64() as _, size),
         }
 
-        while 0 < size && !addr.is_aligned(HUGE_PAGE_SIZE as u64) {
-            self.page_table
-                .unmap(Page::<Size4KiB>::from_start_address(addr).unwrap())
-                .unwrap()
-                .1
-                .flush();
-            addr += PAGE_SIZE as u64;
-            size -= PAGE_SIZE;
+        let end = addr + size as u64;
+        let flush_all = TLB_FLUSH_ALL_THRESHOLD < page_count(addr, size);
+        while addr < end {
+            let TranslateResult::Mapped { frame, flags, .. } = self.page_table.translate(addr)
+            else {
+                addr += PAGE_SIZE as u64;
+                continue;
+            };
+            match frame {
+                MappedFrame::Size4KiB(_) => {
+                    let page = Page::<Size4KiB>::containing_address(addr);
+                    let (_, flush) = self.page_table.unmap(page).unwrap();
+                    flush_page(flush, flush_all);
+                    addr += PAGE_SIZE as u64;
+                }
+                MappedFrame::Size2MiB(frame) => {
+                    let page = Page::<Size2MiB>::containing_address(addr);
+                    if addr != page.start_address() || end < page.start_address() + page.size() {
+                        // Only part of the page is freed, the rest stays mapped with 4 KiB pages.
+                        unsafe { self.split_huge_page(page, frame, flags, flush_all) };
+                        continue;
+                    }
+                    let (_, flush) = self.page_table.unmap(page).unwrap();
+                    flush_page(flush, flush_all);
+                    addr += HUGE_PAGE_SIZE as u64;
+                }
+                MappedFrame::Size1GiB(_) => {
+                    log::warn!("free: skipping the 1 GiB page at {addr:?}");
+                    addr = (addr + 1u64).align_up(Size1GiB::SIZE).min(end);
+                }
+            }
         }
-        while HUGE_PAGE_SIZE <= size {
-            self.page_table
-                .unmap(Page::<Size2MiB>::from_start_address(addr).unwrap())
-                .unwrap()
-                .1
-                .flush();
-            addr += HUGE_PAGE_SIZE as u64;
-            size -= HUGE_PAGE_SIZE;
+        if flush_all {
+            x86_64::instructions::tlb::flush_all();
         }
-        while 0 < size {
-            self.page_table
-                .unmap(Page::<Size4KiB>::from_start_address(addr).unwrap())
-                .unwrap()
-                .1
-                .flush();
-            addr += PAGE_SIZE as u64;
-            size -= PAGE_SIZE;
+    }
+}
+
+/// The size of a mapped page.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MappedPageSize {
+    Size4KiB,
+    Size2MiB,
+    Size1GiB,
+}
+
+impl MappedPageSize {
+    pub const fn size(self) -> u64 {
+        match self {
+            Self::Size4KiB => Size4KiB::SIZE,
+            Self::Size2MiB => Size2MiB::SIZE,
+            Self::Size1GiB => Size1GiB::SIZE,
         }
     }
 }
 
+/// What a page is mapped to, returned by [`VirtualMemoryManager::query`].
+#[derive(Debug, Clone, Copy)]
+pub struct Mapping {
+    /// Start of the physical frame.
+    pub frame: PhysAddr,
+    pub page_size: MappedPageSize,
+    pub flags: PageTableFlags,
+    /// Whether the page is in the kernel half.
+    pub kernel: bool,
+}
+
+impl Mapping {
+    /// The physical address `addr` is mapped to, where `addr` is in the queried page.
+    pub fn phys_addr(&self, addr: VirtAddr) -> PhysAddr {
+        self.frame + (addr.as_u64() & (self.page_size.size() - 1))
+    }
+}
+
+/// A page table hierarchy made by [`VirtualMemoryManager::new_address_space`], sharing the kernel
+/// half with every other address space. It isn't freed when dropped, see
+/// [`VirtualMemoryManager::free_address_space`].
+#[must_use]
+#[derive(Debug)]
+pub struct AddressSpace {
+    pml4: PhysFrame,
+    /// The free lower half address space, while this address space isn't active.
+    user_alloc: TreeBestFitAlloc,
+}
+
+impl AddressSpace {
+    /// The frame of the PML4, which is what CR3 points to when this address space is active.
+    pub fn pml4(&self) -> PhysFrame {
+        self.pml4
+    }
+}
+
+/// A kernel mapping of physical memory made by [`VirtualMemoryManager::map_temp`], unmapped when
+/// dropped. Dropping it locks [`VMM`], so it must not be dropped while the lock is held.
+#[must_use]
+#[derive(Debug)]
+pub struct MappedRegion {
+    addr: VirtAddr,
+    size: usize,
+}
+
+impl MappedRegion {
+    pub fn size(&self) -> usize {
+        self.size
+    }
+}
+
+impl ops::Deref for MappedRegion {
+    type Target = VirtAddr;
+    fn deref(&self) -> &VirtAddr {
+        &self.addr
+    }
+}
+
+impl Drop for MappedRegion {
+    fn drop(&mut self) {
+        // Unmap the whole pages `map` mapped.
+        let start = self.addr.align_down(PAGE_SIZE as u64);
+        let size = ((self.addr - start) as usize + self.size).next_multiple_of(PAGE_SIZE);
+        let mut vmm = VMM.get().expect("VMM not initialized").lock();
+        unsafe { vmm.free(start, size) };
+    }
+}
+
 pub static VMM: spin::Once<spin::Mutex<VirtualMemoryManager<'static>>> = spin::Once::new();
 
 pub fn init(
@@ -443,3 +1096,97 @@ pub fn init(
     });
     ALLOC.vmm.call_once(|| VMM.get().unwrap());
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    const BASE: usize = 0x10_0000;
+
+    /// The free ranges as `(start, end)` offsets from `BASE`, checking that both trees agree.
+    fn free_ranges(tree: &TreeBestFitAlloc) -> Vec<(usize, usize)> {
+        let by_size: BTreeSet<_> = (tree.addr_size_tree.iter())
+            .map(|(&addr, &size)| SizeAddr::new(size, addr))
+            .collect();
+        assert_eq!(by_size, tree.size_addr_tree);
+        (tree.addr_size_tree.iter())
+            .map(|(&addr, &size)| (addr - BASE, addr - BASE + size))
+            .collect()
+    }
+
+    /// A tree whose only range, of 16 pages at `BASE`, is allocated.
+    fn allocated_tree() -> TreeBestFitAlloc {
+        let mut tree = TreeBestFitAlloc::new();
+        tree.free(BASE, 0x10000);
+        assert_eq!(tree.alloc(0x10000, 12), Some(SizeAddr::new(0x10000, BASE)));
+        assert_eq!(free_ranges(&tree), []);
+        tree
+    }
+
+    #[test]
+    fn free_head_tail_and_middle() {
+        let mut tree = allocated_tree();
+        tree.free(BASE, 0x3000);
+        assert_eq!(free_ranges(&tree), [(0, 0x3000)]);
+        tree.free(BASE + 0xD000, 0x3000);
+        assert_eq!(free_ranges(&tree), [(0, 0x3000), (0xD000, 0x10000)]);
+        tree.free(BASE + 0x6000, 0x2000);
+        assert_eq!(
+            free_ranges(&tree),
+            [(0, 0x3000), (0x6000, 0x8000), (0xD000, 0x10000)]
+        );
+
+        // Touching ranges coalesce.
+        tree.free(BASE + 0x3000, 0x3000);
+        assert_eq!(free_ranges(&tree), [(0, 0x8000), (0xD000, 0x10000)]);
+        tree.free(BASE + 0xA000, 0x3000);
+        assert_eq!(free_ranges(&tree), [(0, 0x8000), (0xA000, 0x10000)]);
+        assert_eq!(tree.free_bytes(), 0xE000);
+    }
+
+    #[test]
+    fn free_overlapping_ranges() {
+        let mut tree = allocated_tree();
+        tree.free(BASE + 0x1000, 0x2000);
+        tree.free(BASE + 0x5000, 0x1000);
+        tree.free(BASE + 0x9000, 0x2000);
+
+        // Freeing what's already free changes nothing.
+        tree.free(BASE + 0x1000, 0x1000);
+        tree.free(BASE + 0x5000, 0x1000);
+        assert_eq!(
+            free_ranges(&tree),
+            [(0x1000, 0x3000), (0x5000, 0x6000), (0x9000, 0xB000)]
+        );
+
+        // A range overlapping the end of one and the start of another swallows everything between.
+        tree.free(BASE + 0x2000, 0x8000);
+        assert_eq!(free_ranges(&tree), [(0x1000, 0xB000)]);
+        // Unaligned ranges cover the pages they touch.
+        tree.free(BASE + 0xB800, 0x100);
+        assert_eq!(free_ranges(&tree), [(0x1000, 0xC000)]);
+        tree.free(BASE, 0x10000);
+        assert_eq!(free_ranges(&tree), [(0, 0x10000)]);
+    }
+
+    #[test]
+    fn alloc_best_fit_and_aligned() {
+        let mut tree = allocated_tree();
+        tree.free(BASE + 0x1000, 0x9000);
+        tree.free(BASE + 0xC000, 0x2000);
+        // The smallest range that fits is used.
+        assert_eq!(
+            tree.alloc(0x2000, 12),
+            Some(SizeAddr::new(0x2000, BASE + 0xC000))
+        );
+        // Aligning splits the range, and its head and tail stay free.
+        assert_eq!(
+            tree.alloc(0x1000, 14),
+            Some(SizeAddr::new(0x1000, BASE + 0x4000))
+        );
+        assert_eq!(free_ranges(&tree), [(0x1000, 0x4000), (0x5000, 0xA000)]);
+        assert_eq!(tree.alloc(0x6000, 12), None);
+    }
+}
@@ -1,17 +1,55 @@
-use core::fmt;
+use alloc::{boxed::Box, collections::VecDeque, vec, vec::Vec};
+use core::{
+    array, fmt, ops,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use bootloader_api::info::FrameBuffer;
-use hashbrown::HashMap;
+use bootloader_api::info::{FrameBuffer, PixelFormat};
 use x86_64::instructions::interrupts::without_interrupts;
 
-use crate::psf::{self, PsfFile};
+use crate::{
+    array_vec::ArrayVec,
+    psf::{BuiltinFont, CharTable, Glyph, PsfFile},
+};
 
 pub static CONSOLE: spin::Mutex<Option<ConsoleGraphics>> = spin::Mutex::new(None);
 
-pub fn init(font: &'static PsfFile, framebuffer: FrameBuffer) {
+/// Initializes the console on `framebuffer`. With `double_buffered`, drawing goes to a buffer in RAM
+/// and is copied to the framebuffer by [`ConsoleGraphics::present`].
+pub fn init(font: FontSource<'static>, framebuffer: FrameBuffer, double_buffered: bool) {
     log::info!("Initializing console");
-    let mut console = ConsoleGraphics::new(font, framebuffer);
+
+    // A glyph that doesn't fit in the framebuffer would be drawn out of bounds.
+    let info = framebuffer.info();
+    let (glyph_width, glyph_height) = (font.glyph_width() as usize, font.glyph_height() as usize);
+    if !(1..=info.width).contains(&glyph_width) || !(1..=info.height).contains(&glyph_height) {
+        log::error!(
+            "Console not initialized: {glyph_width}x{glyph_height} glyphs don't fit in the {}x{} \
+             framebuffer",
+            info.width,
+            info.height,
+        );
+        return;
+    }
+
+    // The pixel math assumes every row is `stride` pixels, and all `height` rows are in the buffer.
+    let screen_len = info.bytes_per_pixel * info.stride * info.height;
+    if info.stride < info.width || framebuffer.buffer().len() < screen_len {
+        log::error!(
+            "Console not initialized: the framebuffer is {} bytes, but {}x{} pixels with a stride \
+             of {} and {} bytes per pixel need {screen_len}",
+            framebuffer.buffer().len(),
+            info.width,
+            info.height,
+            info.stride,
+            info.bytes_per_pixel,
+        );
+        return;
+    }
+
+    let mut console = ConsoleGraphics::new(font, framebuffer, double_buffered);
     console.clear();
+    console.present();
     (CONSOLE.lock()).replace(console);
     log::info!("Console initialized");
 }
@@ -32,72 +70,691 @@ impl Point {
     }
 }
 
+/// The font the console renders with.
+#[derive(Debug, Clone, Copy)]
+pub enum FontSource<'a> {
+    Psf(&'a PsfFile<'a>),
+    /// The ASCII-only font built into the kernel, see [`BuiltinFont`].
+    Builtin,
+}
+
+impl FontSource<'_> {
+    fn glyph_width(&self) -> u32 {
+        match self {
+            Self::Psf(font) => font.glyph_width(),
+            Self::Builtin => BuiltinFont.glyph_width(),
+        }
+    }
+    fn glyph_height(&self) -> u32 {
+        match self {
+            Self::Psf(font) => font.glyph_height(),
+            Self::Builtin => BuiltinFont.glyph_height(),
+        }
+    }
+}
+
+/// How many screens of lines the scrollback keeps by default.
+const DEFAULT_SCROLLBACK_SCREENS: usize = 4;
+/// Columns between tab stops by default.
+const DEFAULT_TAB_WIDTH: usize = 8;
+
+/// A color, as its red, green and blue components.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Color {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Color {
+    pub const BLACK: Self = Self::new(0, 0, 0);
+    pub const WHITE: Self = Self::new(255, 255, 255);
+
+    /// The 8 basic ANSI colors (black, red, green, yellow, blue, magenta, cyan, white), as in the
+    /// VGA palette.
+    pub const ANSI: [Self; 8] = [
+        Self::new(0, 0, 0),
+        Self::new(170, 0, 0),
+        Self::new(0, 170, 0),
+        Self::new(170, 85, 0),
+        Self::new(0, 0, 170),
+        Self::new(170, 0, 170),
+        Self::new(0, 170, 170),
+        Self::new(170, 170, 170),
+    ];
+    /// The bright variants of [`Self::ANSI`].
+    pub const ANSI_BRIGHT: [Self; 8] = [
+        Self::new(85, 85, 85),
+        Self::new(255, 85, 85),
+        Self::new(85, 255, 85),
+        Self::new(255, 255, 85),
+        Self::new(85, 85, 255),
+        Self::new(255, 85, 255),
+        Self::new(85, 255, 255),
+        Self::new(255, 255, 255),
+    ];
+
+    pub const fn new(r: u8, g: u8, b: u8) -> Self {
+        Self { r, g, b }
+    }
+
+    /// The bytes of a pixel of this color in `format`, padded with zeros, or `None` if the format
+    /// isn't supported. For [`PixelFormat::Unknown`], each channel is a byte at its bit position
+    /// in a little endian pixel.
+    fn to_pixel(self, format: PixelFormat) -> Option<[u8; 4]> {
+        let Self { r, g, b } = self;
+        match format {
+            PixelFormat::Rgb => Some([r, g, b, 0]),
+            PixelFormat::Bgr => Some([b, g, r, 0]),
+            PixelFormat::U8 => {
+                let gray = (299 * r as u32 + 587 * g as u32 + 114 * b as u32) / 1000;
+                Some([gray as u8, 0, 0, 0])
+            }
+            PixelFormat::Unknown {
+                red_position,
+                green_position,
+                blue_position,
+            } => {
+                let channel = |value: u8, position: u8| {
+                    (value as u32).checked_shl(position as _).unwrap_or(0)
+                };
+                let pixel = channel(r, red_position)
+                    | channel(g, green_position)
+                    | channel(b, blue_position);
+                Some(pixel.to_le_bytes())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Set when drawing was skipped because of an unsupported pixel format, so that it's only logged
+/// once.
+static UNSUPPORTED_FORMAT_LOGGED: AtomicBool = AtomicBool::new(false);
+
+const DEFAULT_FG: Color = Color::WHITE;
+const DEFAULT_BG: Color = Color::BLACK;
+
+/// Most parameters an escape sequence can have, the rest are ignored.
+const MAX_ESCAPE_PARAMS: usize = 8;
+
+/// A character of the scrollback, with the colors it was drawn in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Cell {
+    ch: char,
+    fg: Color,
+    bg: Color,
+}
+
+impl Cell {
+    /// An erased cell, which is drawn as black like the rest of the erased screen.
+    const BLANK: Self = Self {
+        ch: ' ',
+        fg: DEFAULT_FG,
+        bg: Color::BLACK,
+    };
+}
+
+/// Where the console is in parsing an escape sequence.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    /// Not in an escape sequence.
+    Ground,
+    /// After `ESC`.
+    Escape,
+    /// After `ESC [`, reading the parameters.
+    Csi,
+}
+
+/// A text console drawn on a framebuffer. When it's double buffered, drawing only shows up on the
+/// screen after [`Self::present`], which the [`fmt::Write`] impl calls after every write.
 pub struct ConsoleGraphics<'a> {
-    font: &'a PsfFile<'a>,
+    font: FontSource<'a>,
     framebuffer: FrameBuffer,
-    table: HashMap<char, u32>,
+    /// The screen in RAM, which drawing goes to instead of the framebuffer when it's present.
+    back_buffer: Option<Box<[u8]>>,
+    /// The pixel rows of `back_buffer` that changed since the last `present`.
+    dirty_rows: ops::Range<usize>,
+    /// The glyph indices of the font's characters and character sequences, like a letter followed
+    /// by combining marks.
+    table: CharTable,
+    /// The glyph indices of the ASCII characters, so that they skip `table`.
+    ascii_table: [Option<u32>; 128],
+    /// The glyph index drawn for characters the font doesn't have.
+    fallback: Option<u32>,
     cursor: Point,
+    /// The cells of every line in the scrollback and on the screen.
+    lines: VecDeque<Vec<Cell>>,
+    /// Index in `lines` of the line at the top of the screen.
+    top: usize,
+    /// Maximum number of lines kept above the screen.
+    scrollback: usize,
+    /// How many lines the view is scrolled up into the scrollback.
+    view_offset: usize,
+    /// Columns between tab stops.
+    tab_width: usize,
+    /// How many pixels wide and high every glyph pixel is drawn.
+    scale: usize,
+    fg: Color,
+    bg: Color,
+    escape: EscapeState,
+    /// The parameters of the escape sequence being parsed.
+    escape_params: ArrayVec<u16, MAX_ESCAPE_PARAMS>,
 }
 
 impl<'a> ConsoleGraphics<'a> {
-    fn new(font: &'a PsfFile<'a>, framebuffer: FrameBuffer) -> Self {
-        let mut table = HashMap::new();
-        for entry in font.unicode_table_entries() {
-            match entry.value {
-                psf::UnicodeTableEntryValue::Utf8(s) => {
-                    for ch in s.chars() {
-                        table.insert(ch, entry.index);
-                    }
-                }
-                psf::UnicodeTableEntryValue::Ucs2(s) => {
-                    for ch in s.chars() {
-                        table.insert(ch, entry.index);
-                    }
-                }
-            }
-        }
-        Self {
+    fn new(font: FontSource<'a>, framebuffer: FrameBuffer, double_buffered: bool) -> Self {
+        let table = match font {
+            FontSource::Psf(font) => font.build_char_table(),
+            FontSource::Builtin => CharTable::default(),
+        };
+        let ascii_table = array::from_fn(|i| table.get(i as u8 as char));
+        let fallback = table.get('\u{FFFD}').or_else(|| table.get('?'));
+        let info = framebuffer.info();
+        let back_buffer = double_buffered
+            .then(|| vec![0; info.bytes_per_pixel * info.stride * info.height].into_boxed_slice());
+        let mut console = Self {
             font,
             framebuffer,
+            back_buffer,
+            dirty_rows: 0..0,
             table,
+            ascii_table,
+            fallback,
             cursor: Point::new(0, 0),
+            lines: VecDeque::new(),
+            top: 0,
+            scrollback: 0,
+            view_offset: 0,
+            tab_width: DEFAULT_TAB_WIDTH,
+            scale: 1,
+            fg: DEFAULT_FG,
+            bg: DEFAULT_BG,
+            escape: EscapeState::Ground,
+            escape_params: ArrayVec::new(),
+        };
+        console.set_scrollback(DEFAULT_SCROLLBACK_SCREENS);
+        console
+    }
+
+    fn get_glyph(&self, ch: char) -> Option<Glyph<'a>> {
+        match self.font {
+            FontSource::Psf(font) => {
+                let index = match ch.is_ascii() {
+                    true => self.ascii_table[ch as usize]?,
+                    false => self.table.get(ch)?,
+                };
+                font.get_glyph(index)
+            }
+            FontSource::Builtin => BuiltinFont.get_glyph(ch),
+        }
+    }
+
+    /// The glyph drawn for characters the font doesn't have: `U+FFFD`, or `?` if the font doesn't
+    /// have that either.
+    fn fallback_glyph(&self) -> Option<Glyph<'a>> {
+        match self.font {
+            FontSource::Psf(font) => font.get_glyph(self.fallback?),
+            FontSource::Builtin => {
+                (BuiltinFont.get_glyph('\u{FFFD}')).or_else(|| BuiltinFont.get_glyph('?'))
+            }
+        }
+    }
+
+    /// Width of a character cell in pixels, the scaled glyph width.
+    fn cell_width(&self) -> usize {
+        self.font.glyph_width() as usize * self.scale
+    }
+
+    /// Height of a character cell in pixels, the scaled glyph height.
+    fn cell_height(&self) -> usize {
+        self.font.glyph_height() as usize * self.scale
+    }
+
+    /// Draws every glyph pixel as a `scale`x`scale` block, and clears the screen. Returns `false`,
+    /// keeping the current scale, if a scaled glyph doesn't fit in the framebuffer.
+    pub fn set_scale(&mut self, scale: usize) -> bool {
+        let info = self.framebuffer.info();
+        let fits = 0 < scale
+            && (self.font.glyph_width() as usize).saturating_mul(scale) <= info.width
+            && (self.font.glyph_height() as usize).saturating_mul(scale) <= info.height;
+        if fits {
+            self.scale = scale;
+            self.clear();
+        }
+        fits
+    }
+
+    /// Number of text rows on the screen.
+    fn rows(&self) -> usize {
+        (self.framebuffer.info().height - 1) / self.cell_height()
+    }
+
+    /// Number of text columns on the screen.
+    fn columns(&self) -> usize {
+        (self.framebuffer.info().width - 1) / self.cell_width()
+    }
+
+    /// Moves the cursor to (`col`, `row`) in characters, clamped to the screen.
+    pub fn set_cursor(&mut self, col: usize, row: usize) {
+        let col = col.min(self.columns().saturating_sub(1));
+        let row = row.min(self.rows().saturating_sub(1));
+        self.cursor = Point::new(col * self.cell_width(), row * self.cell_height());
+    }
+
+    /// The line of the scrollback the cursor is on.
+    fn cursor_line(&mut self) -> &mut Vec<Cell> {
+        let idx = self.top + self.cursor.y / self.cell_height();
+        while self.lines.len() <= idx {
+            self.lines.push_back(Vec::new());
+        }
+        &mut self.lines[idx]
+    }
+
+    fn cursor_col(&self) -> usize {
+        self.cursor.x / self.cell_width()
+    }
+
+    /// Drops the lines that are more than `scrollback` lines above the screen.
+    fn trim_scrollback(&mut self) {
+        let excess = self.top.saturating_sub(self.scrollback);
+        self.lines.drain(..excess);
+        self.top -= excess;
+    }
+
+    /// Sets how many screens of lines are kept above the screen for scrolling back.
+    pub fn set_scrollback(&mut self, screens: usize) {
+        self.set_scrollback_lines(screens.saturating_mul(self.rows()));
+    }
+
+    /// Sets how many lines are kept above the screen for scrolling back.
+    pub fn set_scrollback_lines(&mut self, lines: usize) {
+        self.scrollback = lines;
+        self.trim_scrollback();
+        if self.top < self.view_offset {
+            self.view_offset = self.top;
+            self.render_view();
+        }
+    }
+
+    /// Shows the screen `lines_up` lines up in the scrollback, clamped to the oldest line. Printing
+    /// returns the view to the bottom.
+    pub fn scroll_view(&mut self, lines_up: usize) {
+        let lines_up = lines_up.min(self.top);
+        if lines_up != self.view_offset {
+            self.view_offset = lines_up;
+            self.render_view();
+        }
+    }
+
+    /// Sets the columns between tab stops, at least 1.
+    pub fn set_tab_width(&mut self, tab_width: usize) {
+        self.tab_width = tab_width.max(1);
+    }
+
+    /// Sets the color of the text drawn from now on, until an SGR escape sequence changes it.
+    pub fn set_fg(&mut self, (r, g, b): (u8, u8, u8)) {
+        self.fg = Color::new(r, g, b);
+    }
+
+    /// Sets the color behind the text drawn from now on, until an SGR escape sequence changes it.
+    pub fn set_bg(&mut self, (r, g, b): (u8, u8, u8)) {
+        self.bg = Color::new(r, g, b);
+    }
+
+    /// Scrolls the view `n` lines further up into the scrollback.
+    pub fn scroll_view_up(&mut self, n: usize) {
+        self.scroll_view(self.view_offset.saturating_add(n));
+    }
+
+    /// Scrolls the view `n` lines back down towards the live screen.
+    pub fn scroll_view_down(&mut self, n: usize) {
+        self.scroll_view(self.view_offset.saturating_sub(n));
+    }
+
+    /// Redraws the screen from the lines `view_offset` lines up in the scrollback, each cell in the
+    /// colors it was drawn in.
+    fn render_view(&mut self) {
+        let info = self.framebuffer.info();
+        self.fill_rect(0, 0, info.width, info.height, 0);
+        let (cell_width, cell_height) = (self.cell_width(), self.cell_height());
+        let colors = (self.fg, self.bg);
+        let first = self.top - self.view_offset;
+        for row in 0..self.rows() {
+            let Some(len) = self.lines.get(first + row).map(Vec::len) else {
+                break;
+            };
+            for col in 0..len {
+                let Cell { ch, fg, bg } = self.lines[first + row][col];
+                (self.fg, self.bg) = (fg, bg);
+                self.draw_glyph_at(ch, col * cell_width, row * cell_height);
+            }
+        }
+        (self.fg, self.bg) = colors;
+    }
+
+    /// Returns the view to the live screen.
+    pub fn scroll_to_bottom(&mut self) {
+        self.scroll_view(0);
+    }
+
+    /// The buffer drawing goes to, marking `rows` (in pixels, clipped to the screen) dirty: the
+    /// back buffer if there is one, or the framebuffer.
+    fn draw_buffer(&mut self, rows: ops::Range<usize>) -> &mut [u8] {
+        let height = self.framebuffer.info().height;
+        let rows = rows.start.min(height)..rows.end.min(height);
+        match &mut self.back_buffer {
+            Some(back_buffer) => {
+                self.dirty_rows = match (self.dirty_rows.is_empty(), rows.is_empty()) {
+                    (_, true) => self.dirty_rows.clone(),
+                    (true, false) => rows,
+                    (false, false) => {
+                        self.dirty_rows.start.min(rows.start)..self.dirty_rows.end.max(rows.end)
+                    }
+                };
+                back_buffer
+            }
+            None => self.framebuffer.buffer_mut(),
         }
     }
 
+    /// Copies the rows of the back buffer that changed to the framebuffer. Does nothing if the
+    /// console isn't double buffered.
+    pub fn present(&mut self) {
+        let Some(back_buffer) = &self.back_buffer else {
+            return;
+        };
+        let info = self.framebuffer.info();
+        let row_len = info.bytes_per_pixel * info.stride;
+        let bytes = row_len * self.dirty_rows.start..row_len * self.dirty_rows.end;
+        self.framebuffer.buffer_mut()[bytes.clone()].copy_from_slice(&back_buffer[bytes]);
+        self.dirty_rows = 0..0;
+    }
+
     pub fn clear(&mut self) {
-        let buf = self.framebuffer.buffer_mut();
+        let height = self.framebuffer.info().height;
+        let buf = self.draw_buffer(0..height);
         buf.fill(0);
         self.cursor = Point::new(0, 0);
+        // The old screen's lines move into the scrollback.
+        self.top = self.lines.len();
+        self.view_offset = 0;
+        self.trim_scrollback();
+    }
+
+    /// Fills every byte of the pixels in the rectangle with `value`. The rectangle is clipped to
+    /// the framebuffer.
+    fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, value: u8) {
+        let info = self.framebuffer.info();
+        let (x, y) = (x.min(info.width), y.min(info.height));
+        let width = width.min(info.width.saturating_sub(x));
+        let height = height.min(info.height.saturating_sub(y));
+        if width == 0 {
+            return;
+        }
+        let buf = self.draw_buffer(y..y + height);
+
+        for y in y..y + height {
+            let start = info.bytes_per_pixel * (info.stride * y + x);
+            buf[start..start + info.bytes_per_pixel * width].fill(value);
+        }
+    }
+
+    /// Blanks the line the cursor is on, and moves the cursor to its start.
+    pub fn clear_line(&mut self) {
+        self.scroll_to_bottom();
+        self.cursor_line().clear();
+        let width = self.framebuffer.info().width;
+        let cell_height = self.cell_height();
+        self.fill_rect(0, self.cursor.y, width, cell_height, 0);
+        self.cursor.x = 0;
+    }
+
+    /// Blanks `n` characters starting at the cursor, without moving it.
+    pub fn erase_chars(&mut self, n: usize) {
+        self.scroll_to_bottom();
+        let col = self.cursor_col();
+        let line = self.cursor_line();
+        let end = line.len().min(col.saturating_add(n));
+        if let Some(cells) = line.get_mut(col..end) {
+            cells.fill(Cell::BLANK);
+        }
+        let cell_width = self.cell_width();
+        let cell_height = self.cell_height();
+        let (x, y) = (self.cursor.x, self.cursor.y);
+        self.fill_rect(x, y, n.saturating_mul(cell_width), cell_height, 0);
     }
 
     pub fn move_right(&mut self, n: usize) {
-        self.cursor.x += n * self.font.glyph_width() as usize;
-        if self.framebuffer.info().width <= self.cursor.x + self.font.glyph_width() as usize {
+        self.cursor.x += n * self.cell_width();
+        if self.framebuffer.info().width <= self.cursor.x + self.cell_width() {
             self.cursor.x = 0;
             self.move_down();
         }
     }
 
     pub fn move_down(&mut self) {
-        self.cursor.y += self.font.glyph_height() as usize;
-        if self.framebuffer.info().height <= self.cursor.y + self.font.glyph_height() as usize {
+        self.cursor.y += self.cell_height();
+        if self.framebuffer.info().height <= self.cursor.y + self.cell_height() {
             self.scrollup(1);
         }
     }
 
     pub fn scrollup(&mut self, lines: usize) {
         let info = self.framebuffer.info();
-        let buf = self.framebuffer.buffer_mut();
-        let buf_len = buf.len();
-        let y_offset = info.height.min(self.font.glyph_height() as usize * lines);
+        // The buffer may be longer than the screen, so only the screen's rows are moved.
+        let screen_len = info.bytes_per_pixel * info.stride * info.height;
+        let y_offset = info.height.min(self.cell_height() * lines);
         let offset = info.bytes_per_pixel * info.stride * y_offset;
+        let buf = &mut self.draw_buffer(0..info.height)[..screen_len];
 
         buf.copy_within(offset.., 0);
-        buf[buf_len - offset..].fill(0);
+        buf[screen_len - offset..].fill(0);
         self.cursor.y = self.cursor.y.saturating_sub(y_offset);
+
+        self.top += y_offset / self.cell_height();
+        self.trim_scrollback();
+    }
+
+    /// Draws `ch` with its top left corner at (`x`, `y`) in pixels, falling back to a replacement
+    /// glyph if the font doesn't have it. The cursor doesn't move, and the screen doesn't scroll.
+    /// Returns whether the font has `ch`.
+    pub fn draw_glyph_at(&mut self, ch: char, x: usize, y: usize) -> bool {
+        let glyph = self.get_glyph(ch);
+        let found = glyph.is_some();
+        if let Some(glyph) = glyph.or_else(|| self.fallback_glyph()) {
+            self.blit_glyph(glyph, x, y);
+        }
+        found
+    }
+
+    /// Draws `glyph` with its top left corner at (`x`, `y`) in the current colors, clipped to the
+    /// framebuffer.
+    fn blit_glyph(&mut self, glyph: Glyph, x: usize, y: usize) {
+        let info = self.framebuffer.info();
+        let (Some(fg), Some(bg)) = (
+            self.fg.to_pixel(info.pixel_format),
+            self.bg.to_pixel(info.pixel_format),
+        ) else {
+            // The logger prints to the console, which is locked, so this only goes to serial.
+            if !UNSUPPORTED_FORMAT_LOGGED.swap(true, Ordering::Relaxed) {
+                crate::sprintln!(
+                    "ERROR: Can't draw in the {:?} pixel format",
+                    info.pixel_format
+                );
+            }
+            return;
+        };
+        let pixel_len = info.bytes_per_pixel.min(fg.len());
+        let scale = self.scale;
+        if info.width <= x {
+            return;
+        }
+        let height = (glyph.rows().len() * scale).min(info.height.saturating_sub(y));
+        let buf = self.draw_buffer(y..y + height);
+
+        for (y, row) in (y..info.height).step_by(scale).zip(glyph.rows()) {
+            for (x, pixel) in (x..info.width).step_by(scale).zip(row) {
+                let color = match pixel {
+                    true => &fg,
+                    false => &bg,
+                };
+                for y in y..(y + scale).min(info.height) {
+                    for x in x..(x + scale).min(info.width) {
+                        let idx = info.bytes_per_pixel * (info.stride * y + x);
+                        let pixel_buf = &mut buf[idx..idx + info.bytes_per_pixel];
+                        pixel_buf[..pixel_len].copy_from_slice(&color[..pixel_len]);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Feeds `ch` to the escape sequence parser. Returns whether `ch` is part of an escape
+    /// sequence, in which case it isn't printed.
+    fn parse_escape(&mut self, ch: char) -> bool {
+        match self.escape {
+            EscapeState::Ground => {
+                if ch != '\x1b' {
+                    return false;
+                }
+                self.escape = EscapeState::Escape;
+            }
+            EscapeState::Escape => {
+                self.escape = match ch {
+                    '[' => EscapeState::Csi,
+                    _ => EscapeState::Ground,
+                };
+                self.escape_params.clear();
+            }
+            EscapeState::Csi => match ch {
+                '0'..='9' => {
+                    if self.escape_params.is_empty() {
+                        let _ = self.escape_params.push(0);
+                    }
+                    let param = self.escape_params.last_mut().unwrap();
+                    *param = param
+                        .saturating_mul(10)
+                        .saturating_add(ch as u16 - b'0' as u16);
+                }
+                ';' => {
+                    if self.escape_params.is_empty() {
+                        let _ = self.escape_params.push(0);
+                    }
+                    let _ = self.escape_params.push(0);
+                }
+                '\x40'..='\x7e' => {
+                    self.escape = EscapeState::Ground;
+                    self.control_sequence(ch);
+                }
+                _ => self.escape = EscapeState::Ground,
+            },
+        }
+        true
+    }
+
+    /// Runs the control sequence (`ESC [ params final`) ending with `final_ch`. Unsupported
+    /// sequences are ignored.
+    fn control_sequence(&mut self, final_ch: char) {
+        // Parameters of 0 mean the default, like missing ones.
+        let param = |i: usize, default: usize| match self.escape_params.get(i) {
+            Some(&param) if param != 0 => param as usize,
+            _ => default,
+        };
+        let (n, mode) = (
+            param(0, 1),
+            self.escape_params.first().copied().unwrap_or(0),
+        );
+        let col = self.cursor_col();
+        let row = self.cursor.y / self.cell_height();
+        match final_ch {
+            'm' => self.select_graphic_rendition(),
+            'A' => self.set_cursor(col, row.saturating_sub(n)),
+            'B' => self.set_cursor(col, row.saturating_add(n)),
+            'C' => self.set_cursor(col.saturating_add(n), row),
+            'D' => self.set_cursor(col.saturating_sub(n), row),
+            'H' | 'f' => self.set_cursor(param(1, 1) - 1, param(0, 1) - 1),
+            'J' => self.erase_in_display(mode),
+            'K' => self.erase_in_line(mode),
+            _ => {}
+        }
+    }
+
+    /// `ESC [ mode J`: erases from the cursor to the end of the screen (0), from the start of the
+    /// screen to the cursor (1), or the whole screen, moving the cursor to the top left (2).
+    fn erase_in_display(&mut self, mode: u16) {
+        self.scroll_to_bottom();
+        let width = self.framebuffer.info().width;
+        let cell_height = self.cell_height();
+        let row = self.cursor.y / cell_height;
+        let rows = match mode {
+            0 => row + 1..self.rows(),
+            1 => 0..row,
+            2 => return self.clear(),
+            _ => return,
+        };
+        self.erase_in_line(mode);
+        for line in self
+            .lines
+            .range_mut(self.top..)
+            .skip(rows.start)
+            .take(rows.len())
+        {
+            line.clear();
+        }
+        self.fill_rect(
+            0,
+            rows.start * cell_height,
+            width,
+            rows.len() * cell_height,
+            0,
+        );
+    }
+
+    /// `ESC [ mode K`: erases from the cursor to the end of the line (0), from the start of the
+    /// line to the cursor (1), or the whole line (2). The cursor doesn't move.
+    fn erase_in_line(&mut self, mode: u16) {
+        let (col, x) = (self.cursor_col(), self.cursor.x);
+        match mode {
+            0 => self.erase_chars(self.columns().saturating_sub(col)),
+            1 => {
+                self.cursor.x = 0;
+                self.erase_chars(col + 1);
+            }
+            2 => self.clear_line(),
+            _ => {}
+        }
+        self.cursor.x = x;
+    }
+
+    /// Applies the SGR parameters: reset, and the basic and bright foreground and background
+    /// colors.
+    fn select_graphic_rendition(&mut self) {
+        let params = self.escape_params.clone();
+        let params = match params.is_empty() {
+            true => &[0][..],
+            false => &params[..],
+        };
+        for &param in params {
+            match param {
+                0 => (self.fg, self.bg) = (DEFAULT_FG, DEFAULT_BG),
+                30..=37 => self.fg = Color::ANSI[param as usize - 30],
+                39 => self.fg = DEFAULT_FG,
+                40..=47 => self.bg = Color::ANSI[param as usize - 40],
+                49 => self.bg = DEFAULT_BG,
+                90..=97 => self.fg = Color::ANSI_BRIGHT[param as usize - 90],
+                100..=107 => self.bg = Color::ANSI_BRIGHT[param as usize - 100],
+                _ => {}
+            }
+        }
     }
 
     pub fn putchar(&mut self, ch: char) -> bool {
+        if self.parse_escape(ch) {
+            return true;
+        }
+        self.scroll_to_bottom();
+
         let mut status = true;
         if ch == '\r' {
             self.cursor.x = 0;
@@ -106,49 +763,87 @@ impl<'a> ConsoleGraphics<'a> {
             self.cursor.x = 0;
             self.move_down();
             return status;
+        } else if ch == '\x08' {
+            // Backspace only moves the cursor, like on a terminal.
+            self.cursor.x = (self.cursor.x).saturating_sub(self.cell_width());
+            return status;
         } else if ch == '\t' {
-            self.move_right(4);
+            // Tab stops are every `tab_width` columns, and a tab past the last one wraps.
+            let col = self.cursor_col();
+            self.move_right(self.tab_width - col % self.tab_width);
             return status;
         }
 
-        let glyph_id = self.table.get(&ch);
-        status &= glyph_id.is_some();
-        if let Some(&glyph_id) = glyph_id
-            .or_else(|| self.table.get(&'\u{FFFD}'))
-            .or_else(|| self.table.get(&'?'))
-        {
-            let glyph = self.font.get_glyph(glyph_id).unwrap();
-
-            let info = self.framebuffer.info();
-            let buf = self.framebuffer.buffer_mut();
-
-            for (y, row) in (self.cursor.y..).zip(glyph.rows()) {
-                for (x, pixel) in (self.cursor.x..).zip(row) {
-                    let idx = info.bytes_per_pixel * (info.stride * y + x);
-                    let pixel_buf = &mut buf[idx..idx + info.bytes_per_pixel];
-                    match pixel {
-                        true => pixel_buf.fill(255),
-                        false => pixel_buf.fill(0),
-                    }
-                }
-            }
+        status &= self.draw_glyph_at(ch, self.cursor.x, self.cursor.y);
+        self.advance_cell(ch);
+
+        status
+    }
+
+    /// Records `ch` in the current colors as the cell under the cursor, and moves to the next cell.
+    fn advance_cell(&mut self, ch: char) {
+        let col = self.cursor_col();
+        let (fg, bg) = (self.fg, self.bg);
+        let line = self.cursor_line();
+        if line.len() <= col {
+            line.resize(col + 1, Cell::BLANK);
         }
+        line[col] = Cell { ch, fg, bg };
 
         self.move_right(1);
+    }
 
-        status
+    /// The longest character sequence at the start of `s` that the font has a glyph for, as its
+    /// length in bytes and the glyph's index.
+    fn match_sequence(&self, s: &str) -> Option<(usize, u32)> {
+        if self.table.longest_sequence() == 0 || self.escape != EscapeState::Ground {
+            return None;
+        }
+        (s.char_indices())
+            .map(|(i, ch)| i + ch.len_utf8())
+            .take(self.table.longest_sequence())
+            .skip(1)
+            .filter_map(|end| Some((end, self.table.get_sequence(&s[..end])?)))
+            .last()
+    }
+
+    /// Draws the glyph at `index` for the character sequence `seq`, taking up a single cell. Only
+    /// the first character is kept in the scrollback.
+    fn put_sequence(&mut self, seq: &str, index: u32) {
+        self.scroll_to_bottom();
+        let glyph = match self.font {
+            FontSource::Psf(font) => font.get_glyph(index),
+            FontSource::Builtin => None,
+        };
+        if let Some(glyph) = glyph.or_else(|| self.fallback_glyph()) {
+            self.blit_glyph(glyph, self.cursor.x, self.cursor.y);
+        }
+        self.advance_cell(seq.chars().next().unwrap_or(' '));
     }
 }
 
 impl fmt::Write for ConsoleGraphics<'_> {
     fn write_char(&mut self, ch: char) -> fmt::Result {
         self.putchar(ch);
+        self.present();
         Ok(())
     }
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        for ch in s.chars() {
-            self.write_char(ch)?;
+    /// Sequences of characters the font has a single glyph for are only matched within `s`.
+    fn write_str(&mut self, mut s: &str) -> fmt::Result {
+        while let Some(ch) = s.chars().next() {
+            let len = match self.match_sequence(s) {
+                Some((len, index)) => {
+                    self.put_sequence(&s[..len], index);
+                    len
+                }
+                None => {
+                    self.putchar(ch);
+                    ch.len_utf8()
+                }
+            };
+            s = &s[len..];
         }
+        self.present();
         Ok(())
     }
 }
@@ -166,22 +861,31 @@ impl fmt::Display for Error {
     }
 }
 
-/// Prints to the serial port. Don't use directly, use `sprint!()` instead.
+/// Prints to the console, or to the serial port when there's no console (e.g. no framebuffer).
+/// Don't use directly, use `cprint!()` instead.
 #[doc(hidden)]
 pub fn _cprint(args: core::fmt::Arguments) -> Result<(), Error> {
     without_interrupts(|| {
-        fmt::write(CONSOLE.lock().as_mut().ok_or(Error::Uninitialized)?, args).unwrap();
+        match CONSOLE.lock().as_mut() {
+            Some(console) => fmt::write(console, args).unwrap(),
+            None => super::serial::_sprint(args),
+        }
         Ok(())
     })
 }
-/// Prints to the serial port. Don't use directly, use `sprintln!()` instead.
+/// Prints to the console, or to the serial port when there's no console (e.g. no framebuffer).
+/// Don't use directly, use `cprintln!()` instead.
 #[doc(hidden)]
 pub fn _cprintln(args: core::fmt::Arguments) -> Result<(), Error> {
     without_interrupts(|| {
-        let mut binding = CONSOLE.lock();
-        let console = binding.as_mut().ok_or(Error::Uninitialized)?;
-        fmt::write(console, args).unwrap();
-        console.putchar('\n');
+        match CONSOLE.lock().as_mut() {
+            Some(console) => {
+                fmt::write(console, args).unwrap();
+                console.putchar('\n');
+                console.present();
+            }
+            None => super::serial::_sprintln(args),
+        }
         Ok(())
     })
 }
@@ -190,7 +894,7 @@ pub fn _cprintln(args: core::fmt::Arguments) -> Result<(), Error> {
 #[macro_export]
 macro_rules! cprint {
     ($($arg:tt)*) => {{
-        $crate::console::_cprint(format_args!($($arg)*)).expect("Printing to console failed");
+        $crate::output::console::_cprint(format_args!($($arg)*)).expect("Printing to console failed");
     }};
 }
 
@@ -204,3 +908,391 @@ macro_rules! cprintln {
         $crate::output::console::_cprintln(format_args!($($arg)*)).expect("Printing to console failed");
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use bootloader_api::info::FrameBufferInfo;
+    use core::fmt::Write;
+
+    const GLYPH_WIDTH: usize = BuiltinFont.glyph_width() as _;
+
+    /// A console with `font`, `columns`x`rows` characters on a framebuffer of 4 byte pixels.
+    fn console_with(
+        font: FontSource<'static>,
+        columns: usize,
+        rows: usize,
+        pixel_format: PixelFormat,
+        double_buffered: bool,
+    ) -> ConsoleGraphics<'static> {
+        let (glyph_width, glyph_height) =
+            (font.glyph_width() as usize, font.glyph_height() as usize);
+        let (width, height) = (glyph_width * columns + 1, glyph_height * rows + 1);
+        let info = FrameBufferInfo {
+            byte_len: 4 * width * height,
+            width,
+            height,
+            pixel_format,
+            bytes_per_pixel: 4,
+            stride: width,
+        };
+        let buffer = Box::leak(vec![0u8; info.byte_len].into_boxed_slice());
+        let framebuffer = unsafe { FrameBuffer::new(buffer.as_mut_ptr() as _, info) };
+        ConsoleGraphics::new(font, framebuffer, double_buffered)
+    }
+
+    /// A console with the builtin font on an RGB framebuffer.
+    fn console(columns: usize, rows: usize) -> ConsoleGraphics<'static> {
+        console_with(FontSource::Builtin, columns, rows, PixelFormat::Rgb, false)
+    }
+
+    /// The font the kernel boots with.
+    fn psf_font() -> &'static PsfFile<'static> {
+        let font = PsfFile::parse(include_bytes!("../../LatKaCyrHeb-14.psfu")).unwrap();
+        Box::leak(Box::new(font))
+    }
+
+    /// The color of the framebuffer's pixel at (`x`, `y`).
+    fn pixel(console: &ConsoleGraphics, x: usize, y: usize) -> Color {
+        let info = console.framebuffer.info();
+        let idx = info.bytes_per_pixel * (info.stride * y + x);
+        let [r, g, b] = console.framebuffer.buffer()[idx..idx + 3]
+            .try_into()
+            .unwrap();
+        Color::new(r, g, b)
+    }
+
+    /// A pixel of `ch`'s glyph that's set, and one that isn't.
+    fn glyph_pixels(ch: char) -> ((usize, usize), (usize, usize)) {
+        let glyph = BuiltinFont.get_glyph(ch).unwrap();
+        let find = |value| {
+            (glyph.rows().enumerate())
+                .find_map(|(y, mut row)| Some((row.position(|pixel| pixel == value)?, y)))
+                .unwrap()
+        };
+        (find(true), find(false))
+    }
+
+    /// The characters of the console's lines.
+    fn text(console: &ConsoleGraphics) -> Vec<String> {
+        (console.lines.iter())
+            .map(|line| line.iter().map(|cell| cell.ch).collect())
+            .collect()
+    }
+
+    #[test]
+    fn sgr_sets_and_resets_colors() {
+        let mut console = console(10, 3);
+        write!(console, "\x1b[31;44m").unwrap();
+        assert_eq!((console.fg, console.bg), (Color::ANSI[1], Color::ANSI[4]));
+        write!(console, "\x1b[92m").unwrap();
+        assert_eq!(console.fg, Color::ANSI_BRIGHT[2]);
+        write!(console, "\x1b[39m").unwrap();
+        assert_eq!((console.fg, console.bg), (DEFAULT_FG, Color::ANSI[4]));
+        write!(console, "\x1b[5m").unwrap();
+        assert_eq!((console.fg, console.bg), (DEFAULT_FG, Color::ANSI[4]));
+        write!(console, "\x1b[m").unwrap();
+        assert_eq!((console.fg, console.bg), (DEFAULT_FG, DEFAULT_BG));
+
+        // A sequence split across writes, which prints nothing.
+        write!(console, "\x1b[1").unwrap();
+        write!(console, "03;0;36m").unwrap();
+        assert_eq!((console.fg, console.bg), (Color::ANSI[6], DEFAULT_BG));
+        assert_eq!(console.escape, EscapeState::Ground);
+        assert_eq!(console.cursor, Point::new(0, 0));
+    }
+
+    #[test]
+    fn scrolled_back_lines_keep_their_colors() {
+        let mut console = console(10, 3);
+        write!(console, "\x1b[32;41mA\x1b[0mB\n1\n2\n3\n").unwrap();
+        assert_eq!(console.top, 2);
+        console.scroll_view_up(2);
+
+        let (set, unset) = glyph_pixels('A');
+        assert_eq!(pixel(&console, set.0, set.1), Color::ANSI[2]);
+        assert_eq!(pixel(&console, unset.0, unset.1), Color::ANSI[1]);
+        let (set, _) = glyph_pixels('B');
+        assert_eq!(pixel(&console, GLYPH_WIDTH + set.0, set.1), DEFAULT_FG);
+        // Redrawing doesn't change the current colors.
+        assert_eq!((console.fg, console.bg), (DEFAULT_FG, DEFAULT_BG));
+        assert_eq!(text(&console)[0], "AB");
+    }
+
+    #[test]
+    fn colors_are_packed_in_the_pixel_format() {
+        let color = Color::new(0x12, 0x34, 0x56);
+        assert_eq!(
+            color.to_pixel(PixelFormat::Rgb),
+            Some([0x12, 0x34, 0x56, 0])
+        );
+        assert_eq!(
+            color.to_pixel(PixelFormat::Bgr),
+            Some([0x56, 0x34, 0x12, 0])
+        );
+        assert_eq!(Color::WHITE.to_pixel(PixelFormat::U8), Some([255, 0, 0, 0]));
+        let unknown = PixelFormat::Unknown {
+            red_position: 16,
+            green_position: 8,
+            blue_position: 0,
+        };
+        assert_eq!(color.to_pixel(unknown), Some([0x56, 0x34, 0x12, 0]));
+        let out_of_range = PixelFormat::Unknown {
+            red_position: 24,
+            green_position: 32,
+            blue_position: 0,
+        };
+        assert_eq!(color.to_pixel(out_of_range), Some([0x56, 0, 0, 0x12]));
+    }
+
+    #[test]
+    fn glyphs_are_drawn_in_the_pixel_format() {
+        let mut console = console_with(FontSource::Builtin, 4, 2, PixelFormat::Bgr, false);
+        write!(console, "\x1b[31mA").unwrap();
+        let (set, _) = glyph_pixels('A');
+        // The bytes are read back as RGB, so the red and blue are swapped.
+        let Color { r: b, g, b: r } = pixel(&console, set.0, set.1);
+        assert_eq!(Color::new(r, g, b), Color::ANSI[1]);
+    }
+
+    #[test]
+    fn set_colors_are_drawn_in_the_pixel_format() {
+        let mut console = console_with(FontSource::Builtin, 4, 2, PixelFormat::Bgr, false);
+        console.set_fg((0x12, 0x34, 0x56));
+        console.set_bg((0xAB, 0xCD, 0xEF));
+        console.putchar('A');
+
+        let info = console.framebuffer.info();
+        let bytes = |(x, y): (usize, usize)| {
+            let idx = info.bytes_per_pixel * (info.stride * y + x);
+            console.framebuffer.buffer()[idx..idx + 3].to_vec()
+        };
+        let (set, unset) = glyph_pixels('A');
+        assert_eq!(bytes(set), [0x56, 0x34, 0x12]);
+        assert_eq!(bytes(unset), [0xEF, 0xCD, 0xAB]);
+    }
+
+    #[test]
+    fn scrolling_back_redraws_earlier_lines() {
+        let mut console = console(10, 3);
+        write!(console, "one\ntwo\nthree").unwrap();
+        let first_screen = console.framebuffer.buffer().to_vec();
+        write!(console, "\nfour\nfive").unwrap();
+        let live_screen = console.framebuffer.buffer().to_vec();
+        assert_eq!(text(&console), ["one", "two", "three", "four", "five"]);
+        assert_eq!(console.top, 2);
+
+        // Scrolling is clamped to the oldest line.
+        console.scroll_view_up(5);
+        assert_eq!(console.view_offset, 2);
+        assert!(console.framebuffer.buffer() == first_screen);
+        console.scroll_view_down(1);
+        console.scroll_to_bottom();
+        assert!(console.framebuffer.buffer() == live_screen);
+
+        // The scrollback is bounded.
+        console.set_scrollback_lines(1);
+        assert_eq!(text(&console), ["two", "three", "four", "five"]);
+        console.scroll_view_up(5);
+        assert_eq!(console.view_offset, 1);
+    }
+
+    #[test]
+    fn ascii_skips_the_char_table() {
+        let font = psf_font();
+        let mut console = console_with(FontSource::Psf(font), 20, 2, PixelFormat::Rgb, false);
+        for ch in (0..128u8).map(char::from) {
+            assert_eq!(
+                console.ascii_table[ch as usize],
+                console.table.get(ch),
+                "{ch:?}"
+            );
+        }
+        let fallback = console
+            .table
+            .get('\u{FFFD}')
+            .or_else(|| console.table.get('?'));
+        assert_eq!(console.fallback, fallback);
+        assert!(fallback.is_some());
+
+        // Without its table, the console still draws ASCII and the fallback the same way.
+        let mut tableless = console_with(FontSource::Psf(font), 20, 2, PixelFormat::Rgb, false);
+        tableless.table = CharTable::default();
+        for console in [&mut console, &mut tableless] {
+            assert!("Hello, world!".chars().all(|ch| console.putchar(ch)));
+            assert!(!console.putchar('\u{10FFFF}'));
+        }
+        assert!(console.framebuffer.buffer() == tableless.framebuffer.buffer());
+    }
+
+    #[test]
+    fn double_buffering_presents_dirty_rows() {
+        let mut console = console_with(FontSource::Builtin, 4, 2, PixelFormat::Rgb, true);
+        console.present();
+        let glyph_height = BuiltinFont.glyph_height() as usize;
+
+        console.putchar('A');
+        let back_buffer = console.back_buffer.clone().unwrap();
+        assert!(back_buffer.iter().any(|&byte| byte != 0));
+        assert!(console.framebuffer.buffer().iter().all(|&byte| byte == 0));
+        assert_eq!(console.dirty_rows, 0..glyph_height);
+
+        console.present();
+        assert!(console.framebuffer.buffer() == &*back_buffer);
+        assert!(console.dirty_rows.is_empty());
+
+        // Only the rows drawn to since are copied.
+        let last_row = console.framebuffer.buffer().len() - 4;
+        console.framebuffer.buffer_mut()[last_row] = 0xAB;
+        console.putchar('B');
+        console.present();
+        assert_eq!(console.framebuffer.buffer()[last_row], 0xAB);
+        assert!(
+            console.framebuffer.buffer()[..last_row]
+                == console.back_buffer.as_ref().unwrap()[..last_row]
+        );
+    }
+
+    #[test]
+    fn drawing_off_screen_is_clipped() {
+        let mut console = console_with(FontSource::Builtin, 4, 2, PixelFormat::Rgb, true);
+        console.present();
+        let info = console.framebuffer.info();
+        console.putchar('A');
+        let dirty_rows = console.dirty_rows.clone();
+
+        // Past the bottom and right edges, nothing is drawn or marked dirty.
+        console.draw_glyph_at('B', 0, info.height + 10);
+        console.draw_glyph_at('B', info.width + 10, dirty_rows.end);
+        console.fill_rect(info.width + 10, info.height + 10, 8, 8, 0xFF);
+        console.fill_rect(info.width + 10, info.height - 1, 8, 8, 0xFF);
+        console.fill_rect(0, info.height, 8, 8, 0xFF);
+        assert_eq!(console.dirty_rows, dirty_rows);
+
+        // Straddling the bottom edge, only the rows on the screen are dirty.
+        let glyph_height = BuiltinFont.glyph_height() as usize;
+        console.draw_glyph_at('B', 0, info.height - 1);
+        assert_eq!(console.dirty_rows, 0..info.height);
+        assert!(info.height - 1 + glyph_height > info.height);
+
+        console.present();
+        assert!(console.framebuffer.buffer() == &console.back_buffer.clone().unwrap()[..]);
+    }
+
+    /// The cursor's column and row.
+    fn cursor(console: &ConsoleGraphics) -> (usize, usize) {
+        (
+            console.cursor_col(),
+            console.cursor.y / console.cell_height(),
+        )
+    }
+
+    #[test]
+    fn tabs_move_to_the_next_tab_stop() {
+        let mut console = console(20, 4);
+        for (col, stop) in [(0, 8), (3, 8), (7, 8), (8, 16), (15, 16)] {
+            console.set_cursor(col, 0);
+            console.putchar('\t');
+            assert_eq!(cursor(&console), (stop, 0), "tab from column {col}");
+        }
+        // A tab past the last tab stop wraps.
+        console.set_cursor(16, 0);
+        console.putchar('\t');
+        assert_eq!(cursor(&console), (0, 1));
+
+        console.set_tab_width(4);
+        console.set_cursor(1, 0);
+        console.putchar('\t');
+        assert_eq!(cursor(&console), (4, 0));
+        console.putchar('\t');
+        assert_eq!(cursor(&console), (8, 0));
+
+        // The tab width is at least 1.
+        console.set_tab_width(0);
+        console.set_cursor(5, 0);
+        console.putchar('\t');
+        assert_eq!(cursor(&console), (6, 0));
+    }
+
+    #[test]
+    fn control_sequences_move_the_cursor_and_erase() {
+        let mut console = console(20, 4);
+        console.write_str("\x1b[3;5H").unwrap();
+        assert_eq!(cursor(&console), (4, 2));
+        console.write_str("\x1b[H").unwrap();
+        assert_eq!(cursor(&console), (0, 0));
+
+        // Moves are clamped to the screen, and a count of 0 moves by 1.
+        console.write_str("\x1b[2;2H\x1b[5A").unwrap();
+        assert_eq!(cursor(&console), (1, 0));
+        console.write_str("\x1b[99B").unwrap();
+        assert_eq!(cursor(&console), (1, 3));
+        console.write_str("\x1b[0C\x1b[C").unwrap();
+        assert_eq!(cursor(&console), (3, 3));
+        console.write_str("\x1b[99C").unwrap();
+        assert_eq!(cursor(&console), (19, 3));
+        console.write_str("\x1b[99D").unwrap();
+        assert_eq!(cursor(&console), (0, 3));
+
+        // K erases from the cursor to the end of the line without moving it.
+        console.write_str("\x1b[Habcdef\x1b[1;3H\x1b[K").unwrap();
+        assert_eq!(cursor(&console), (2, 0));
+        assert_eq!(text(&console)[0].trim_end(), "ab");
+        let (set, _) = glyph_pixels('c');
+        assert_eq!(pixel(&console, 2 * GLYPH_WIDTH + set.0, set.1), DEFAULT_BG);
+        let (set, _) = glyph_pixels('b');
+        assert_eq!(pixel(&console, GLYPH_WIDTH + set.0, set.1), DEFAULT_FG);
+
+        console.write_str("\x1b[2;4Hxyz\x1b[2J").unwrap();
+        assert_eq!(cursor(&console), (0, 0));
+        assert!(console.framebuffer.buffer().iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn scaled_glyphs_fill_blocks() {
+        let mut console = console(20, 4);
+        assert!(console.set_scale(2));
+        assert!(!console.set_scale(0));
+        assert!(!console.set_scale(100));
+        assert_eq!(console.scale, 2);
+        assert_eq!(console.columns(), 10);
+
+        console.putchar('A');
+        assert_eq!(console.cursor.x, 2 * GLYPH_WIDTH);
+        let (set, unset) = glyph_pixels('A');
+        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            assert_eq!(pixel(&console, 2 * set.0 + dx, 2 * set.1 + dy), DEFAULT_FG);
+            assert_eq!(
+                pixel(&console, 2 * unset.0 + dx, 2 * unset.1 + dy),
+                DEFAULT_BG
+            );
+        }
+
+        console.putchar('A');
+        assert_eq!(cursor(&console), (2, 0));
+        let x = 2 * GLYPH_WIDTH + 2 * set.0;
+        assert_eq!(pixel(&console, x + 1, 2 * set.1 + 1), DEFAULT_FG);
+    }
+
+    #[test]
+    fn sequences_take_a_single_cell() {
+        use crate::psf::tests::{psf2, PSF2_SEQUENCES};
+
+        let bytes = psf2(&[[0; 2], [0xFF; 2], [0x0F; 2], [0; 2]], PSF2_SEQUENCES);
+        let font = PsfFile::parse(Box::leak(bytes.into_boxed_slice())).unwrap();
+        let font = Box::leak(Box::new(font));
+        let mut console = console_with(FontSource::Psf(font), 10, 2, PixelFormat::Rgb, false);
+
+        assert_eq!(console.match_sequence("e\u{301}z"), Some((3, 1)));
+        assert_eq!(console.match_sequence("u\u{308}"), Some((3, 2)));
+        assert_eq!(console.match_sequence("ez"), None);
+
+        console.write_str("e\u{301}o\u{302}x").unwrap();
+        assert_eq!(text(&console), ["eox"]);
+        assert_eq!(cursor(&console), (3, 0));
+        assert_eq!(pixel(&console, 0, 0), DEFAULT_FG);
+        assert_eq!(pixel(&console, 8, 0), DEFAULT_BG);
+        assert_eq!(pixel(&console, 12, 1), DEFAULT_FG);
+    }
+}
@@ -1,12 +1,25 @@
-use core::fmt::{self, Write};
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
 
 pub mod console;
 pub mod serial;
 
 use console::CONSOLE;
-use serial::SERIAL1;
+use log::LevelFilter;
+use serial::{SERIAL1, SERIAL2};
 use x86_64::instructions::interrupts::without_interrupts;
 
+use crate::array_vec::ArrayVec;
+
+static MIRROR_TO_COM2: AtomicBool = AtomicBool::new(false);
+
+/// Whether `print!()` and the logger also print to COM2.
+pub fn set_mirror_to_com2(mirror: bool) {
+    MIRROR_TO_COM2.store(mirror, Ordering::Relaxed);
+}
+
 #[doc(hidden)]
 pub struct _MultiWriter;
 
@@ -14,6 +27,9 @@ impl Write for _MultiWriter {
     fn write_char(&mut self, c: char) -> fmt::Result {
         without_interrupts(|| {
             SERIAL1.lock().write_char(c)?;
+            if MIRROR_TO_COM2.load(Ordering::Relaxed) {
+                SERIAL2.lock().write_char(c)?;
+            }
             if let Some(console) = CONSOLE.lock().as_mut() {
                 console.write_char(c)?;
             }
@@ -23,6 +39,9 @@ impl Write for _MultiWriter {
     fn write_str(&mut self, s: &str) -> fmt::Result {
         without_interrupts(|| {
             SERIAL1.lock().write_str(s)?;
+            if MIRROR_TO_COM2.load(Ordering::Relaxed) {
+                SERIAL2.lock().write_str(s)?;
+            }
             if let Some(console) = CONSOLE.lock().as_mut() {
                 console.write_str(s)?;
             }
@@ -51,26 +70,143 @@ macro_rules! println {
     }};
 }
 
-/// `SerialLogger` implements `log::Log`, it logs to the serial port with the format: `"LEVEL: MSG"`
+/// `SerialLogger` implements `log::Log`, it logs to the serial port with the format:
+/// `"[SECS.MILLIS] LEVEL: MSG"`
 pub struct Logger {
     _private: (),
 }
 
 pub static LOGGER: Logger = Logger { _private: () };
 
-/// Force unlock the serial port and the console.
+/// The maximum level logged by modules without an override, as a `LevelFilter`.
+static LEVEL: AtomicUsize = AtomicUsize::new(LevelFilter::Info as _);
+/// Module path prefixes with their own maximum level.
+static MODULE_LEVELS: spin::RwLock<ArrayVec<(&'static str, LevelFilter), 8>> =
+    spin::RwLock::new(ArrayVec::new());
+
+fn level_from_usize(level: usize) -> LevelFilter {
+    LevelFilter::iter()
+        .find(|&filter| filter as usize == level)
+        .unwrap_or(LevelFilter::Trace)
+}
+
+/// Updates `log`'s max level, which skips the records no module would log.
+fn update_max_level() {
+    let module_max = (MODULE_LEVELS.read().as_slice().iter())
+        .map(|&(_, level)| level)
+        .max();
+    let level = level_from_usize(LEVEL.load(Ordering::Relaxed));
+    log::set_max_level(module_max.map_or(level, |module_max| module_max.max(level)));
+}
+
+/// Sets the maximum level logged by modules without an override.
+pub fn set_level(level: LevelFilter) {
+    LEVEL.store(level as _, Ordering::Relaxed);
+    update_max_level();
+}
+
+/// Sets the maximum level logged by the module `prefix` and its submodules, like
+/// `"kernel::memory::malloc"`. The longest matching prefix applies. Returns false if there are too
+/// many overrides.
+pub fn set_module_level(prefix: &'static str, level: LevelFilter) -> bool {
+    let set = without_interrupts(|| {
+        let mut levels = MODULE_LEVELS.write();
+        match levels.as_mut_slice().iter_mut().find(|(p, _)| *p == prefix) {
+            Some((_, old)) => {
+                *old = level;
+                true
+            }
+            None => levels.push((prefix, level)).is_ok(),
+        }
+    });
+    update_max_level();
+    set
+}
+
+/// Removes the override of the module `prefix`.
+pub fn clear_module_level(prefix: &str) {
+    without_interrupts(|| {
+        let mut levels = MODULE_LEVELS.write();
+        if let Some(i) = levels.as_slice().iter().position(|&(p, _)| p == prefix) {
+            levels.remove(i);
+        }
+    });
+    update_max_level();
+}
+
+/// The maximum level logged by the module `target`.
+fn level_for(target: &str) -> LevelFilter {
+    let is_in = |prefix: &str| {
+        (target.strip_prefix(prefix)).is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    };
+    (MODULE_LEVELS.read().as_slice().iter())
+        .filter(|&&(prefix, _)| is_in(prefix))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map_or_else(
+            || level_from_usize(LEVEL.load(Ordering::Relaxed)),
+            |&(_, level)| level,
+        )
+}
+
+/// Force unlock the serial ports and the console.
 pub unsafe fn force_unlock() {
     unsafe { serial::SERIAL1.force_unlock() };
+    unsafe { serial::SERIAL2.force_unlock() };
+    unsafe { console::CONSOLE.force_unlock() };
+}
+
+/// Prints a panic message. It goes to the serial port through [`serial::_emergency_print`], and to
+/// the console, if it's initialized, ignoring its lock. This doesn't depend on any output being
+/// initialized, so it works at any point of the boot.
+///
+/// # Safety
+/// Must only be called when nothing else will print, e.g. in the panic handler.
+pub unsafe fn panic_print(args: fmt::Arguments) {
+    serial::_emergency_print(args);
     unsafe { console::CONSOLE.force_unlock() };
+    if let Some(console) = CONSOLE.lock().as_mut() {
+        let _ = console.write_fmt(args);
+    }
+}
+
+/// When a record was logged.
+enum Timestamp {
+    /// Milliseconds since the clock started.
+    Uptime(u64),
+    /// APIC timer ticks, before the timer is calibrated.
+    Ticks(u64),
+}
+
+impl Timestamp {
+    /// Reads the clock, or the APIC timer ticks if there's no time source.
+    fn now() -> Self {
+        if crate::time::source().is_some() {
+            return Self::Uptime(crate::time::now_ms());
+        }
+        let ticks = crate::interrupts::timer_ticks();
+        match crate::interrupts::apic_timer_frequency() {
+            0 => Self::Ticks(ticks),
+            _ => Self::Uptime(ticks * crate::interrupts::APIC_TIMER_PERIOD_MS),
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Uptime(ms) => write!(f, "[{}.{:03}s]", ms / 1000, ms % 1000),
+            Self::Ticks(ticks) => write!(f, "[{ticks} ticks]"),
+        }
+    }
 }
 
 impl log::Log for Logger {
-    fn enabled(&self, _metadata: &log::Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= level_for(metadata.target())
     }
     fn log(&self, record: &log::Record) {
         if self.enabled(record.metadata()) {
-            println!("{}: {}", record.level(), record.args());
+            println!("{} {}: {}", Timestamp::now(), record.level(), record.args());
         }
     }
     fn flush(&self) {}
@@ -80,5 +216,72 @@ impl log::Log for Logger {
 /// and `init_logger` sets the default logger to serial.
 pub fn init_logger() {
     log::set_logger(&LOGGER).expect("Failed to set logger");
-    log::set_max_level(log::LevelFilter::Info);
+    update_max_level();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use log::{Level, Log, Metadata};
+
+    // The levels are global, so this is the only test that sets them.
+    #[test]
+    fn module_levels_override_the_global_level() {
+        // `set_module_level` disables interrupts, which a host test can't do.
+        let set_module_levels = |levels: &[(&'static str, LevelFilter)]| {
+            let mut module_levels = MODULE_LEVELS.write();
+            *module_levels = ArrayVec::new();
+            for &level in levels {
+                module_levels.push(level).unwrap();
+            }
+        };
+        let enabled = |target, level| {
+            LOGGER.enabled(&Metadata::builder().target(target).level(level).build())
+        };
+
+        set_module_levels(&[]);
+        set_level(LevelFilter::Info);
+        assert_eq!(level_for("kernel::memory"), LevelFilter::Info);
+        assert!(enabled("kernel::memory", Level::Info));
+        assert!(!enabled("kernel::memory", Level::Debug));
+        set_level(LevelFilter::Warn);
+        assert!(!enabled("kernel::memory", Level::Info));
+        assert!(enabled("kernel", Level::Error));
+
+        set_module_levels(&[
+            ("kernel::memory", LevelFilter::Trace),
+            ("kernel::memory::malloc", LevelFilter::Off),
+        ]);
+        assert_eq!(level_for("kernel::memory"), LevelFilter::Trace);
+        assert_eq!(level_for("kernel::memory::pmm"), LevelFilter::Trace);
+        // The longest matching prefix applies.
+        assert_eq!(level_for("kernel::memory::malloc"), LevelFilter::Off);
+        assert_eq!(
+            level_for("kernel::memory::malloc::pairing_heap"),
+            LevelFilter::Off
+        );
+        // Prefixes only match whole module names.
+        assert_eq!(level_for("kernel::memoryx"), LevelFilter::Warn);
+        assert_eq!(level_for("kernel::memory::mallocx"), LevelFilter::Trace);
+        assert_eq!(level_for("kernel"), LevelFilter::Warn);
+        assert!(enabled("kernel::memory::vmm", Level::Trace));
+        assert!(!enabled("kernel::memory::malloc", Level::Error));
+        assert!(!enabled("kernel::memoryx", Level::Info));
+
+        set_module_levels(&[]);
+        set_level(LevelFilter::Info);
+    }
+
+    #[test]
+    fn timestamps_format_seconds_and_millis() {
+        let formatted = |timestamp: Timestamp| alloc::format!("{timestamp}");
+        assert_eq!(formatted(Timestamp::Uptime(0)), "[0.000s]");
+        assert_eq!(formatted(Timestamp::Uptime(7)), "[0.007s]");
+        assert_eq!(formatted(Timestamp::Uptime(1000)), "[1.000s]");
+        assert_eq!(formatted(Timestamp::Uptime(12_345)), "[12.345s]");
+        assert_eq!(formatted(Timestamp::Uptime(3_600_050)), "[3600.050s]");
+        assert_eq!(formatted(Timestamp::Ticks(0)), "[0 ticks]");
+        assert_eq!(formatted(Timestamp::Ticks(42)), "[42 ticks]");
+    }
 }
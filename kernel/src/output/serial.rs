@@ -4,14 +4,63 @@ use core::fmt;
 
 use spin::{Lazy, Mutex};
 use uart_16550::SerialPort;
-use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
 
-/// The serial port.
-pub static SERIAL1: Lazy<Mutex<SerialPort>> = Lazy::new(|| {
-    let mut serial_port = unsafe { SerialPort::new(0x3f8) };
+use crate::{interrupts::irq, ring_buffer::RingBuffer};
+
+/// Base I/O port of COM1.
+const COM1: u16 = 0x3f8;
+/// Base I/O port of COM2.
+const COM2: u16 = 0x2f8;
+/// The ISA IRQ of COM1.
+const COM1_IRQ: u8 = 4;
+/// The vector COM1's interrupt is routed to.
+pub const COM1_VECTOR: u8 = irq::IRQ_VECTOR_BASE + COM1_IRQ;
+/// Offset of the line status register, and its bit set when a received byte is waiting.
+const LINE_STATUS: u16 = 5;
+const LINE_STATUS_DATA_READY: u8 = 1 << 0;
+
+/// Bytes COM1 received, queued by its interrupt handler.
+static RECEIVED: RingBuffer<256> = RingBuffer::new();
+
+/// COM1, which `sprint!()` and the logger print to.
+pub static SERIAL1: Lazy<Mutex<SerialPort>> = Lazy::new(|| Mutex::new(unsafe { init_port(COM1) }));
+/// COM2, which the output is mirrored to if [`super::set_mirror_to_com2`] is enabled.
+pub static SERIAL2: Lazy<Mutex<SerialPort>> = Lazy::new(|| Mutex::new(unsafe { init_port(COM2) }));
+
+/// A UART at some base I/O port, which tests can stand in for [`SerialPort`].
+trait Uart {
+    /// # Safety
+    /// Same as [`SerialPort::new`].
+    unsafe fn new(base: u16) -> Self;
+    fn init(&mut self);
+}
+
+impl Uart for SerialPort {
+    unsafe fn new(base: u16) -> Self {
+        unsafe { SerialPort::new(base) }
+    }
+
+    fn init(&mut self) {
+        SerialPort::init(self)
+    }
+}
+
+/// Initializes the 16550 UART with the I/O ports starting at `base`.
+///
+/// # Safety
+/// `base` must be the base I/O port of a 16550 UART, which nothing else uses.
+pub unsafe fn init_port(base: u16) -> SerialPort {
+    unsafe { init_uart(base) }
+}
+
+/// # Safety
+/// Same as [`init_port`].
+unsafe fn init_uart<U: Uart>(base: u16) -> U {
+    let mut serial_port = unsafe { U::new(base) };
     serial_port.init();
-    Mutex::new(serial_port)
-});
+    serial_port
+}
 
 /// Prints to the serial port. Don't use directly, use `sprint!()` instead.
 #[doc(hidden)]
@@ -31,6 +80,51 @@ pub fn _sprintln(args: core::fmt::Arguments) {
     })
 }
 
+/// Reads a byte from the serial port, if one was received.
+pub fn try_read_byte() -> Option<u8> {
+    without_interrupts(|| SERIAL1.lock().try_receive().ok())
+}
+
+/// Reads a byte COM1 received, from what its interrupt handler queued, or else from the port.
+pub fn read_byte() -> Option<u8> {
+    RECEIVED.pop().map(|byte| byte as u8).or_else(try_read_byte)
+}
+
+/// Moves COM1's receive FIFO into `RECEIVED`, dropping what doesn't fit. This reads the ports
+/// directly, since the interrupted code may be holding `SERIAL1`'s lock.
+fn com1_handler() {
+    let mut line_status = Port::<u8>::new(COM1 + LINE_STATUS);
+    let mut data = Port::<u8>::new(COM1);
+    while unsafe { line_status.read() } & LINE_STATUS_DATA_READY != 0 {
+        RECEIVED.push(unsafe { data.read() } as _);
+    }
+}
+
+/// Delivers COM1's received data interrupt to the current CPU, which `SERIAL1`'s initialization
+/// enables.
+pub fn init_input() {
+    Lazy::force(&SERIAL1);
+    if !irq::register_handler(COM1_VECTOR, com1_handler) {
+        log::warn!("COM1 vector {COM1_VECTOR} is already taken");
+        return;
+    }
+    unsafe {
+        crate::interrupts::ioapic::route_isa_irq(
+            COM1_IRQ,
+            COM1_VECTOR,
+            crate::interrupts::lapic_id(),
+        )
+    };
+}
+
+/// Prints to a freshly initialized COM1, bypassing `SERIAL1` and its lock, so it works before
+/// `SERIAL1` is initialized, or while it's locked. Only meant for panics.
+#[doc(hidden)]
+pub fn _emergency_print(args: core::fmt::Arguments) {
+    let mut serial_port = unsafe { init_port(COM1) };
+    let _ = fmt::write(&mut serial_port, args);
+}
+
 /// Print to serial port.
 #[macro_export]
 macro_rules! sprint {
@@ -49,3 +143,41 @@ macro_rules! sprintln {
         $crate::output::serial::_sprintln(format_args!($($arg)*));
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::{vec, vec::Vec};
+
+    use super::*;
+
+    /// Records the ports it was created and initialized at.
+    struct MockUart {
+        base: u16,
+        inits: Vec<u16>,
+    }
+
+    impl Uart for MockUart {
+        unsafe fn new(base: u16) -> Self {
+            Self {
+                base,
+                inits: Vec::new(),
+            }
+        }
+
+        fn init(&mut self) {
+            self.inits.push(self.base);
+        }
+    }
+
+    #[test]
+    fn ports_at_different_bases_are_independent() {
+        let com1: MockUart = unsafe { init_uart(COM1) };
+        let mut com2: MockUart = unsafe { init_uart(COM2) };
+        assert_eq!((com1.base, &com1.inits[..]), (COM1, &[COM1][..]));
+        assert_eq!((com2.base, &com2.inits[..]), (COM2, &[COM2][..]));
+
+        com2.init();
+        assert_eq!(com1.inits, vec![COM1]);
+        assert_eq!(com2.inits, vec![COM2, COM2]);
+    }
+}
@@ -0,0 +1,200 @@
+use core::iter::FusedIterator;
+
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+/// The legacy configuration space access mechanism: write the address to `CONFIG_ADDRESS`, then
+/// access the dword through `CONFIG_DATA`.
+static CONFIG_PORTS: spin::Mutex<(Port<u32>, Port<u32>)> =
+    spin::Mutex::new((Port::new(0xCF8), Port::new(0xCFC)));
+
+const STATUS_OFFSET: u8 = 0x06;
+const STATUS_CAPABILITIES_LIST: u16 = 1 << 4;
+const CAPABILITIES_POINTER_OFFSET: u8 = 0x34;
+
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+/// A PCI function, addressed by bus, device and function number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PciDevice {
+    bus: u8,
+    device: u8,
+    function: u8,
+}
+
+impl PciDevice {
+    /// # Safety
+    /// The function must exist, and its configuration space must not be modified concurrently.
+    pub unsafe fn new(bus: u8, device: u8, function: u8) -> Self {
+        debug_assert!(device < 32 && function < 8);
+        Self {
+            bus,
+            device,
+            function,
+        }
+    }
+
+    pub fn bus(&self) -> u8 {
+        self.bus
+    }
+    pub fn device(&self) -> u8 {
+        self.device
+    }
+    pub fn function(&self) -> u8 {
+        self.function
+    }
+
+    /// Reads the configuration space dword containing `offset`.
+    pub fn read_u32(&self, offset: u8) -> u32 {
+        let address = 1 << 31
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & !0b11) as u32;
+        without_interrupts(|| {
+            let (address_port, data_port) = &mut *CONFIG_PORTS.lock();
+            unsafe {
+                address_port.write(address);
+                data_port.read()
+            }
+        })
+    }
+    pub fn read_u16(&self, offset: u8) -> u16 {
+        (self.read_u32(offset) >> (8 * (offset & 0b10))) as _
+    }
+    pub fn read_u8(&self, offset: u8) -> u8 {
+        (self.read_u32(offset) >> (8 * (offset & 0b11))) as _
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read_u16(0x00)
+    }
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(0x02)
+    }
+
+    /// Walks the capabilities list, yielding `(offset, id)` of each capability.
+    pub fn capabilities(&self) -> Capabilities {
+        let next = match self.read_u16(STATUS_OFFSET) & STATUS_CAPABILITIES_LIST != 0 {
+            true => self.read_u8(CAPABILITIES_POINTER_OFFSET),
+            false => 0,
+        };
+        Capabilities {
+            device: *self,
+            next,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<u8> {
+        (self.capabilities()).find_map(|(offset, cap_id)| (cap_id == id).then_some(offset))
+    }
+
+    pub fn msi_capability(&self) -> Option<MsiCapability> {
+        let offset = self.find_capability(CAPABILITY_MSI)?;
+        Some(MsiCapability {
+            offset,
+            control: self.read_u16(offset + 2),
+        })
+    }
+
+    pub fn msix_capability(&self) -> Option<MsixCapability> {
+        let offset = self.find_capability(CAPABILITY_MSIX)?;
+        let table = self.read_u32(offset + 4);
+        let pba = self.read_u32(offset + 8);
+        Some(MsixCapability {
+            offset,
+            control: self.read_u16(offset + 2),
+            table_bir: (table & 0b111) as _,
+            table_offset: table & !0b111,
+            pba_bir: (pba & 0b111) as _,
+            pba_offset: pba & !0b111,
+        })
+    }
+}
+
+/// There are at most 48 capabilities in the 192 bytes after the header, this bounds the walk in case
+/// the list is cyclic.
+const MAX_CAPABILITIES: u8 = 48;
+
+/// Iterator over a device's capabilities, see [`PciDevice::capabilities`].
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    device: PciDevice,
+    next: u8,
+    remaining: u8,
+}
+
+impl Iterator for Capabilities {
+    type Item = (u8, u8);
+    fn next(&mut self) -> Option<Self::Item> {
+        // The bottom two bits are reserved, and the pointer can't point into the header.
+        let offset = self.next & !0b11;
+        if offset < 0x40 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let header = self.device.read_u16(offset);
+        self.next = (header >> 8) as _;
+        Some((offset, header as _))
+    }
+}
+
+impl FusedIterator for Capabilities {}
+
+/// The MSI capability structure.
+#[derive(Debug, Clone, Copy)]
+pub struct MsiCapability {
+    /// Offset of the capability in the configuration space.
+    pub offset: u8,
+    /// Message Control register.
+    pub control: u16,
+}
+
+impl MsiCapability {
+    pub fn enabled(&self) -> bool {
+        self.control & 1 != 0
+    }
+    /// Number of vectors the function can request, a power of two up to 32.
+    pub fn multiple_message_capable(&self) -> u8 {
+        1 << ((self.control >> 1) & 0b111)
+    }
+    /// Whether the message address has an upper dword, which moves the message data to
+    /// `offset + 0xC`, instead of `offset + 0x8`.
+    pub fn is_64bit(&self) -> bool {
+        self.control & 1 << 7 != 0
+    }
+    pub fn per_vector_masking(&self) -> bool {
+        self.control & 1 << 8 != 0
+    }
+}
+
+/// The MSI-X capability structure.
+#[derive(Debug, Clone, Copy)]
+pub struct MsixCapability {
+    /// Offset of the capability in the configuration space.
+    pub offset: u8,
+    /// Message Control register.
+    pub control: u16,
+    /// Index of the BAR the MSI-X table is in.
+    pub table_bir: u8,
+    /// Offset of the MSI-X table in its BAR.
+    pub table_offset: u32,
+    /// Index of the BAR the pending bit array is in.
+    pub pba_bir: u8,
+    /// Offset of the pending bit array in its BAR.
+    pub pba_offset: u32,
+}
+
+impl MsixCapability {
+    /// Number of entries in the MSI-X table.
+    pub fn table_size(&self) -> u16 {
+        (self.control & 0x7FF) + 1
+    }
+    pub fn function_mask(&self) -> bool {
+        self.control & 1 << 14 != 0
+    }
+    pub fn enabled(&self) -> bool {
+        self.control & 1 << 15 != 0
+    }
+}
@@ -0,0 +1,62 @@
+//! Data private to each CPU, found through the GS base.
+
+use core::{arch::asm, ptr, sync::atomic::AtomicPtr};
+
+use alloc::boxed::Box;
+use x86_64::{registers::model_specific::GsBase, VirtAddr};
+
+#[repr(C)]
+pub struct PerCpu {
+    /// Points to the block itself, so that [`this_cpu`] can find it with a single GS-relative read.
+    this: *const PerCpu,
+    pub cpu_id: u32,
+    pub lapic_id: u32,
+    /// Which of the allocator's per-thread heaps this CPU uses.
+    pub alloc_index: usize,
+    /// The thread running on this CPU, once there are threads.
+    pub current_thread: AtomicPtr<()>,
+}
+
+/// Where the GS base points before [`init_percpu`]: an empty block, whose null `this` tells
+/// [`this_cpu`] that there's no block yet.
+static NO_PERCPU: usize = 0;
+
+/// Points the current CPU's GS base at an empty block, so that [`this_cpu`] returns `None` until
+/// [`init_percpu`]. It has to run before anything allocates, since the allocator uses [`this_cpu`].
+pub fn init_early() {
+    GsBase::write(VirtAddr::from_ptr(&NO_PERCPU));
+}
+
+/// Allocates the current CPU's block, and points its GS base at it.
+pub fn init_percpu(cpu_id: u32) {
+    assert!(
+        (cpu_id as usize) < crate::memory::malloc::MAX_CPUS,
+        "CPU {cpu_id} is over MAX_CPUS={}",
+        crate::memory::malloc::MAX_CPUS,
+    );
+    let percpu = Box::leak(Box::new(PerCpu {
+        this: ptr::null(),
+        cpu_id,
+        lapic_id: crate::interrupts::lapic_id(),
+        alloc_index: cpu_id as _,
+        current_thread: AtomicPtr::new(ptr::null_mut()),
+    }));
+    percpu.this = percpu;
+    GsBase::write(VirtAddr::from_ptr(percpu));
+    debug_assert_eq!(this_cpu().map(|cpu| cpu.cpu_id), Some(cpu_id));
+}
+
+/// The current CPU's block, or `None` before [`init_percpu`] ran on this CPU.
+pub fn this_cpu() -> Option<&'static PerCpu> {
+    // Nothing else writes the GS base, and `kernel_main` starts with `init_early`, so it points at
+    // `NO_PERCPU` or a block. Checking it would add a slow MSR read to the allocator's fast path.
+    let percpu: *const PerCpu;
+    unsafe {
+        asm!(
+            "mov {}, gs:[0]",
+            out(reg) percpu,
+            options(nostack, preserves_flags, readonly),
+        );
+        percpu.as_ref()
+    }
+}
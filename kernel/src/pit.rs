@@ -0,0 +1,93 @@
+//! Delays using channel 2 of the Programmable Interval Timer, which is available before any other
+//! timer and is the reference for calibrating the others. Channel 0 is the last resort time source.
+
+use x86_64::instructions::{interrupts::without_interrupts, port::Port};
+
+/// Frequency of the PIT's input clock in Hz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+/// The longest delay a single one-shot can measure, since the reload value is 16 bits (~54.9 ms).
+const MAX_SHOT_MS: u16 = (0xFFFF * 1000 / PIT_FREQUENCY) as _;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const CHANNEL2_DATA: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+/// Controls the channel 2 gate (bit 0) and the speaker (bit 1), and reads back the channel 2
+/// output (bit 5).
+const SPEAKER_CONTROL: u16 = 0x61;
+
+/// Channel 2, lobyte/hibyte access, mode 0 (interrupt on terminal count), binary.
+const CHANNEL2_ONE_SHOT: u8 = 0b1011_0000;
+/// Channel 0, lobyte/hibyte access, mode 2 (rate generator), binary.
+const CHANNEL0_RATE_GENERATOR: u8 = 0b0011_0100;
+/// Latches channel 0's count for reading it.
+const CHANNEL0_LATCH: u8 = 0b0000_0000;
+
+/// Channel 0's ticks until it was last read, and its count at that point.
+struct Counter {
+    ticks: u64,
+    count: u16,
+}
+
+/// The command port is shared between the channels, so the ports are only used with this locked.
+static PIT: spin::Mutex<Counter> = spin::Mutex::new(Counter { ticks: 0, count: 0 });
+
+/// Runs channel 2 from `reload` down to zero, spinning until its output goes high.
+fn one_shot(reload: u16) {
+    let mut control = Port::<u8>::new(SPEAKER_CONTROL);
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL2_DATA);
+    without_interrupts(|| unsafe {
+        let _pit = PIT.lock();
+        // Lower the gate and disconnect the speaker while programming the channel.
+        let gate_low = control.read() & !0b11;
+        control.write(gate_low);
+        command.write(CHANNEL2_ONE_SHOT);
+        data.write(reload as u8);
+        data.write((reload >> 8) as u8);
+        // Counting starts when the gate goes high.
+        control.write(gate_low | 1);
+        while control.read() & 1 << 5 == 0 {
+            core::hint::spin_loop();
+        }
+        control.write(gate_low);
+    });
+}
+
+/// Busy-waits for `ms` milliseconds, in one-shots of at most ~54 ms.
+pub fn sleep_pit_ms(mut ms: u16) {
+    while 0 < ms {
+        let shot = ms.min(MAX_SHOT_MS);
+        one_shot((PIT_FREQUENCY * shot as u64 / 1000) as _);
+        ms -= shot;
+    }
+}
+
+/// Runs channel 0 as a free-running counter for [`ticks`], wrapping around every ~54.9 ms.
+pub fn start_counter() {
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL0_DATA);
+    without_interrupts(|| unsafe {
+        let mut pit = PIT.lock();
+        // A reload value of zero counts all 65536 values.
+        command.write(CHANNEL0_RATE_GENERATOR);
+        data.write(0);
+        data.write(0);
+        *pit = Counter { ticks: 0, count: 0 };
+    });
+}
+
+/// Ticks of [`PIT_FREQUENCY`] since [`start_counter`]. The ticks of a whole wraparound are lost if
+/// this isn't called at least every ~54.9 ms.
+pub fn ticks() -> u64 {
+    let mut command = Port::<u8>::new(COMMAND);
+    let mut data = Port::<u8>::new(CHANNEL0_DATA);
+    without_interrupts(|| unsafe {
+        let mut pit = PIT.lock();
+        command.write(CHANNEL0_LATCH);
+        let count = u16::from_le_bytes([data.read(), data.read()]);
+        // The count goes down.
+        pit.ticks += pit.count.wrapping_sub(count) as u64;
+        pit.count = count;
+        pit.ticks
+    })
+}
@@ -0,0 +1,103 @@
+//! Rebooting and powering off the machine.
+
+use x86_64::{
+    instructions::{interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+    VirtAddr,
+};
+
+use crate::acpi;
+
+/// What the panic handler does after printing the panic message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicAction {
+    /// Halt forever, for looking at the message.
+    Halt,
+    /// Reboot the machine.
+    Reboot,
+    /// Exit QEMU with the given code, see [`exit_qemu`].
+    QemuExit(u32),
+}
+
+static PANIC_ACTION: spin::Mutex<PanicAction> = spin::Mutex::new(PanicAction::Halt);
+
+pub fn set_panic_action(action: PanicAction) {
+    interrupts::without_interrupts(|| *PANIC_ACTION.lock() = action);
+}
+
+/// The action set by [`set_panic_action`], or [`PanicAction::Halt`] if it's being set right now.
+pub fn panic_action() -> PanicAction {
+    PANIC_ACTION
+        .try_lock()
+        .map_or(PanicAction::Halt, |action| *action)
+}
+
+/// Halts forever, with interrupts disabled.
+pub fn halt() -> ! {
+    loop {
+        interrupts::disable();
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Resets the machine, through the keyboard controller, then the ACPI reset register, and as a
+/// last resort a triple fault.
+pub fn reboot() -> ! {
+    interrupts::disable();
+    log::info!("Rebooting");
+
+    // Pulse the CPU reset line through the 8042 keyboard controller, once its input buffer is empty.
+    let mut status_port = Port::<u8>::new(0x64);
+    for _ in 0..0x10000 {
+        if unsafe { status_port.read() } & 0b10 == 0 {
+            break;
+        }
+    }
+    unsafe { status_port.write(0xFE) };
+
+    if let Some((reg, value)) = acpi::reset_register() {
+        match reg.address_space {
+            acpi::ADDRESS_SPACE_SYSTEM_IO => unsafe { Port::new(reg.address as u16).write(value) },
+            space => log::warn!("Unsupported ACPI reset register address space {space}"),
+        }
+    }
+
+    // With an empty IDT, any exception triple faults, which resets the CPU.
+    let empty_idt = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::zero(),
+    };
+    unsafe { lidt(&empty_idt) };
+    interrupts::int3();
+
+    log::error!("Reboot failed");
+    halt();
+}
+
+/// Powers off the machine. Without an AML interpreter the ACPI sleep states are out of reach, so
+/// this only works under QEMU and Bochs.
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    log::info!("Shutting down");
+
+    // QEMU's PIIX4 power management port.
+    unsafe { Port::<u16>::new(0x604).write(0x2000) };
+    // Bochs and older QEMU versions.
+    unsafe { Port::<u16>::new(0xB004).write(0x2000) };
+
+    log::error!("Shutdown failed");
+    halt();
+}
+
+/// The [`exit_qemu`] codes the runner reports as success and failure. Zero can't be used for
+/// success, since QEMU would exit with 1 like it does on its own errors.
+pub const QEMU_EXIT_SUCCESS: u32 = 0x10;
+pub const QEMU_EXIT_FAILURE: u32 = 0x11;
+
+/// Exits QEMU through its `isa-debug-exit` device at port 0xF4, which makes QEMU exit with
+/// `code << 1 | 1`. Halts if the device isn't there.
+pub fn exit_qemu(code: u32) -> ! {
+    interrupts::disable();
+    unsafe { Port::<u32>::new(0xF4).write(code) };
+    halt();
+}
@@ -1,9 +1,20 @@
+use alloc::string::String;
 use core::{cmp::Ordering, fmt, iter::FusedIterator, str};
 
+use hashbrown::HashMap;
+
+mod builtin;
 mod ucs2;
 
+pub use builtin::BuiltinFont;
 use ucs2::Ucs2Str;
 
+/// PSF1 mode bits: the font has 512 glyphs instead of 256, it has a unicode table, and its unicode
+/// table has sequences (which implies it has one).
+const PSF1_MODE512: u8 = 0x01;
+const PSF1_MODEHASTAB: u8 = 0x02;
+const PSF1_MODESEQ: u8 = 0x04;
+
 // should be less than (255 / 4)
 // const MAX_UNICODE_CHAR_NUM: usize = 4;
 
@@ -127,8 +138,11 @@ impl<'a> PsfFile<'a> {
             raw_bytes: bytes,
             version: PsfVersion::Psf1,
             header_size: 4,
-            num_glyphs: if header[2] & 1 == 0 { 256 } else { 512 },
-            has_unicode_table: header[2] & 6 != 0,
+            num_glyphs: match header[2] & PSF1_MODE512 {
+                0 => 256,
+                _ => 512,
+            },
+            has_unicode_table: header[2] & (PSF1_MODEHASTAB | PSF1_MODESEQ) != 0,
             glyph_size: header[3] as u32,
             glyph_height: header[3] as u32,
             glyph_width: 8,
@@ -256,11 +270,67 @@ impl<'a> PsfFile<'a> {
             version: self.version,
             raw_bytes: self.raw_bytes,
             entry_index: 0,
+            num_glyphs: self.num_glyphs,
             index: self.unicode_table_start(),
+            in_sequence: false,
         }
     }
 
+    /// The glyph of `ch`, found by scanning the unicode table. Use [`Self::build_char_table`] for
+    /// repeated lookups.
+    pub fn glyph_for_char(&self, ch: char) -> Option<Glyph<'a>> {
+        let entry = self.unicode_table_entries().find(|entry| {
+            !entry.sequence
+                && match entry.value {
+                    UnicodeTableEntryValue::Utf8(s) => s.contains(ch),
+                    UnicodeTableEntryValue::Ucs2(s) => s.chars().any(|c| c == ch),
+                }
+        })?;
+        self.get_glyph(entry.index)
+    }
+
+    /// Collects the unicode table into maps from characters and sequences to glyph indices.
+    pub fn build_char_table(&self) -> CharTable {
+        let mut table = CharTable::default();
+        for entry in self.unicode_table_entries() {
+            let chars: String = match entry.value {
+                UnicodeTableEntryValue::Utf8(s) => s.into(),
+                UnicodeTableEntryValue::Ucs2(s) => s.chars().collect(),
+            };
+            match entry.sequence {
+                // A single character sequence is just a character.
+                true if 1 < chars.chars().count() => {
+                    let len = chars.chars().count();
+                    table.longest_sequence = table.longest_sequence.max(len);
+                    table.sequences.insert(chars, entry.index);
+                }
+                _ => {
+                    for ch in chars.chars() {
+                        table.chars.insert(ch, entry.index);
+                    }
+                }
+            }
+        }
+        table
+    }
+
+    /// Every glyph of the font in order, so `.enumerate()` gives their indices.
+    pub fn iter_glyphs(
+        &self,
+    ) -> impl 'a + DoubleEndedIterator<Item = Glyph<'a>> + FusedIterator + ExactSizeIterator {
+        let glyphs = &self.raw_bytes[self.header_size as usize..self.unicode_table_start()];
+        let (size, width) = (self.glyph_size as usize, self.glyph_width);
+        (0..self.num_glyphs as usize).map(move |i| Glyph {
+            bytes: &glyphs[i * size..(i + 1) * size],
+            width,
+        })
+    }
+
+    /// The glyph at index `entry`, or `None` if the font doesn't have that many glyphs.
     pub fn get_glyph(&self, entry: u32) -> Option<Glyph<'a>> {
+        if self.num_glyphs <= entry {
+            return None;
+        }
         let start = (self.header_size + entry * self.glyph_size) as usize;
         let bytes = self
             .raw_bytes
@@ -272,6 +342,33 @@ impl<'a> PsfFile<'a> {
     }
 }
 
+/// Maps from characters and character sequences to glyph indices, made by
+/// [`PsfFile::build_char_table`].
+#[derive(Debug, Clone, Default)]
+pub struct CharTable {
+    chars: HashMap<char, u32>,
+    sequences: HashMap<String, u32>,
+    /// Number of characters in the longest key of `sequences`.
+    longest_sequence: usize,
+}
+
+impl CharTable {
+    /// The glyph index of `ch`.
+    pub fn get(&self, ch: char) -> Option<u32> {
+        self.chars.get(&ch).copied()
+    }
+
+    /// The glyph index of the character sequence `seq`.
+    pub fn get_sequence(&self, seq: &str) -> Option<u32> {
+        self.sequences.get(seq).copied()
+    }
+
+    /// Number of characters in the longest sequence with a glyph, or 0 if there are none.
+    pub fn longest_sequence(&self) -> usize {
+        self.longest_sequence
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct Glyph<'a> {
     pub bytes: &'a [u8],
@@ -294,6 +391,21 @@ impl<'a> GlyphRowIter<'a> {
     fn split_indices(&self) -> (u8, u8) {
         (self.indices & 7, self.indices >> 4)
     }
+
+    /// Packs the remaining pixels into the low bits of an integer, with the first pixel in the most
+    /// significant of them. Panics if more than 32 pixels remain.
+    pub fn bits(self) -> u32 {
+        let len = self.len();
+        assert!(len <= 32, "a row of {len} pixels doesn't fit in a u32");
+        if len == 0 {
+            return 0;
+        }
+        // At most 7 skipped bits and 32 pixels, so at most 5 bytes.
+        let bytes = self.bytes.iter().fold(0u64, |acc, &b| acc << 8 | b as u64);
+        let (start, _) = self.split_indices();
+        let trailing = 8 * self.bytes.len() - start as usize - len;
+        ((bytes >> trailing) & ((1 << len) - 1)) as u32
+    }
 }
 
 impl<'a> Iterator for GlyphRowIter<'a> {
@@ -316,19 +428,21 @@ impl<'a> Iterator for GlyphRowIter<'a> {
     }
 
     fn nth(&mut self, n: usize) -> Option<bool> {
-        self.bytes = &self.bytes[self.bytes.len().min(n / 8 + 1)..];
-        self.indices += (n % 8) as u8;
-        if self.indices & 8 != 0 {
-            self.bytes = self.bytes.get(1..).unwrap_or(&[]);
-            self.indices ^= 8;
-        }
+        // Bit position of the `n`th bit, counting from the start of the first byte.
+        let (start, _) = self.split_indices();
+        let pos = start as usize + n;
+        self.bytes = &self.bytes[self.bytes.len().min(pos / 8)..];
+        self.indices = self.indices & 0xF0 | (pos % 8) as u8;
         self.next()
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        let len = self.bytes.len();
         let (start, end) = self.split_indices();
-        let len = 8 * len - start as usize + end as usize - 8;
+        // `end` is inclusive, and `start` may be past `end` when there's one byte left.
+        let len = match self.bytes.len() {
+            0 => 0,
+            len => (8 * (len - 1) + end as usize + 1).saturating_sub(start as usize),
+        };
         (len, Some(len))
     }
 
@@ -359,12 +473,11 @@ impl<'a> DoubleEndedIterator for GlyphRowIter<'a> {
     }
 
     fn nth_back(&mut self, n: usize) -> Option<bool> {
-        self.bytes = &self.bytes[..self.bytes.len().saturating_sub(n / 8)];
-        self.indices -= (n % 8 << 4) as u8;
-        if self.indices & 0x80 != 0 {
-            self.bytes = &self.bytes[..self.bytes.len().saturating_sub(1)];
-            self.indices ^= 0x80;
-        }
+        // Bit position of the `n`th bit from the back, counting from the end of the last byte.
+        let (_, end) = self.split_indices();
+        let pos = (7 - end) as usize + n;
+        self.bytes = &self.bytes[..self.bytes.len().saturating_sub(pos / 8)];
+        self.indices = self.indices & 0x0F | ((7 - pos % 8) << 4) as u8;
         self.next_back()
     }
 }
@@ -385,7 +498,11 @@ pub struct UnicodeTableEntries<'a> {
     raw_bytes: &'a [u8],
     version: PsfVersion,
     entry_index: u32,
+    /// Entries past the font's glyphs are skipped.
+    num_glyphs: u32,
     index: usize,
+    /// Whether the next entry comes after a sequence separator.
+    in_sequence: bool,
 }
 
 #[derive(Debug, Clone, Copy, Hash)]
@@ -394,56 +511,364 @@ pub enum UnicodeTableEntryValue<'a> {
     Ucs2(&'a Ucs2Str),
 }
 
+/// A part of a glyph's unicode table entry. The table has a glyph's characters first, each of them
+/// mapping to the glyph on its own, followed by sequences of characters (like a letter and combining
+/// marks) that together map to the glyph. Every sequence is preceded by a separator (`0xFE` in
+/// PSF2, `0xFFFE` in PSF1) and is its own [`UnicodeTableEntry`].
 #[derive(Debug, Clone, Hash)]
 pub struct UnicodeTableEntry<'a> {
     pub index: u32,
     pub value: UnicodeTableEntryValue<'a>,
+    /// Whether `value` is a single sequence, rather than characters that map to the glyph one by
+    /// one.
+    pub sequence: bool,
 }
 
 impl<'a> Iterator for UnicodeTableEntries<'a> {
     type Item = UnicodeTableEntry<'a>;
+    /// The table is validated when the file is parsed, but if it's malformed anyway, a missing
+    /// terminator ends the iteration, and entries that aren't valid strings or are past the last
+    /// glyph are skipped.
     fn next(&mut self) -> Option<Self::Item> {
-        const ERROR_MSG: &str = "PSF unicode table is invalid";
-
-        if self.index == self.raw_bytes.len() {
-            return None;
-        }
+        loop {
+            if self.raw_bytes.len() <= self.index {
+                return None;
+            }
 
-        let (entry_index, str_start) = (self.entry_index, self.index);
-        let mut str_end = self.index;
-        match self.version {
-            PsfVersion::Psf1 => {
-                str_end += 2 * self.raw_bytes[self.index..]
-                    .chunks(2)
+            let (entry_index, str_start) = (self.entry_index, self.index);
+            let mut str_end = self.index;
+            let terminator = match self.version {
+                PsfVersion::Psf1 => (self.raw_bytes[self.index..].chunks(2))
                     .position(|ch| matches!(ch, [0xFF | 0xFE, 0xFF]))
-                    .expect(ERROR_MSG);
-                self.index = str_end + 2;
+                    .map(|pos| 2 * pos),
+                PsfVersion::Psf2 => (self.raw_bytes[self.index..].iter()).position(|&ch| 0xFD < ch),
+            };
+            let Some(terminator) = terminator else {
+                self.index = self.raw_bytes.len();
+                return None;
+            };
+            str_end += terminator;
+            self.index = match self.version {
+                PsfVersion::Psf1 => str_end + 2,
+                PsfVersion::Psf2 => str_end + 1,
+            };
+
+            let sequence = self.in_sequence;
+            self.in_sequence = self.raw_bytes[str_end] == 0xFE;
+            if self.raw_bytes[str_end] == 0xFF {
+                self.entry_index += 1;
             }
-            PsfVersion::Psf2 => {
-                str_end += self.raw_bytes[self.index..]
-                    .iter()
-                    .position(|&ch| 0xFD < ch)
-                    .expect(ERROR_MSG);
-                self.index = str_end + 1;
+            let bytes = &self.raw_bytes[str_start..str_end];
+            let value = match self.version {
+                PsfVersion::Psf1 => Ucs2Str::from_bytes(bytes).map(UnicodeTableEntryValue::Ucs2),
+                PsfVersion::Psf2 => (str::from_utf8(bytes).ok()).map(UnicodeTableEntryValue::Utf8),
+            };
+            match value {
+                Some(value) if entry_index < self.num_glyphs => {
+                    return Some(UnicodeTableEntry {
+                        index: entry_index,
+                        value,
+                        sequence,
+                    });
+                }
+                _ => {}
             }
         }
-        if self.raw_bytes[str_end] == 0xFF {
-            self.entry_index += 1;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use alloc::{string::String, vec::Vec};
+
+    use super::*;
+
+    /// A glyph of `width` pixels per row, over `bytes`.
+    fn glyph(bytes: &[u8], width: u32) -> Glyph<'_> {
+        Glyph { bytes, width }
+    }
+
+    /// A PSF2 font of 8x2 glyphs, followed by the unicode table `table`.
+    pub(crate) fn psf2(glyphs: &[[u8; 2]], table: &[u8]) -> Vec<u8> {
+        let header = [0x864ab572, 0, 32, 1, glyphs.len() as u32, 2, 2, 8];
+        let mut bytes: Vec<u8> = header.iter().flat_map(|n: &u32| n.to_le_bytes()).collect();
+        bytes.extend(glyphs.iter().flatten());
+        bytes.extend(table);
+        bytes
+    }
+
+    /// A PSF1 font with `mode` and 8x8 glyphs whose bytes are their index, followed by the unicode
+    /// table `table`.
+    fn psf1(mode: u8, num_glyphs: usize, table: &[u16]) -> Vec<u8> {
+        let mut bytes = alloc::vec![0x36, 0x04, mode, 8];
+        bytes.extend((0..num_glyphs).flat_map(|glyph| [glyph as u8; 8]));
+        bytes.extend(table.iter().flat_map(|ch| ch.to_le_bytes()));
+        bytes
+    }
+
+    /// A PSF2 unicode table for 4 glyphs. Glyph 1 has two characters and the sequence "e\u{301}",
+    /// and glyph 2 only the sequences "o\u{302}" and "u\u{308}".
+    pub(crate) const PSF2_SEQUENCES: &[u8] =
+        b"A\xFFBC\xFEe\xCC\x81\xFF\xFEo\xCC\x82\xFEu\xCC\x88\xFFx\xFF";
+
+    const BYTES: [u8; 8] = [0b1011_0010, 0x5A, 0xC3, 0x0F, 0x96, 0x01, 0xF0, 0x7E];
+
+    #[test]
+    fn row_nth_matches_next() {
+        for width in [3, 8, 11, 16, 21] {
+            for row in glyph(&BYTES, width).rows() {
+                let pixels: Vec<bool> = row.clone().collect();
+                assert_eq!(pixels.len(), row.len());
+                for n in 0..=pixels.len() {
+                    let mut iter = row.clone();
+                    assert_eq!(
+                        iter.nth(n),
+                        pixels.get(n).copied(),
+                        "width {width}, nth({n})"
+                    );
+                    let rest: Vec<bool> = iter.collect();
+                    assert_eq!(rest, pixels.get(n + 1..).unwrap_or_default());
+                }
+            }
         }
-        let bytes = &self.raw_bytes[str_start..str_end];
-        // if bytes.is_empty() {
-        //     panic!("{ERROR_MSG}");
-        // }
-        Some(UnicodeTableEntry {
-            index: entry_index,
-            value: match self.version {
-                PsfVersion::Psf1 => {
-                    UnicodeTableEntryValue::Ucs2(Ucs2Str::from_bytes(bytes).expect(ERROR_MSG))
+    }
+
+    #[test]
+    fn row_nth_back_matches_next_back() {
+        for width in [3, 8, 11, 16, 21] {
+            for row in glyph(&BYTES, width).rows() {
+                let pixels: Vec<bool> = row.clone().rev().collect();
+                for n in 0..=pixels.len() {
+                    let mut iter = row.clone();
+                    assert_eq!(
+                        iter.nth_back(n),
+                        pixels.get(n).copied(),
+                        "width {width}, nth_back({n})",
+                    );
+                    let rest: Vec<bool> = iter.rev().collect();
+                    assert_eq!(rest, pixels.get(n + 1..).unwrap_or_default());
                 }
-                PsfVersion::Psf2 => {
-                    UnicodeTableEntryValue::Utf8(str::from_utf8(bytes).expect(ERROR_MSG))
+            }
+        }
+    }
+
+    #[test]
+    fn row_nth_from_both_ends() {
+        for width in [3, 8, 11, 16, 21] {
+            let row = glyph(&BYTES, width).rows().next().unwrap();
+            let pixels: Vec<bool> = row.clone().collect();
+            for front in 0..pixels.len() {
+                for back in 0..pixels.len() - front {
+                    let mut iter = row.clone();
+                    assert_eq!(iter.nth(front), Some(pixels[front]));
+                    let last = pixels.len() - 1 - back;
+                    match front < last {
+                        true => assert_eq!(iter.nth_back(back), Some(pixels[last])),
+                        false => assert_eq!(iter.nth_back(back), None),
+                    }
+                    let rest: Vec<bool> = iter.collect();
+                    assert_eq!(rest, pixels.get(front + 1..last).unwrap_or_default());
                 }
-            },
-        })
+            }
+        }
+    }
+
+    #[test]
+    fn row_bits_match_iteration() {
+        let pack = |iter: GlyphRowIter| iter.fold(0, |acc, pixel| acc << 1 | pixel as u32);
+        for width in [3, 8, 11, 16, 21, 32] {
+            for row in glyph(&BYTES, width).rows() {
+                let len = row.len();
+                for front in 0..=len {
+                    for back in 0..=len - front {
+                        let mut iter = row.clone();
+                        if let Some(n) = front.checked_sub(1) {
+                            iter.nth(n);
+                        }
+                        if let Some(n) = back.checked_sub(1) {
+                            iter.nth_back(n);
+                        }
+                        assert_eq!(
+                            iter.clone().bits(),
+                            pack(iter),
+                            "width {width}, front {front}, back {back}",
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic = "doesn't fit in a u32"]
+    fn row_bits_of_wide_rows_panic() {
+        glyph(&BYTES, 33).rows().next().unwrap().bits();
+    }
+
+    #[test]
+    fn psf2_sequences() {
+        let bytes = psf2(&[[0; 2], [1; 2], [2; 2], [3; 2]], PSF2_SEQUENCES);
+        let font = PsfFile::parse(&bytes).unwrap();
+        let entries: Vec<(u32, &str, bool)> = (font.unicode_table_entries())
+            .map(|entry| match entry.value {
+                UnicodeTableEntryValue::Utf8(s) => (entry.index, s, entry.sequence),
+                UnicodeTableEntryValue::Ucs2(_) => unreachable!(),
+            })
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                (0, "A", false),
+                (1, "BC", false),
+                (1, "e\u{301}", true),
+                (2, "", false),
+                (2, "o\u{302}", true),
+                (2, "u\u{308}", true),
+                (3, "x", false),
+            ]
+        );
+
+        let table = font.build_char_table();
+        assert_eq!(table.get('B'), Some(1));
+        assert_eq!(table.get('C'), Some(1));
+        assert_eq!(table.get('x'), Some(3));
+        assert_eq!(table.get('e'), None);
+        assert_eq!(table.get_sequence("e\u{301}"), Some(1));
+        assert_eq!(table.get_sequence("o\u{302}"), Some(2));
+        assert_eq!(table.get_sequence("u\u{308}"), Some(2));
+        assert_eq!(table.longest_sequence(), 2);
+        // Sequences don't map their characters on their own.
+        assert_eq!(
+            font.glyph_for_char('\u{301}').map(|glyph| glyph.bytes),
+            None
+        );
+
+        // An entry separator is needed for every glyph.
+        let bytes = psf2(&[[0; 2]; 5], PSF2_SEQUENCES);
+        assert!(matches!(
+            PsfFile::parse(&bytes),
+            Err(Error::InvalidUnicodeTableSize {
+                num_glyphs: 5,
+                num_entries: 4
+            })
+        ));
+    }
+
+    /// The entries of a malformed unicode table `table` for `num_glyphs` glyphs, which parsing would
+    /// have rejected.
+    fn corrupt_entries(
+        version: PsfVersion,
+        table: &[u8],
+        num_glyphs: u32,
+    ) -> Vec<(u32, String, bool)> {
+        let mut entries = UnicodeTableEntries {
+            raw_bytes: table,
+            version,
+            entry_index: 0,
+            num_glyphs,
+            index: 0,
+            in_sequence: false,
+        };
+        let values = (entries.by_ref())
+            .map(|entry| {
+                let value = match entry.value {
+                    UnicodeTableEntryValue::Utf8(s) => s.into(),
+                    UnicodeTableEntryValue::Ucs2(s) => s.into(),
+                };
+                (entry.index, value, entry.sequence)
+            })
+            .collect();
+        assert!(entries.next().is_none());
+        values
+    }
+
+    #[test]
+    fn corrupt_unicode_tables_end_iteration() {
+        let entry = |index, s: &str| (index, String::from(s), false);
+
+        // A missing terminator ends the table.
+        let entries = corrupt_entries(PsfVersion::Psf2, b"A\xFFB\xFEC", 3);
+        assert_eq!(entries, [entry(0, "A"), entry(1, "B")]);
+        // Invalid UTF-8 is skipped.
+        let entries = corrupt_entries(PsfVersion::Psf2, b"A\xFF\xC3\xFFB\xFF", 3);
+        assert_eq!(entries, [entry(0, "A"), entry(2, "B")]);
+        // So are entries past the last glyph.
+        let entries = corrupt_entries(PsfVersion::Psf2, b"A\xFFB\xFFC\xFF", 2);
+        assert_eq!(entries, [entry(0, "A"), entry(1, "B")]);
+        assert_eq!(corrupt_entries(PsfVersion::Psf2, b"", 1), []);
+
+        // An odd trailing byte can't be a terminator.
+        let entries = corrupt_entries(PsfVersion::Psf1, b"A\0\xFF\xFFB\0\xFF", 2);
+        assert_eq!(entries, [entry(0, "A")]);
+        // Neither can a terminator that isn't aligned to a character.
+        let entries = corrupt_entries(PsfVersion::Psf1, b"A\xFF\xFF\0", 1);
+        assert_eq!(entries, []);
+        // A surrogate isn't a character.
+        let entries = corrupt_entries(PsfVersion::Psf1, b"\0\xD8\xFF\xFFB\0\xFF\xFF", 2);
+        assert_eq!(entries, [entry(1, "B")]);
+        let entries = corrupt_entries(PsfVersion::Psf1, b"A\0\xFF\xFFB\0\xFF\xFF", 1);
+        assert_eq!(entries, [entry(0, "A")]);
+    }
+
+    #[test]
+    fn glyph_for_char_in_bundled_font() {
+        let font = PsfFile::parse(include_bytes!("../LatKaCyrHeb-14.psfu")).unwrap();
+        let table = font.build_char_table();
+        for ch in ['A', 'Ж'] {
+            let glyph = font.glyph_for_char(ch).unwrap();
+            assert!(glyph.bytes.iter().any(|&byte| byte != 0), "{ch:?} is blank");
+            let index = table.get(ch).unwrap();
+            assert_eq!(glyph.bytes, font.get_glyph(index).unwrap().bytes);
+        }
+        assert!(font.glyph_for_char('\u{10FFFF}').is_none());
+    }
+
+    #[test]
+    fn psf1_512_glyphs_with_sequences() {
+        // Glyph `i` is U+0100 + `i`, glyph 1 also has a sequence, and glyph 2 two of them.
+        let mut table = Vec::new();
+        for glyph in 0..512u16 {
+            table.push(0x100 + glyph);
+            match glyph {
+                1 => table.extend([0xFFFE, 'a' as u16, 0x300]),
+                2 => table.extend([0xFFFE, 'e' as u16, 0x301, 0xFFFE, 'o' as u16, 0x302]),
+                _ => {}
+            }
+            table.push(0xFFFF);
+        }
+        let mode = PSF1_MODE512 | PSF1_MODEHASTAB | PSF1_MODESEQ;
+        let bytes = psf1(mode, 512, &table);
+        let font = PsfFile::parse(&bytes).unwrap();
+        assert_eq!(font.num_glyphs(), 512);
+        assert_eq!(font.unicode_table_entries().count(), 515);
+
+        let table = font.build_char_table();
+        assert_eq!(table.get('\u{101}'), Some(1));
+        assert_eq!(table.get('\u{2FF}'), Some(511));
+        assert_eq!(table.get('a'), None);
+        assert_eq!(table.get_sequence("a\u{300}"), Some(1));
+        assert_eq!(table.get_sequence("e\u{301}"), Some(2));
+        assert_eq!(table.get_sequence("o\u{302}"), Some(2));
+        assert_eq!(table.longest_sequence(), 2);
+
+        assert_eq!(font.get_glyph(511).unwrap().bytes, [0xFF; 8]);
+        assert!(font.get_glyph(512).is_none());
+        assert_eq!(font.iter_glyphs().len(), 512);
+
+        // Without the 512 glyph bit, the table has more entries than glyphs.
+        let bytes = psf1(
+            PSF1_MODEHASTAB | PSF1_MODESEQ,
+            256,
+            &(0..512)
+                .flat_map(|glyph| [0x100 + glyph, 0xFFFF])
+                .collect::<Vec<u16>>(),
+        );
+        assert!(matches!(
+            PsfFile::parse(&bytes),
+            Err(Error::InvalidUnicodeTableSize {
+                num_glyphs: 256,
+                num_entries: 512
+            })
+        ));
     }
 }
@@ -64,6 +64,21 @@ impl Ucs2Str {
     pub fn as_bytes(&self) -> &[u8] {
         &self.0
     }
+
+    /// The character index of the first occurrence of `ch`.
+    pub fn find(&self, ch: char) -> Option<usize> {
+        self.chars().position(|c| c == ch)
+    }
+
+    /// Splits the string at the character index `mid`, panicking if it's past the end.
+    pub fn split_at(&self, mid: usize) -> (&Self, &Self) {
+        (&self[..mid], &self[mid..])
+    }
+
+    pub fn starts_with(&self, prefix: &str) -> bool {
+        let mut chars = self.chars();
+        prefix.chars().all(|p| chars.next() == Some(p))
+    }
 }
 
 impl PartialEq<str> for Ucs2Str {
@@ -98,18 +113,30 @@ impl From<&Ucs2Str> for String {
 impl<R: ops::RangeBounds<usize>> ops::Index<R> for Ucs2Str {
     type Output = Self;
 
+    /// Indices are in characters, and panic like `str` slicing when they're out of range.
     fn index(&self, range: R) -> &Self {
         let start = match range.start_bound() {
-            ops::Bound::Included(start) => 2 * start,
-            ops::Bound::Excluded(start) => 2 * (start + 1),
+            ops::Bound::Included(&start) => start,
+            ops::Bound::Excluded(&start) => start
+                .checked_add(1)
+                .expect("Ucs2Str index start overflowed"),
             ops::Bound::Unbounded => 0,
         };
         let end = match range.end_bound() {
-            ops::Bound::Included(_) => todo!(),
-            ops::Bound::Excluded(_) => todo!(),
-            ops::Bound::Unbounded => self.0.len(),
+            ops::Bound::Included(&end) => end.checked_add(1).expect("Ucs2Str index end overflowed"),
+            ops::Bound::Excluded(&end) => end,
+            ops::Bound::Unbounded => self.len(),
         };
-        unsafe { mem::transmute(&self.0[start..end]) }
+        assert!(
+            start <= end,
+            "Ucs2Str index starts at {start} but ends at {end}",
+        );
+        assert!(
+            end <= self.len(),
+            "Ucs2Str index end {end} is out of range for a string of length {}",
+            self.len(),
+        );
+        unsafe { mem::transmute(&self.0[2 * start..2 * end]) }
     }
 }
 
@@ -140,3 +167,96 @@ impl fmt::Debug for Ucs2Str {
         f.debug_tuple("Ucs2Str").field(&Adapter(self)).finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::*;
+
+    /// "añ€bc" in UCS-2 LE.
+    const BYTES: &[u8] = b"a\0\xF1\0\xAC\x20b\0c\0";
+
+    fn s() -> &'static Ucs2Str {
+        Ucs2Str::from_bytes(BYTES).unwrap()
+    }
+
+    fn chars(s: &Ucs2Str) -> Vec<char> {
+        s.chars().collect()
+    }
+
+    #[test]
+    fn index_ranges_match_chars() {
+        let all = chars(s());
+        assert_eq!(all, ['a', 'ñ', '€', 'b', 'c']);
+        for a in 0..=all.len() {
+            for b in a..=all.len() {
+                assert_eq!(chars(&s()[a..b]), all[a..b], "{a}..{b}");
+                assert_eq!(chars(&s()[..b]), all[..b], "..{b}");
+                if b < all.len() {
+                    assert_eq!(chars(&s()[a..=b]), all[a..=b], "{a}..={b}");
+                }
+            }
+            assert_eq!(chars(&s()[a..]), all[a..], "{a}..");
+        }
+        assert_eq!(&s()[1..3], "ñ€");
+        assert_eq!(ops::Index::index(s(), ..), s());
+    }
+
+    #[test]
+    #[should_panic = "out of range"]
+    fn index_end_past_len_panics() {
+        let _ = &s()[2..6];
+    }
+
+    #[test]
+    #[should_panic = "out of range"]
+    fn index_inclusive_end_at_len_panics() {
+        let _ = &s()[..=5];
+    }
+
+    #[test]
+    #[should_panic = "starts at 3 but ends at 2"]
+    fn index_start_past_end_panics() {
+        #[allow(clippy::reversed_empty_ranges)]
+        let _ = &s()[3..2];
+    }
+
+    #[test]
+    fn find() {
+        assert_eq!(s().find('a'), Some(0));
+        assert_eq!(s().find('€'), Some(2));
+        assert_eq!(s().find('c'), Some(4));
+        assert_eq!(s().find('x'), None);
+        assert_eq!(Ucs2Str::EMPTY.find('a'), None);
+    }
+
+    #[test]
+    fn split_at() {
+        for mid in 0..=s().len() {
+            let (head, tail) = s().split_at(mid);
+            assert_eq!(head, &s()[..mid]);
+            assert_eq!(tail, &s()[mid..]);
+        }
+        let (head, tail) = Ucs2Str::EMPTY.split_at(0);
+        assert_eq!((head.len(), tail.len()), (0, 0));
+    }
+
+    #[test]
+    #[should_panic = "out of range"]
+    fn split_at_past_len_panics() {
+        let _ = s().split_at(6);
+    }
+
+    #[test]
+    fn starts_with() {
+        assert!(s().starts_with(""));
+        assert!(s().starts_with("a"));
+        assert!(s().starts_with("añ€"));
+        assert!(s().starts_with("añ€bc"));
+        assert!(!s().starts_with("añ€bcd"));
+        assert!(!s().starts_with("b"));
+        assert!(Ucs2Str::EMPTY.starts_with(""));
+        assert!(!Ucs2Str::EMPTY.starts_with("a"));
+    }
+}
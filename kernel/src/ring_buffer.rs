@@ -0,0 +1,129 @@
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// A lock-free queue of up to `N` `u32`s, with one producer and one consumer, like an interrupt
+/// handler and the code polling for what it received.
+pub struct RingBuffer<const N: usize> {
+    values: [AtomicU32; N],
+    /// Where the next value is read from.
+    head: AtomicUsize,
+    /// Where the next value is written to.
+    tail: AtomicUsize,
+}
+
+impl<const N: usize> RingBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            values: [const { AtomicU32::new(0) }; N],
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+        }
+    }
+
+    /// Pushes `value`, or returns false if the buffer is full.
+    pub fn push(&self, value: u32) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == N {
+            return false;
+        }
+        self.values[tail % N].store(value, Ordering::Relaxed);
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    pub fn pop(&self) -> Option<u32> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = self.values[head % N].load(Ordering::Relaxed);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+}
+
+impl<const N: usize> Default for RingBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn full_and_empty() {
+        let buffer = RingBuffer::<4>::new();
+        assert_eq!(buffer.pop(), None);
+        for value in 0..4 {
+            assert!(buffer.push(value));
+        }
+        // A push to a full buffer is dropped.
+        assert!(!buffer.push(4));
+        for value in 0..4 {
+            assert_eq!(buffer.pop(), Some(value));
+        }
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn wraps_around() {
+        let buffer = RingBuffer::<3>::new();
+        let mut next = 0;
+        for round in 0..20 {
+            let pushes = round % 3 + 1;
+            for i in 0..pushes {
+                assert!(buffer.push(next + i));
+            }
+            for i in 0..pushes {
+                assert_eq!(buffer.pop(), Some(next + i));
+            }
+            next += pushes;
+        }
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn indices_wrap_around() {
+        let buffer = RingBuffer::<4>::new();
+        buffer.head.store(usize::MAX - 1, Ordering::Relaxed);
+        buffer.tail.store(usize::MAX - 1, Ordering::Relaxed);
+        for value in 0..4 {
+            assert!(buffer.push(value));
+        }
+        assert!(!buffer.push(4));
+        for value in 0..4 {
+            assert_eq!(buffer.pop(), Some(value));
+            assert!(buffer.push(value + 10));
+        }
+        for value in 0..4 {
+            assert_eq!(buffer.pop(), Some(value + 10));
+        }
+        assert_eq!(buffer.pop(), None);
+    }
+
+    #[test]
+    fn producer_and_consumer_threads() {
+        const COUNT: u32 = 10_000;
+        let buffer = RingBuffer::<16>::new();
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for value in 0..COUNT {
+                    while !buffer.push(value) {
+                        std::thread::yield_now();
+                    }
+                }
+            });
+            for value in 0..COUNT {
+                let popped = loop {
+                    match buffer.pop() {
+                        Some(popped) => break popped,
+                        None => std::thread::yield_now(),
+                    }
+                };
+                assert_eq!(popped, value);
+            }
+        });
+        assert_eq!(buffer.pop(), None);
+    }
+}
@@ -0,0 +1,185 @@
+//! Checks that need the real machine (page tables, MSRs, interrupts), run at boot instead of the
+//! shell when the kernel is built with the `self_test` feature. They end QEMU through
+//! [`power::exit_qemu`], so the runner's exit status is the result. The pure code has host unit
+//! tests instead.
+
+use alloc::{alloc::Layout, vec::Vec};
+
+use x86_64::{
+    instructions::interrupts::without_interrupts, registers::model_specific::GsBase,
+    structures::paging::PageTableFlags, PhysAddr, VirtAddr,
+};
+
+use crate::{
+    interrupts,
+    memory::{
+        self,
+        vmm::{MappedPageSize, MappingFlags},
+    },
+    percpu, power,
+};
+
+const TESTS: &[(&str, fn())] = &[
+    ("address_space_switch", address_space_switch),
+    ("percpu_gs_base", percpu_gs_base),
+    ("heap_demand_paging", heap_demand_paging),
+    ("heap_alloc_zeroed_huge", heap_alloc_zeroed_huge),
+    ("protect_part_of_huge_page", protect_part_of_huge_page),
+    ("protect_part_of_1gib_page", protect_part_of_1gib_page),
+];
+
+/// Runs every test and exits QEMU, with failure if one of them panics.
+pub fn run() -> ! {
+    power::set_panic_action(power::PanicAction::QemuExit(power::QEMU_EXIT_FAILURE));
+    for (name, test) in TESTS {
+        log::info!("Self test {name}...");
+        test();
+        log::info!("Self test {name}: ok");
+    }
+    log::info!("All {} self tests passed", TESTS.len());
+    power::exit_qemu(power::QEMU_EXIT_SUCCESS);
+}
+
+/// Switches to a new address space and back, checking that the kernel's code, statics and stack
+/// translate the same way in it.
+fn address_space_switch() {
+    let stack_marker = 0u8;
+    let addrs = [
+        VirtAddr::from_ptr(address_space_switch as *const ()),
+        VirtAddr::from_ptr(&memory::VMM),
+        VirtAddr::from_ptr(&stack_marker),
+    ];
+    without_interrupts(|| {
+        let mut vmm = memory::VMM.get().unwrap().lock();
+        let translate = |vmm: &memory::vmm::VirtualMemoryManager, addr| {
+            let mapping = vmm.query(addr).expect("kernel address isn't mapped");
+            assert!(mapping.kernel, "{addr:?} is in the lower half");
+            mapping.phys_addr(addr)
+        };
+        let before: Vec<_> = addrs.iter().map(|&addr| translate(&vmm, addr)).collect();
+        let (_, total_bytes) = vmm.phys_memory();
+
+        let space = vmm.new_address_space().expect("out of memory");
+        let pml4 = space.pml4();
+        let old = unsafe { vmm.switch_to(space) };
+        assert_eq!(x86_64::registers::control::Cr3::read().0, pml4);
+        let after: Vec<_> = addrs.iter().map(|&addr| translate(&vmm, addr)).collect();
+        let space = unsafe { vmm.switch_to(old) };
+        unsafe { vmm.free_address_space(space) };
+
+        assert_eq!(before, after);
+        // The freed tables go back to the frame allocator, rather than being added as new memory.
+        assert_eq!(vmm.phys_memory().1, total_bytes);
+    });
+}
+
+/// Reads the bootstrap CPU's block back through the GS base.
+fn percpu_gs_base() {
+    let cpu = percpu::this_cpu().expect("per-CPU data isn't initialized");
+    assert_eq!(VirtAddr::from_ptr(cpu), GsBase::read());
+    assert_eq!(cpu.cpu_id, 0);
+    assert_eq!(cpu.lapic_id, interrupts::lapic_id());
+}
+
+/// Reserves a heap allocation, and checks that touching its pages maps them through the page fault
+/// handler.
+fn heap_demand_paging() {
+    let layout = Layout::from_size_align(3 << 20 | 0x1000, 8).unwrap();
+    let ptr = memory::malloc::ALLOC.alloc_reserved(layout);
+    assert!(!ptr.is_null(), "out of memory");
+    let query = |offset: usize| {
+        let addr = VirtAddr::from_ptr(ptr) + offset as u64;
+        without_interrupts(|| memory::VMM.get().unwrap().lock().query(addr))
+    };
+
+    let last = layout.size() - 1;
+    assert!(query(0).is_none() && query(last).is_none());
+    for offset in [0, last] {
+        assert_eq!(unsafe { ptr.add(offset).read_volatile() }, 0);
+        unsafe { ptr.add(offset).write_volatile(0x5A) };
+        assert!(
+            query(offset).is_some(),
+            "offset {offset:#x} wasn't committed"
+        );
+        assert_eq!(unsafe { ptr.add(offset).read_volatile() }, 0x5A);
+    }
+    // The pages in between are still untouched.
+    assert!(query(1 << 20).is_none());
+    unsafe { memory::malloc::ALLOC.dealloc_reserved(ptr) };
+    assert!(query(0).is_none());
+}
+
+/// A huge zeroed allocation is mapped up front, and a cached one is zeroed again when it's reused.
+fn heap_alloc_zeroed_huge() {
+    let layout = Layout::from_size_align(2 << 20 | 0x3000, 8).unwrap();
+    let ptr = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    assert!(!ptr.is_null(), "out of memory");
+    let addr = VirtAddr::from_ptr(ptr);
+    let mapped = || without_interrupts(|| memory::VMM.get().unwrap().lock().query(addr).is_some());
+    assert!(mapped());
+
+    let bytes = unsafe { core::slice::from_raw_parts_mut(ptr, layout.size()) };
+    assert!(bytes.iter().all(|&byte| byte == 0));
+    bytes.fill(0xA5);
+    unsafe { alloc::alloc::dealloc(ptr, layout) };
+
+    let reused = unsafe { alloc::alloc::alloc_zeroed(layout) };
+    assert_eq!(reused, ptr, "the allocation wasn't cached");
+    let bytes = unsafe { core::slice::from_raw_parts(reused, layout.size()) };
+    assert!(bytes.iter().all(|&byte| byte == 0));
+    unsafe { alloc::alloc::dealloc(reused, layout) };
+}
+
+/// Makes a 4 KiB page in the middle of a 2 MiB page read-only, which splits only that 2 MiB page.
+fn protect_part_of_huge_page() {
+    without_interrupts(|| {
+        let mut vmm = memory::VMM.get().unwrap().lock();
+        let addr = (vmm.alloc(true, 4 << 20, 21, MappingFlags::DATA)).expect("out of memory");
+        let mapping = |vmm: &memory::vmm::VirtualMemoryManager, offset: u64| {
+            vmm.query(addr + offset)
+                .expect("the allocation isn't mapped")
+        };
+        assert_eq!(mapping(&vmm, 0).page_size, MappedPageSize::Size2MiB);
+
+        vmm.protect(addr + 0x3000u64, 0x1000, MappingFlags::empty())
+            .unwrap();
+        let protected = mapping(&vmm, 0x3000);
+        assert_eq!(protected.page_size, MappedPageSize::Size4KiB);
+        assert!(!protected.flags.contains(PageTableFlags::WRITABLE));
+        for offset in [0x2000, 0x4000] {
+            let neighbour = mapping(&vmm, offset);
+            assert_eq!(neighbour.page_size, MappedPageSize::Size4KiB);
+            assert!(neighbour.flags.contains(PageTableFlags::WRITABLE));
+        }
+        assert_eq!(mapping(&vmm, 2 << 20).page_size, MappedPageSize::Size2MiB);
+        assert_eq!(
+            mapping(&vmm, 0x3000).phys_addr(addr + 0x3000u64),
+            mapping(&vmm, 0).frame + 0x3000u64,
+        );
+
+        unsafe { vmm.free(addr, 4 << 20) };
+    });
+}
+
+/// Protecting part of a 1 GiB page fails without changing it. The physical memory window is the
+/// only place with 1 GiB pages, when the CPU supports them.
+fn protect_part_of_1gib_page() {
+    let addr = memory::phys_to_virt(PhysAddr::new(0), 0);
+    without_interrupts(|| {
+        let mut vmm = memory::VMM.get().unwrap().lock();
+        let Some(before) = vmm.query(addr) else {
+            log::warn!("Physical memory window isn't mapped at {addr:?}, skipping");
+            return;
+        };
+        if before.page_size != MappedPageSize::Size1GiB {
+            log::warn!("No 1 GiB pages, skipping");
+            return;
+        }
+        let result = vmm.protect(addr + 0x1000u64, 0x1000, MappingFlags::empty());
+        assert_eq!(result, Err(addr + 0x1000u64));
+        assert_eq!(
+            vmm.query(addr).map(|mapping| mapping.flags),
+            Some(before.flags)
+        );
+    });
+}
@@ -0,0 +1,75 @@
+//! A tiny interactive shell, reading from the keyboard and the serial port and printing to both
+//! outputs.
+
+use alloc::string::String;
+
+use x86_64::instructions::interrupts::{self, without_interrupts};
+
+use crate::{keyboard, memory, output, power, print, println, time};
+
+const PROMPT: &str = "> ";
+
+/// Waits for a character from the keyboard or the serial port, halting until an interrupt while
+/// there's none.
+fn read_char() -> char {
+    loop {
+        interrupts::disable();
+        let ch = keyboard::read_char().or_else(|| output::serial::read_byte().map(char::from));
+        if let Some(ch) = ch {
+            interrupts::enable();
+            return ch;
+        }
+        // Interrupts are only enabled once `hlt` runs, so one can't arrive between the check and
+        // the halt.
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Reads a line, echoing it back. Backspace erases the last character.
+pub fn read_line() -> String {
+    let mut line = String::new();
+    loop {
+        match read_char() {
+            '\r' | '\n' => {
+                println!();
+                return line;
+            }
+            '\x08' | '\x7F' => {
+                if line.pop().is_some() {
+                    print!("\x08 \x08");
+                }
+            }
+            ch @ ' '..='~' => {
+                line.push(ch);
+                print!("{ch}");
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Runs the shell forever.
+pub fn run() -> ! {
+    println!("Type `help` for a list of commands");
+    loop {
+        print!("{PROMPT}");
+        match read_line().trim() {
+            "" => {}
+            "help" => println!("Commands: help, mem, clear, uptime, reboot, shutdown"),
+            "mem" => println!("Free heap: {} bytes", memory::free_heap_bytes()),
+            "clear" => without_interrupts(|| {
+                if let Some(console) = output::console::CONSOLE.lock().as_mut() {
+                    console.clear();
+                    console.present();
+                }
+            }),
+            "uptime" => {
+                let ms = time::now_ms();
+                println!("Up for {}.{:03}s", ms / 1000, ms % 1000);
+            }
+            "reboot" => power::reboot(),
+            "shutdown" => power::shutdown(),
+            cmd => println!("Unknown command `{cmd}`, try `help`"),
+        }
+    }
+}
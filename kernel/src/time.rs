@@ -0,0 +1,126 @@
+//! The kernel's monotonic clock. [`init`] picks the best available time source, and everything that
+//! needs the time should go through [`now_ns`]/[`now_ms`]. The time sources are the HPET, the
+//! invariant TSC, the local APIC timer and the PIT, in that order.
+
+use core::arch::x86_64::_rdtsc;
+
+use raw_cpuid::CpuId;
+
+/// The time sources, from best to worst.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeSource {
+    /// The HPET's main counter, found through ACPI.
+    Hpet,
+    /// The invariant Time Stamp Counter, which ticks at a constant rate.
+    Tsc,
+    /// The local APIC timer's interrupts, counted by [`crate::interrupts::timer_ticks`]. The time
+    /// stays at zero until [`crate::interrupts::init_apic`] starts the timer.
+    ApicTimer,
+    /// Channel 0 of the PIT, counted by [`crate::pit::ticks`]. Only picked without a local APIC,
+    /// and it loses time if it isn't read at least every ~54.9 ms.
+    Pit,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Clock {
+    source: TimeSource,
+    /// Frequency of the counter in Hz.
+    frequency: u64,
+    /// Counter value at `init`.
+    start: u64,
+}
+
+impl Clock {
+    fn read_counter(&self) -> u64 {
+        match self.source {
+            TimeSource::Hpet => crate::hpet::counter(),
+            TimeSource::Tsc => unsafe { _rdtsc() },
+            TimeSource::ApicTimer => crate::interrupts::timer_ticks(),
+            TimeSource::Pit => crate::pit::ticks(),
+        }
+    }
+}
+
+static CLOCK: spin::Once<Clock> = spin::Once::new();
+/// Frequency of the TSC in Hz, or `None` if it isn't invariant.
+static TSC_FREQUENCY: spin::Once<Option<u64>> = spin::Once::new();
+
+/// Frequency of the TSC in Hz, if the CPU reports it.
+fn cpuid_tsc_frequency() -> Option<u64> {
+    let cpuid = CpuId::new();
+    if let Some(freq) = cpuid.get_tsc_info().and_then(|info| info.tsc_frequency()) {
+        return Some(freq);
+    }
+    // The processor base frequency is the TSC frequency on CPUs with an invariant TSC.
+    let base_mhz = cpuid
+        .get_processor_frequency_info()?
+        .processor_base_frequency();
+    (base_mhz != 0).then_some(base_mhz as u64 * 1_000_000)
+}
+
+/// Measures the TSC frequency in Hz against the PIT.
+fn calibrate_tsc() -> u64 {
+    const CALIBRATION_MS: u16 = 50;
+    let start = unsafe { _rdtsc() };
+    crate::pit::sleep_pit_ms(CALIBRATION_MS);
+    let ticks = unsafe { _rdtsc() } - start;
+    ticks * 1000 / CALIBRATION_MS as u64
+}
+
+/// Picks the best available time source: the HPET, the invariant TSC (calibrated against the PIT if
+/// the CPU doesn't report its frequency), the APIC timer's ticks, and otherwise the PIT. Needs ACPI
+/// to find the HPET.
+pub fn init() {
+    let tsc_frequency = *TSC_FREQUENCY.call_once(|| {
+        let has_invariant_tsc = (CpuId::new().get_advanced_power_mgmt_info())
+            .is_some_and(|info| info.has_invariant_tsc());
+        has_invariant_tsc.then(|| cpuid_tsc_frequency().unwrap_or_else(calibrate_tsc))
+    });
+    let clock = CLOCK.call_once(|| {
+        let has_apic = (CpuId::new().get_feature_info()).is_some_and(|info| info.has_apic());
+        let (source, frequency) = match (crate::hpet::init(), tsc_frequency) {
+            (Some(frequency), _) => (TimeSource::Hpet, frequency),
+            (None, Some(frequency)) => (TimeSource::Tsc, frequency),
+            _ if has_apic => (
+                TimeSource::ApicTimer,
+                1000 / crate::interrupts::APIC_TIMER_PERIOD_MS,
+            ),
+            _ => {
+                crate::pit::start_counter();
+                (TimeSource::Pit, crate::pit::PIT_FREQUENCY)
+            }
+        };
+        let mut clock = Clock {
+            source,
+            frequency,
+            start: 0,
+        };
+        clock.start = clock.read_counter();
+        clock
+    });
+    log::info!("Time source: {:?} at {} Hz", clock.source, clock.frequency);
+}
+
+/// The time source picked by [`init`].
+pub fn source() -> Option<TimeSource> {
+    Some(CLOCK.get()?.source)
+}
+
+/// Frequency of the TSC in Hz, if it's invariant.
+pub fn tsc_frequency() -> Option<u64> {
+    *TSC_FREQUENCY.get()?
+}
+
+/// Nanoseconds since [`init`], or zero before it.
+pub fn now_ns() -> u64 {
+    let Some(clock) = CLOCK.get() else {
+        return 0;
+    };
+    let ticks = clock.read_counter().wrapping_sub(clock.start);
+    (ticks as u128 * 1_000_000_000 / clock.frequency as u128) as u64
+}
+
+/// Milliseconds since [`init`], or zero before it.
+pub fn now_ms() -> u64 {
+    now_ns() / 1_000_000
+}
@@ -1,15 +1,149 @@
-use std::{fs, path::PathBuf};
+use std::{env, fs, path::PathBuf};
 
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use time::{macros::format_description, OffsetDateTime};
 
+/// The code the kernel passes to `power::exit_qemu` on success, `kernel::power::QEMU_EXIT_SUCCESS`.
+const QEMU_EXIT_SUCCESS: i32 = 0x10;
+
+const DEFAULT_OVMF_CODE: &str = "/usr/share/OVMF/OVMF_CODE.fd";
+const DEFAULT_OVMF_VARS: &str = "/usr/share/OVMF/OVMF_VARS.fd";
+
+const USAGE: &str = "\
+usage: runner [--uefi | --bios] [--ovmf-code PATH] [--ovmf-vars PATH] [--gdb] [--mem SIZE]
+              [--no-kvm] [-- QEMU_ARGS...]
+
+  --uefi             boot the UEFI image (the default)
+  --bios             boot the BIOS image
+  --gdb              freeze the CPU at startup until a debugger attaches to localhost:1234
+  --mem SIZE         the guest's memory size, in QEMU's format (default 8G)
+  --no-kvm           don't use KVM acceleration
+  --ovmf-code PATH   the OVMF code image, else $OVMF_CODE, else /usr/share/OVMF/OVMF_CODE.fd
+  --ovmf-vars PATH   the OVMF vars image, else $OVMF_VARS, else /usr/share/OVMF/OVMF_VARS.fd
+  -- QEMU_ARGS...    passed to QEMU as is";
+
+/// The runner's command line.
+#[derive(Debug)]
+struct Options {
+    /// Whether to start the UEFI or BIOS image.
+    uefi: bool,
+    ovmf_code: String,
+    ovmf_vars: String,
+    /// Whether to wait for a debugger before starting.
+    gdb: bool,
+    mem: String,
+    kvm: bool,
+    /// Extra arguments for QEMU.
+    qemu_args: Vec<String>,
+}
+
+/// What the command line asks for.
+#[derive(Debug)]
+enum Command {
+    Run(Options),
+    /// Print the usage.
+    Help,
+}
+
+impl Options {
+    /// Parses the arguments, without the program name.
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Command> {
+        let mut args = args.into_iter();
+        let mut uefi = true;
+        let mut ovmf_code = None;
+        let mut ovmf_vars = None;
+        let mut gdb = false;
+        let mut mem = "8G".into();
+        let mut kvm = true;
+        let mut qemu_args = Vec::new();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--uefi" => uefi = true,
+                "--bios" => uefi = false,
+                "--ovmf-code" => ovmf_code = Some(args.next().context("--ovmf-code needs a path")?),
+                "--ovmf-vars" => ovmf_vars = Some(args.next().context("--ovmf-vars needs a path")?),
+                "--gdb" => gdb = true,
+                "--mem" => mem = args.next().context("--mem needs a size")?,
+                "--no-kvm" => kvm = false,
+                "--" => qemu_args.extend(args.by_ref()),
+                "-h" | "--help" => return Ok(Command::Help),
+                _ => bail!("unknown argument {arg:?}\n{USAGE}"),
+            }
+        }
+        let or_env = |path: Option<String>, var, default: &str| {
+            path.or_else(|| env::var(var).ok())
+                .unwrap_or_else(|| default.into())
+        };
+        Ok(Command::Run(Self {
+            uefi,
+            ovmf_code: or_env(ovmf_code, "OVMF_CODE", DEFAULT_OVMF_CODE),
+            ovmf_vars: or_env(ovmf_vars, "OVMF_VARS", DEFAULT_OVMF_VARS),
+            gdb,
+            mem,
+            kvm,
+            qemu_args,
+        }))
+    }
+
+    /// QEMU's arguments, for booting from the disk images and logging the serial port to
+    /// `log_file`.
+    fn qemu_args(&self, uefi_path: &str, bios_path: &str, log_file: &str) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.kvm {
+            args.push("-enable-kvm".into());
+        }
+        // `-s` starts a gdbstub on localhost:1234, and `-S` waits for it to continue.
+        args.push("-s".into());
+        if self.gdb {
+            args.push("-S".into());
+        }
+        args.extend(["-m".into(), self.mem.clone()]);
+        args.extend(["-serial".into(), format!("file:{log_file}")]);
+        // Lets the kernel exit QEMU with a status, see `exit_code`.
+        args.extend([
+            "-device".into(),
+            "isa-debug-exit,iobase=0xf4,iosize=0x04".into(),
+        ]);
+        if self.uefi {
+            args.extend([
+                "-drive".into(),
+                format!("format=raw,file={uefi_path}"),
+                "-drive".into(),
+                format!("if=pflash,format=raw,readonly=on,file={}", self.ovmf_code),
+                "-drive".into(),
+                format!("if=pflash,format=raw,readonly=on,file={}", self.ovmf_vars),
+            ]);
+        } else {
+            args.extend(["-drive".into(), format!("format=raw,file={bios_path}")]);
+        }
+        args.extend(self.qemu_args.iter().cloned());
+        args
+    }
+}
+
+/// The runner's exit code for QEMU's exit `status`: 0 only if the kernel exited QEMU with
+/// `QEMU_EXIT_SUCCESS`, which makes QEMU exit with `code << 1 | 1`, and otherwise the kernel's code,
+/// or 1 if QEMU exited on its own.
+fn exit_code(status: Option<i32>) -> i32 {
+    match status {
+        Some(status) if status == (QEMU_EXIT_SUCCESS << 1 | 1) => 0,
+        Some(status) if status & 1 == 1 && 1 < status => status >> 1,
+        _ => 1,
+    }
+}
+
 fn main() -> Result<()> {
     // read env variables that were set in build script
     let uefi_path = env!("UEFI_PATH");
     let bios_path = env!("BIOS_PATH");
 
-    // choose whether to start the UEFI or BIOS image
-    let uefi = true;
+    let options = match Options::parse(env::args().skip(1))? {
+        Command::Run(options) => options,
+        Command::Help => {
+            println!("{USAGE}");
+            return Ok(());
+        }
+    };
 
     let log_file = PathBuf::from(OffsetDateTime::now_local()?.format(format_description!(
         "logs/[year]-[month]-[day]/\
@@ -30,23 +164,107 @@ fn main() -> Result<()> {
     std::os::unix::fs::symlink(log_file.strip_prefix("logs/")?, "logs/last.log")?;
 
     let mut cmd = std::process::Command::new("qemu-system-x86_64");
-    cmd.args(["-enable-kvm", "-s", "-m", "8G"]);
-    cmd.args(["-serial", &format!("file:{}", log_file.display())]);
-
-    if uefi {
-        cmd.args([
-            "-drive",
-            &format!("format=raw,file={uefi_path}"),
-            "-drive",
-            "if=pflash,format=raw,readonly=on,file=/usr/share/OVMF/OVMF_CODE.fd",
-            "-drive",
-            "if=pflash,format=raw,readonly=on,file=/usr/share/OVMF/OVMF_VARS.fd",
-        ]);
-    } else {
-        cmd.args(["-drive", &format!("format=raw,file={bios_path}")]);
-    }
+    cmd.args(options.qemu_args(uefi_path, bios_path, &log_file.display().to_string()));
     let mut child = cmd.spawn()?;
-    child.wait()?;
+    let status = child.wait()?;
+
+    std::process::exit(exit_code(status.code()));
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command> {
+        Options::parse(args.iter().map(|&arg| arg.into()))
+    }
+
+    /// QEMU's arguments for `args`, with the OVMF images given so the environment doesn't matter.
+    fn qemu_args(args: &[&str]) -> Vec<String> {
+        let ovmf = ["--ovmf-code", "code.fd", "--ovmf-vars", "vars.fd"];
+        let args = [&ovmf[..], args].concat();
+        match parse(&args).unwrap() {
+            Command::Run(options) => options.qemu_args("uefi.img", "bios.img", "serial.log"),
+            Command::Help => panic!("{args:?} asks for help"),
+        }
+    }
+
+    #[test]
+    fn argv_to_qemu_args() {
+        assert_eq!(
+            qemu_args(&[]),
+            [
+                "-enable-kvm",
+                "-s",
+                "-m",
+                "8G",
+                "-serial",
+                "file:serial.log",
+                "-device",
+                "isa-debug-exit,iobase=0xf4,iosize=0x04",
+                "-drive",
+                "format=raw,file=uefi.img",
+                "-drive",
+                "if=pflash,format=raw,readonly=on,file=code.fd",
+                "-drive",
+                "if=pflash,format=raw,readonly=on,file=vars.fd",
+            ]
+        );
+        assert_eq!(
+            qemu_args(&["--bios", "--", "-display", "none", "--gdb"]),
+            [
+                "-enable-kvm",
+                "-s",
+                "-m",
+                "8G",
+                "-serial",
+                "file:serial.log",
+                "-device",
+                "isa-debug-exit,iobase=0xf4,iosize=0x04",
+                "-drive",
+                "format=raw,file=bios.img",
+                "-display",
+                "none",
+                "--gdb",
+            ]
+        );
+    }
+
+    #[test]
+    fn help_and_bad_arguments() {
+        assert!(matches!(parse(&["--help"]), Ok(Command::Help)));
+        assert!(matches!(parse(&["--bios", "-h"]), Ok(Command::Help)));
+        assert!(parse(&["--mem"]).is_err());
+        assert!(parse(&["--ovmf-code"]).is_err());
+        assert!(parse(&["--frobnicate"]).is_err());
+    }
+
+    #[test]
+    fn gdb_mem_and_kvm_options() {
+        let default = qemu_args(&[]);
+        assert!(!default.iter().any(|arg| arg == "-S"));
+        assert!(default.contains(&"-enable-kvm".into()));
+
+        let gdb = qemu_args(&["--gdb"]);
+        assert_eq!(gdb.iter().filter(|&arg| arg == "-S").count(), 1);
+        assert!(gdb.contains(&"-s".into()));
+
+        let args = qemu_args(&["--mem", "512M", "--no-kvm"]);
+        let mem = args.iter().position(|arg| arg == "-m").unwrap();
+        assert_eq!(args[mem + 1], "512M");
+        assert!(!args.contains(&"-enable-kvm".into()));
+        assert!(!args.contains(&"-S".into()));
+    }
+
+    #[test]
+    fn exit_codes() {
+        assert_eq!(exit_code(Some(0x21)), 0);
+        assert_eq!(exit_code(Some(0x23)), 0x11);
+        assert_eq!(exit_code(Some(3)), 1);
+        // QEMU's own exits, and being killed by a signal.
+        assert_eq!(exit_code(Some(1)), 1);
+        assert_eq!(exit_code(Some(0)), 1);
+        assert_eq!(exit_code(Some(2)), 1);
+        assert_eq!(exit_code(None), 1);
+    }
 }
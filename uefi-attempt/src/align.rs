@@ -1,13 +1,29 @@
 use core::{
     borrow::{Borrow, BorrowMut},
-    ops,
+    fmt, mem, ops,
 };
 
+mod sealed {
+    pub trait Sealed {}
+}
+
+/// The zero sized alignment markers, `Align2` to `Align4096` without a value, which give
+/// [`Aligned`] their alignment.
+pub trait Alignment: sealed::Sealed + Copy + Default {
+    const ALIGN: usize;
+}
+
 macro_rules! def_align {
     ($name:ident, $make:ident, $align:literal) => {
         #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Hash)]
         #[repr(C, align($align))]
-        pub struct $name<T>(pub T);
+        pub struct $name<T = ()>(pub T);
+
+        impl sealed::Sealed for $name {}
+
+        impl Alignment for $name {
+            const ALIGN: usize = $align;
+        }
 
         #[inline]
         #[must_use]
@@ -41,6 +57,21 @@ macro_rules! def_align {
             }
         }
 
+        impl<const N: usize> $name<[u8; N]> {
+            /// The buffer as a byte slice, without going through `Deref`.
+            #[inline]
+            #[must_use]
+            pub const fn as_byte_slice(&self) -> &[u8] {
+                &self.0
+            }
+
+            #[inline]
+            #[must_use]
+            pub const fn as_byte_slice_mut(&mut self) -> &mut [u8] {
+                &mut self.0
+            }
+        }
+
         impl<T> From<T> for $name<T> {
             #[inline]
             #[must_use]
@@ -114,6 +145,120 @@ def_align!(Align1024, align1024, 1024);
 def_align!(Align2048, align2048, 2048);
 def_align!(Align4096, align4096, 4096);
 
+/// `T` aligned to `A`, like the `AlignN` wrappers but with the alignment as a parameter.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Hash)]
+#[repr(C)]
+pub struct Aligned<T, A: Alignment> {
+    _align: [A; 0],
+    value: T,
+}
+
+const _: () = assert!(mem::align_of::<Aligned<u8, Align64>>() == 64);
+const _: () = assert!(mem::size_of::<Aligned<u8, Align64>>() == 64);
+
+impl<T, A: Alignment> Aligned<T, A> {
+    pub const ALIGN: usize = A::ALIGN;
+
+    #[inline]
+    #[must_use]
+    pub const fn new(value: T) -> Self {
+        Self { _align: [], value }
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn into_inner(this: Self) -> T {
+        this.value
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn inner(&self) -> &T {
+        &self.value
+    }
+
+    #[inline]
+    #[must_use]
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<const N: usize, A: Alignment> Aligned<[u8; N], A> {
+    /// The buffer as a byte slice, without going through `Deref`.
+    #[inline]
+    #[must_use]
+    pub const fn as_byte_slice(&self) -> &[u8] {
+        &self.value
+    }
+
+    #[inline]
+    #[must_use]
+    pub const fn as_byte_slice_mut(&mut self) -> &mut [u8] {
+        &mut self.value
+    }
+}
+
+impl<T: fmt::Debug, A: Alignment> fmt::Debug for Aligned<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Aligned")
+            .field(&self.value)
+            .field(&A::ALIGN)
+            .finish()
+    }
+}
+
+impl<T, A: Alignment> From<T> for Aligned<T, A> {
+    #[inline]
+    fn from(value: T) -> Self {
+        Self::new(value)
+    }
+}
+
+impl<T, A: Alignment> ops::Deref for Aligned<T, A> {
+    type Target = T;
+
+    #[inline]
+    fn deref(&self) -> &T {
+        &self.value
+    }
+}
+
+impl<T, A: Alignment> ops::DerefMut for Aligned<T, A> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+}
+
+impl<T, A: Alignment> AsRef<T> for Aligned<T, A> {
+    #[inline]
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T, A: Alignment> AsMut<T> for Aligned<T, A> {
+    #[inline]
+    fn as_mut(&mut self) -> &mut T {
+        self
+    }
+}
+
+impl<T, A: Alignment> Borrow<T> for Aligned<T, A> {
+    #[inline]
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T, A: Alignment> BorrowMut<T> for Aligned<T, A> {
+    #[inline]
+    fn borrow_mut(&mut self) -> &mut T {
+        self
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default, Hash)]
 #[repr(C, align(8))]
 pub struct U32Pair(pub u32, pub u32);
@@ -121,3 +266,36 @@ pub struct U32Pair(pub u32, pub u32);
 pub const fn u32_pair(a: u32, b: u32) -> U32Pair {
     U32Pair(a, b)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn align_n_byte_slices() {
+        let mut buf = Align8([0u8; 12]);
+        assert_eq!(buf.as_byte_slice().len(), 12);
+        assert_eq!(buf.as_byte_slice().as_ptr() as usize % 8, 0);
+
+        buf.as_byte_slice_mut()[3] = 0xAB;
+        buf.as_byte_slice_mut()[11] = 0xCD;
+        assert_eq!(buf.0[3], 0xAB);
+        assert_eq!(buf.as_byte_slice()[11], 0xCD);
+        assert_eq!(buf.as_byte_slice().iter().filter(|&&b| b != 0).count(), 2);
+
+        assert!(Align4096([0u8; 0]).as_byte_slice().is_empty());
+    }
+
+    #[test]
+    fn aligned_byte_slices() {
+        let mut buf = Aligned::<[u8; 5], Align64>::new([1, 2, 3, 4, 5]);
+        assert_eq!(buf.as_byte_slice(), [1, 2, 3, 4, 5]);
+        assert_eq!(buf.as_byte_slice().as_ptr() as usize % 64, 0);
+
+        buf.as_byte_slice_mut().copy_from_slice(b"hello");
+        assert_eq!(Aligned::into_inner(buf), *b"hello");
+
+        let mut empty = Aligned::<[u8; 0], Align16>::new([]);
+        assert!(empty.as_byte_slice_mut().is_empty());
+    }
+}
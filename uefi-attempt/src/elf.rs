@@ -0,0 +1,314 @@
+//! Just enough of ELF64 to find a kernel's entry point and the segments to load.
+
+use core::fmt;
+
+const MAGIC: [u8; 4] = *b"\x7FELF";
+const CLASS_64: u8 = 2;
+const DATA_LITTLE_ENDIAN: u8 = 1;
+const VERSION_CURRENT: u8 = 1;
+const TYPE_EXEC: u16 = 2;
+const TYPE_DYN: u16 = 3;
+const MACHINE_X86_64: u16 = 0x3E;
+
+const HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+/// Program header types.
+pub const PT_LOAD: u32 = 1;
+
+/// Program header flags.
+pub const PF_X: u32 = 1 << 0;
+pub const PF_W: u32 = 1 << 1;
+pub const PF_R: u32 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    UnexpectedEnd,
+    InvalidMagic,
+    Not64Bit,
+    NotLittleEndian,
+    UnknownVersion(u8),
+    NotExecutable(u16),
+    NotX86_64(u16),
+    InvalidProgramHeaderSize(u16),
+    /// A segment's file bytes are past the end of the file or more than its memory size, or its
+    /// memory is past the end of the address space.
+    InvalidSegment(u16),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Error::UnexpectedEnd => write!(f, "ELF file ends unexpectedly"),
+            Error::InvalidMagic => write!(f, "Not an ELF file"),
+            Error::Not64Bit => write!(f, "ELF file isn't 64-bit"),
+            Error::NotLittleEndian => write!(f, "ELF file isn't little endian"),
+            Error::UnknownVersion(version) => write!(f, "Unknown ELF version {version}"),
+            Error::NotExecutable(kind) => write!(f, "ELF file of type {kind} isn't executable"),
+            Error::NotX86_64(machine) => {
+                write!(f, "ELF file is for machine {machine:#x}, not x86_64")
+            }
+            Error::InvalidProgramHeaderSize(size) => {
+                write!(f, "Invalid ELF program header size {size}")
+            }
+            Error::InvalidSegment(index) => write!(f, "ELF segment {index} is out of bounds"),
+        }
+    }
+}
+
+type Result<T, E = Error> = core::result::Result<T, E>;
+
+fn read<const N: usize>(bytes: &[u8], offset: usize) -> Result<[u8; N]> {
+    let bytes = bytes.get(offset..offset + N).ok_or(Error::UnexpectedEnd)?;
+    Ok(bytes.try_into().unwrap())
+}
+fn read_u16(bytes: &[u8], offset: usize) -> Result<u16> {
+    read(bytes, offset).map(u16::from_le_bytes)
+}
+fn read_u32(bytes: &[u8], offset: usize) -> Result<u32> {
+    read(bytes, offset).map(u32::from_le_bytes)
+}
+fn read_u64(bytes: &[u8], offset: usize) -> Result<u64> {
+    read(bytes, offset).map(u64::from_le_bytes)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub paddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    fn parse(bytes: &[u8]) -> Result<Self> {
+        Ok(Self {
+            kind: read_u32(bytes, 0)?,
+            flags: read_u32(bytes, 4)?,
+            offset: read_u64(bytes, 8)?,
+            vaddr: read_u64(bytes, 16)?,
+            paddr: read_u64(bytes, 24)?,
+            file_size: read_u64(bytes, 32)?,
+            mem_size: read_u64(bytes, 40)?,
+            align: read_u64(bytes, 48)?,
+        })
+    }
+}
+
+/// A validated ELF64 x86_64 executable.
+#[derive(Debug, Clone, Copy)]
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    entry: u64,
+    ph_offset: usize,
+    ph_entry_size: usize,
+    ph_count: u16,
+}
+
+impl<'a> Elf<'a> {
+    /// Checks the header, the program header table, and that the segments are inside the file and
+    /// the address space.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self> {
+        let ident: [u8; 16] = read(bytes, 0)?;
+        if ident[..4] != MAGIC {
+            return Err(Error::InvalidMagic);
+        }
+        if ident[4] != CLASS_64 {
+            return Err(Error::Not64Bit);
+        }
+        if ident[5] != DATA_LITTLE_ENDIAN {
+            return Err(Error::NotLittleEndian);
+        }
+        if ident[6] != VERSION_CURRENT {
+            return Err(Error::UnknownVersion(ident[6]));
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Err(Error::UnexpectedEnd);
+        }
+        let kind = read_u16(bytes, 16)?;
+        if !matches!(kind, TYPE_EXEC | TYPE_DYN) {
+            return Err(Error::NotExecutable(kind));
+        }
+        let machine = read_u16(bytes, 18)?;
+        if machine != MACHINE_X86_64 {
+            return Err(Error::NotX86_64(machine));
+        }
+        let ph_entry_size = read_u16(bytes, 54)?;
+        let ph_count = read_u16(bytes, 56)?;
+        if 0 < ph_count && (ph_entry_size as usize) < PROGRAM_HEADER_SIZE {
+            return Err(Error::InvalidProgramHeaderSize(ph_entry_size));
+        }
+        let slf = Self {
+            bytes,
+            entry: read_u64(bytes, 24)?,
+            ph_offset: read_u64(bytes, 32)?
+                .try_into()
+                .map_err(|_| Error::UnexpectedEnd)?,
+            ph_entry_size: ph_entry_size as _,
+            ph_count,
+        };
+        let table_len = slf.ph_count as usize * slf.ph_entry_size;
+        match slf.ph_offset.checked_add(table_len) {
+            Some(end) if end <= bytes.len() => {}
+            _ => return Err(Error::UnexpectedEnd),
+        }
+        for (i, ph) in slf.program_headers().enumerate() {
+            let in_file = ph
+                .offset
+                .checked_add(ph.file_size)
+                .is_some_and(|end| end <= bytes.len() as u64);
+            let in_memory = ph.vaddr.checked_add(ph.mem_size).is_some();
+            if !in_file || !in_memory || ph.mem_size < ph.file_size {
+                return Err(Error::InvalidSegment(i as _));
+            }
+        }
+        Ok(slf)
+    }
+
+    pub const fn entry(&self) -> u64 {
+        self.entry
+    }
+
+    pub fn program_headers(&self) -> impl 'a + Iterator<Item = ProgramHeader> {
+        let (bytes, offset, size) = (self.bytes, self.ph_offset, self.ph_entry_size);
+        // The table is checked to be inside the file in `parse`.
+        (0..self.ph_count as usize)
+            .map(move |i| ProgramHeader::parse(&bytes[offset + i * size..]).unwrap())
+    }
+
+    /// The segments to load, with their file bytes. The rest of each segment, up to its memory
+    /// size, is zeroed.
+    pub fn load_segments(&self) -> impl 'a + Iterator<Item = (ProgramHeader, &'a [u8])> {
+        let bytes = self.bytes;
+        self.program_headers()
+            .filter(|ph| ph.kind == PT_LOAD)
+            .map(move |ph| {
+                let start = ph.offset as usize;
+                (ph, &bytes[start..start + ph.file_size as usize])
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ENTRY: u64 = 0xFFFF_8000_0000_1000;
+
+    /// An executable with one `PT_LOAD` segment of 16 bytes in the file and 32 in memory, right
+    /// after its program header.
+    fn elf() -> Vec<u8> {
+        let mut bytes = vec![0; HEADER_SIZE + PROGRAM_HEADER_SIZE + 16];
+        let mut put = |offset: usize, value: &[u8]| {
+            bytes[offset..offset + value.len()].copy_from_slice(value);
+        };
+        put(0, &MAGIC);
+        put(4, &[CLASS_64, DATA_LITTLE_ENDIAN, VERSION_CURRENT]);
+        put(16, &TYPE_EXEC.to_le_bytes());
+        put(18, &MACHINE_X86_64.to_le_bytes());
+        put(24, &ENTRY.to_le_bytes());
+        put(32, &(HEADER_SIZE as u64).to_le_bytes());
+        put(54, &(PROGRAM_HEADER_SIZE as u16).to_le_bytes());
+        put(56, &1u16.to_le_bytes());
+
+        let ph = HEADER_SIZE;
+        put(ph, &PT_LOAD.to_le_bytes());
+        put(ph + 4, &(PF_R | PF_X).to_le_bytes());
+        put(ph + 8, &((ph + PROGRAM_HEADER_SIZE) as u64).to_le_bytes());
+        put(ph + 16, &ENTRY.to_le_bytes());
+        put(ph + 32, &16u64.to_le_bytes());
+        put(ph + 40, &32u64.to_le_bytes());
+        bytes
+    }
+
+    /// The error from parsing `elf()` with `value` written at `offset`.
+    fn parse_with(offset: usize, value: &[u8]) -> Error {
+        let mut bytes = elf();
+        bytes[offset..offset + value.len()].copy_from_slice(value);
+        Elf::parse(&bytes).unwrap_err()
+    }
+
+    #[test]
+    fn parses_entry_and_segments() {
+        let mut bytes = elf();
+        let len = bytes.len();
+        bytes[len - 16..].fill(0xAB);
+        let elf = Elf::parse(&bytes).unwrap();
+        assert_eq!(elf.entry(), ENTRY);
+        assert_eq!(elf.program_headers().count(), 1);
+
+        let segments: Vec<_> = elf.load_segments().collect();
+        let [(ph, file_bytes)] = segments[..] else {
+            panic!("expected one segment, got {segments:?}");
+        };
+        assert_eq!((ph.vaddr, ph.flags), (ENTRY, PF_R | PF_X));
+        assert_eq!((ph.file_size, ph.mem_size), (16, 32));
+        assert_eq!(file_bytes, [0xAB; 16]);
+    }
+
+    #[test]
+    fn rejects_bad_headers() {
+        assert_eq!(parse_with(0, b"\x7FELG"), Error::InvalidMagic);
+        assert_eq!(parse_with(4, &[1]), Error::Not64Bit);
+        assert_eq!(parse_with(5, &[2]), Error::NotLittleEndian);
+        assert_eq!(parse_with(6, &[2]), Error::UnknownVersion(2));
+        assert_eq!(parse_with(16, &1u16.to_le_bytes()), Error::NotExecutable(1));
+        assert_eq!(parse_with(18, &3u16.to_le_bytes()), Error::NotX86_64(3));
+        assert_eq!(
+            parse_with(54, &8u16.to_le_bytes()),
+            Error::InvalidProgramHeaderSize(8)
+        );
+        // Shared objects are executable too.
+        let mut bytes = elf();
+        bytes[16..18].copy_from_slice(&TYPE_DYN.to_le_bytes());
+        assert!(Elf::parse(&bytes).is_ok());
+    }
+
+    #[test]
+    fn rejects_truncated_files() {
+        let bytes = elf();
+        assert_eq!(Elf::parse(&[]).unwrap_err(), Error::UnexpectedEnd);
+        assert_eq!(Elf::parse(&bytes[..10]).unwrap_err(), Error::UnexpectedEnd);
+        assert_eq!(Elf::parse(&bytes[..40]).unwrap_err(), Error::UnexpectedEnd);
+        // The program header table is cut off.
+        assert_eq!(Elf::parse(&bytes[..100]).unwrap_err(), Error::UnexpectedEnd);
+        assert_eq!(
+            parse_with(32, &u64::MAX.to_le_bytes()),
+            Error::UnexpectedEnd
+        );
+        // The segment's bytes are cut off.
+        assert_eq!(
+            Elf::parse(&bytes[..bytes.len() - 1]).unwrap_err(),
+            Error::InvalidSegment(0)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_segments() {
+        let ph = HEADER_SIZE;
+        // More bytes in the file than in memory.
+        assert_eq!(
+            parse_with(ph + 40, &8u64.to_le_bytes()),
+            Error::InvalidSegment(0)
+        );
+        // Offset and file size overflow.
+        assert_eq!(
+            parse_with(ph + 8, &(u64::MAX - 8).to_le_bytes()),
+            Error::InvalidSegment(0)
+        );
+        // The segment's memory wraps around the address space.
+        assert_eq!(
+            parse_with(ph + 16, &(u64::MAX - 16).to_le_bytes()),
+            Error::InvalidSegment(0)
+        );
+        // Segments that aren't loaded are checked too.
+        let mut bytes = elf();
+        bytes[ph..ph + 4].copy_from_slice(&0u32.to_le_bytes());
+        bytes[ph + 40..ph + 48].copy_from_slice(&8u64.to_le_bytes());
+        assert_eq!(Elf::parse(&bytes).unwrap_err(), Error::InvalidSegment(0));
+    }
+}
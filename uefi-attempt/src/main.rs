@@ -1,10 +1,12 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
-// extern crate alloc;
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
 use core::{
     fmt::{self, Write},
-    panic::PanicInfo,
+    slice,
 };
 
 use rand::prelude::*;
@@ -13,12 +15,18 @@ use uefi::{
     prelude::*,
     proto::{
         console::{gop::GraphicsOutput, text::Output},
-        media::fs::SimpleFileSystem,
+        media::{
+            file::{Directory, File, FileAttribute, FileInfo, FileMode},
+            fs::SimpleFileSystem,
+        },
         rng::Rng as RngProto,
     },
+    table::boot::MemoryType,
+    CStr16,
 };
 
 pub mod align;
+pub mod elf;
 pub mod serial;
 
 type Result<T, E = Error> = core::result::Result<T, E>;
@@ -27,6 +35,9 @@ type Result<T, E = Error> = core::result::Result<T, E>;
 enum Error {
     Uefi(uefi::Status),
     Fmt(fmt::Error),
+    /// The file doesn't exist, or is a directory.
+    NotFound(&'static CStr16),
+    Elf(elf::Error),
 }
 
 impl fmt::Display for Error {
@@ -34,6 +45,8 @@ impl fmt::Display for Error {
         match self {
             Error::Uefi(err) => fmt::Display::fmt(err, f),
             Error::Fmt(err) => fmt::Display::fmt(err, f),
+            Error::NotFound(path) => write!(f, "File not found: {path}"),
+            Error::Elf(err) => fmt::Display::fmt(err, f),
         }
     }
 }
@@ -50,6 +63,12 @@ impl From<fmt::Error> for Error {
     }
 }
 
+impl From<elf::Error> for Error {
+    fn from(err: elf::Error) -> Self {
+        Self::Elf(err)
+    }
+}
+
 fn halt() -> ! {
     loop {
         x86_64::instructions::hlt();
@@ -65,8 +84,117 @@ fn init_rng(bt: &BootServices) -> uefi::Result<StdRng> {
     Ok(StdRng::from_seed(seed))
 }
 
-fn main(_image: Handle, st: SystemTable<Boot>) -> Result<()> {
+/// A directory entry, copied out of its `FileInfo`.
+#[derive(Debug, Clone)]
+struct DirEntry {
+    name: String,
+    size: u64,
+    is_directory: bool,
+}
+
+/// Decodes a UCS-2 file name. UCS-2 has no surrogates, so every code unit is a `char`.
+fn decode_name(name: &CStr16) -> String {
+    name.iter().map(|&ch| char::from(ch)).collect()
+}
+
+/// Reads the entries of `dir`, except for `.` and `..`.
+fn read_dir(bt: &BootServices, dir: &mut Directory) -> Result<Vec<DirEntry>> {
+    let mut entries = Vec::new();
+    let mut stack_buf = align::Align8([0; 80 + 2 * 256]);
+    // A bigger buffer from the pool, for entries with names too long for `stack_buf`.
+    let mut pool_buf: Option<&mut [u8]> = None;
+    let result = loop {
+        let buf = match &mut pool_buf {
+            Some(buf) => &mut **buf,
+            None => stack_buf.as_byte_slice_mut(),
+        };
+        match dir.read_entry(buf) {
+            Ok(Some(file)) => {
+                let name = file.file_name();
+                if name.eq_str_until_nul(".") || name.eq_str_until_nul("..") {
+                    continue;
+                }
+                let entry = DirEntry {
+                    name: decode_name(name),
+                    size: file.file_size(),
+                    is_directory: file.is_directory(),
+                };
+                sprintln!(
+                    "{}{} ({} bytes)",
+                    entry.name,
+                    if entry.is_directory { "/" } else { "" },
+                    entry.size,
+                );
+                entries.push(entry);
+            }
+            Ok(None) => break Ok(entries),
+            Err(err) if err.status() == Status::BUFFER_TOO_SMALL => {
+                let Some(size) = *err.data() else {
+                    break Err(err.into());
+                };
+                // Pool allocations are 8-byte aligned, enough for `FileInfo`.
+                let ptr = match bt.allocate_pool(MemoryType::LOADER_DATA, size) {
+                    Ok(ptr) => ptr,
+                    Err(err) => break Err(err.into()),
+                };
+                if let Some(old_buf) = pool_buf.take() {
+                    unsafe { bt.free_pool(old_buf.as_mut_ptr())? };
+                }
+                // Zeroed, since a slice can't be over uninitialized memory.
+                unsafe { ptr.write_bytes(0, size) };
+                pool_buf = Some(unsafe { slice::from_raw_parts_mut(ptr, size) });
+            }
+            Err(err) => break Err(err.into()),
+        }
+    };
+    if let Some(buf) = pool_buf {
+        unsafe { bt.free_pool(buf.as_mut_ptr())? };
+    }
+    result
+}
+
+/// The kernel, in the root directory of the volume.
+const KERNEL_PATH: &CStr16 = cstr16!("kernel.elf");
+
+/// Reads the whole file at `path` in `dir` into a buffer from the pool, which is never freed.
+fn read_file(
+    bt: &BootServices,
+    dir: &mut Directory,
+    path: &'static CStr16,
+) -> Result<&'static [u8]> {
+    let file = match dir.open(path, FileMode::Read, FileAttribute::empty()) {
+        Ok(file) => file,
+        Err(err) if err.status() == Status::NOT_FOUND => return Err(Error::NotFound(path)),
+        Err(err) => return Err(err.into()),
+    };
+    let Some(mut file) = file.into_regular_file() else {
+        return Err(Error::NotFound(path));
+    };
+    let size = file.get_boxed_info::<FileInfo>()?.file_size() as usize;
+    // The pool can't allocate 0 bytes.
+    let ptr = bt.allocate_pool(MemoryType::LOADER_DATA, size.max(1))?;
+    // Zeroed, since a slice can't be over uninitialized memory.
+    unsafe { ptr.write_bytes(0, size) };
+    let buf = unsafe { slice::from_raw_parts_mut(ptr, size) };
+    let mut len = 0;
+    while len < size {
+        match file.read(&mut buf[len..]) {
+            // The file got shorter since its size was read.
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(err) => {
+                unsafe { bt.free_pool(ptr)? };
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(&buf[..len])
+}
+
+fn main(_image: Handle, mut st: SystemTable<Boot>) -> Result<()> {
     serial::init_logger();
+    // Boot services are never exited.
+    unsafe { uefi::allocator::init(&mut st) };
 
     let bt = st.boot_services();
 
@@ -81,11 +209,23 @@ fn main(_image: Handle, st: SystemTable<Boot>) -> Result<()> {
     let mut fs = bt.open_protocol_exclusive::<SimpleFileSystem>(fs_handle)?;
 
     let mut dir = fs.open_volume()?;
-    loop {
-        let mut buf = align::Align8([0; 80 + 2 * 256]);
-        let Some(file) = dir.read_entry(&mut *buf)? else {
-            break;
-        };
+    let entries = read_dir(bt, &mut dir)?;
+    sprintln!("{} entries in the root directory", entries.len());
+
+    let kernel = read_file(bt, &mut dir, KERNEL_PATH)?;
+    let kernel = elf::Elf::parse(kernel)?;
+    sprintln!("{}: entry point at {:#x}", KERNEL_PATH, kernel.entry());
+    for (ph, _) in kernel.load_segments() {
+        sprintln!(
+            "  {:#x}..{:#x} from {:#x} ({} bytes in the file), {}{}{}",
+            ph.vaddr,
+            ph.vaddr + ph.mem_size,
+            ph.offset,
+            ph.file_size,
+            if ph.flags & elf::PF_R != 0 { "r" } else { "-" },
+            if ph.flags & elf::PF_W != 0 { "w" } else { "-" },
+            if ph.flags & elf::PF_X != 0 { "x" } else { "-" },
+        );
     }
 
     if (|| true)() {
@@ -122,12 +262,16 @@ fn main(_image: Handle, st: SystemTable<Boot>) -> Result<()> {
 
 #[entry]
 fn efi_start(image: Handle, st: SystemTable<Boot>) -> Status {
-    main(image, st).unwrap();
+    // The panic handler prints nothing, so the error is printed here.
+    if let Err(err) = main(image, st) {
+        sprintln!("ERROR: {}", err);
+    }
     halt();
 }
 
+#[cfg(not(test))]
 #[panic_handler]
-fn panic(_info: &PanicInfo) -> ! {
+fn panic(_info: &core::panic::PanicInfo) -> ! {
     // unsafe {
     //     serial::SERIAL_LOGGER.force_unlock();
     //     sprintln!();
@@ -138,3 +282,22 @@ fn panic(_info: &PanicInfo) -> ! {
     // log::error!("PANIC: {}", info);
     halt();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_decoded_from_ucs2() {
+        // "añ€.elf", as the little endian code units `FileInfo` stores it in.
+        let bytes = b"a\0\xF1\0\xAC\x20.\0e\0l\0f\0\0\0";
+        let units: Vec<u16> = (bytes.chunks(2))
+            .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+            .collect();
+        let name = CStr16::from_u16_with_nul(&units).unwrap();
+        assert_eq!(decode_name(name), "añ€.elf");
+
+        let empty = CStr16::from_u16_with_nul(&[0]).unwrap();
+        assert_eq!(decode_name(empty), "");
+    }
+}